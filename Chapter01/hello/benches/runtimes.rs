@@ -0,0 +1,117 @@
+//! Compare the thread-pool and tokio backends.
+//!
+//! Each run first parks `BENCH_IDLE` keep-alive connections on the server,
+//! then measures how fast `BENCH_CLIENTS` busy clients get through
+//! `BENCH_REQUESTS` requests each. The blocking server spends a worker on
+//! every idle connection until it times out; the async one does not.
+//!
+//!     cargo bench --features async
+
+use std::{
+    env,
+    io::{prelude::*, BufReader},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use hello::{
+    response::{Response, StatusCode},
+    router::Router,
+    server::Server,
+};
+
+fn setting(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn router() -> Router {
+    let mut router = Router::new();
+    router.get("/", |_| {
+        Response::new(StatusCode::Ok).with_body("text/plain", "hello")
+    });
+    router
+}
+
+/// Send one request and read its response, leaving the connection open.
+fn round_trip(reader: &mut BufReader<TcpStream>) {
+    reader
+        .get_mut()
+        .write_all(b"GET / HTTP/1.1\r\nHost: bench\r\n\r\n")
+        .unwrap();
+
+    let mut length = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length: ") {
+            length = value.trim().parse().unwrap();
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).unwrap();
+}
+
+fn run(name: &str, serve: fn(Server, Router) -> bool) {
+    let idle = setting("BENCH_IDLE", 16);
+    let clients = setting("BENCH_CLIENTS", 8);
+    let requests = setting("BENCH_REQUESTS", 2_000);
+
+    let server = Server::bind("127.0.0.1:0")
+        .unwrap()
+        .workers(4)
+        .keep_alive_timeout(Duration::from_secs(1))
+        .max_requests_per_connection(usize::MAX);
+    let addr: SocketAddr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || serve(server, router()));
+
+    let parked: Vec<TcpStream> = (0..idle)
+        .map(|_| {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_nodelay(true).unwrap();
+            stream
+        })
+        .collect();
+
+    let started = Instant::now();
+    let busy: Vec<_> = (0..clients)
+        .map(|_| {
+            thread::spawn(move || {
+                let stream = TcpStream::connect(addr).unwrap();
+                stream.set_nodelay(true).unwrap();
+                let mut reader = BufReader::new(stream);
+                for _ in 0..requests {
+                    round_trip(&mut reader);
+                }
+            })
+        })
+        .collect();
+    for client in busy {
+        client.join().unwrap();
+    }
+    let elapsed = started.elapsed();
+
+    drop(parked);
+    shutdown.trigger();
+    handle.join().unwrap();
+
+    let total = clients * requests;
+    println!(
+        "{name:>8}: {total} requests with {idle} idle connections in {:.2?} ({:.0} req/s)",
+        elapsed,
+        total as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    run("blocking", Server::serve);
+    run("async", Server::serve_async);
+}
@@ -0,0 +1,281 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    date::DateTime,
+    request::{Method, Version},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET / HTTP/1.1" 200 2326`
+    Common,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "common" | "clf" => Ok(Format::Common),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("expected common or json, got {s:?}")),
+        }
+    }
+}
+
+/// One line of the access log. An empty `path` stands for a request that
+/// couldn't be read, logged as `"-"` in place of the request line.
+#[derive(Debug, Clone)]
+pub struct Entry<'a> {
+    pub client: SocketAddr,
+    pub time: SystemTime,
+    pub method: Method,
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub version: Version,
+    pub status: u16,
+    /// Body bytes sent, not counting the status line and headers.
+    pub bytes: u64,
+    pub latency: Duration,
+}
+
+impl Entry<'_> {
+    pub fn format(&self, format: Format) -> String {
+        let target = match self.query {
+            Some(query) => format!("{}?{query}", self.path),
+            None => self.path.to_string(),
+        };
+        let time = DateTime::from_system_time(self.time);
+        let unread = self.path.is_empty();
+
+        match format {
+            Format::Common => {
+                let bytes = match self.bytes {
+                    0 => "-".to_string(),
+                    n => n.to_string(),
+                };
+                let request_line = match unread {
+                    true => "-".to_string(),
+                    false => format!("{} {target} {}", self.method, self.version),
+                };
+                format!(
+                    "{} - - [{}] \"{request_line}\" {} {}",
+                    self.client.ip(),
+                    time.clf(),
+                    self.status,
+                    bytes
+                )
+            }
+            Format::Json => {
+                let (method, path, version) = match unread {
+                    true => ("null".to_string(), "null".to_string(), "null".to_string()),
+                    false => (
+                        format!("\"{}\"", self.method),
+                        json_string(&target),
+                        format!("\"{}\"", self.version),
+                    ),
+                };
+                format!(
+                    "{{\"time\":\"{}\",\"client\":\"{}\",\"method\":{method},\"path\":{path},\
+                     \"version\":{version},\"status\":{},\"bytes\":{},\"latency_ms\":{:.3}}}",
+                    time.rfc3339(),
+                    self.client,
+                    self.status,
+                    self.bytes,
+                    self.latency.as_secs_f64() * 1000.0
+                )
+            }
+        }
+    }
+}
+
+/// Quote and escape `s` as a JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Where access log lines go.
+pub enum Sink {
+    Stdout,
+    File(RotatingFile),
+}
+
+/// An append-only file that is renamed to `name.1`, `name.2`, ... once it
+/// grows past `max_bytes`, keeping at most `keep` old files.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    pub fn open(path: impl AsRef<Path>, max_bytes: u64, keep: usize) -> io::Result<RotatingFile> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_bytes,
+            keep,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            let _ = fs::remove_file(self.rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.max_bytes > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Writes one line per request, shared by every worker thread.
+pub struct AccessLog {
+    format: Format,
+    sink: Mutex<Sink>,
+}
+
+impl AccessLog {
+    pub fn new(format: Format, sink: Sink) -> AccessLog {
+        AccessLog {
+            format,
+            sink: Mutex::new(sink),
+        }
+    }
+
+    pub fn record(&self, entry: &Entry<'_>) {
+        let mut line = entry.format(self.format);
+        line.push('\n');
+
+        let mut sink = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        let result = match &mut *sink {
+            Sink::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Sink::File(file) => file.write_line(&line),
+        };
+        if let Err(e) = result {
+            crate::warn!("Problem writing access log: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process, time::UNIX_EPOCH};
+
+    fn entry(path: &str) -> Entry<'_> {
+        Entry {
+            client: "192.0.2.7:51234".parse().unwrap(),
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+            method: Method::Get,
+            path,
+            query: Some("q=1"),
+            version: Version::Http11,
+            status: 200,
+            bytes: 2326,
+            latency: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn formats_common_and_json() {
+        assert_eq!(
+            entry("/index.html").format(Format::Common),
+            "192.0.2.7 - - [10/Oct/2000:13:55:36 +0000] \"GET /index.html?q=1 HTTP/1.1\" 200 2326"
+        );
+        assert_eq!(
+            entry("/a\"b").format(Format::Json),
+            "{\"time\":\"2000-10-10T13:55:36Z\",\"client\":\"192.0.2.7:51234\",\
+             \"method\":\"GET\",\"path\":\"/a\\\"b?q=1\",\"version\":\"HTTP/1.1\",\
+             \"status\":200,\"bytes\":2326,\"latency_ms\":1.500}"
+        );
+
+        // A request that couldn't be read has no request line to log.
+        assert_eq!(
+            entry("").format(Format::Common),
+            "192.0.2.7 - - [10/Oct/2000:13:55:36 +0000] \"-\" 200 2326"
+        );
+        assert!(entry("")
+            .format(Format::Json)
+            .contains("\"method\":null,\"path\":null,\"version\":null,"));
+    }
+
+    #[test]
+    fn rotates_full_files() {
+        let dir = env::temp_dir().join(format!("hello-access-log-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let log = AccessLog::new(
+            Format::Common,
+            Sink::File(RotatingFile::open(&path, 150, 2).unwrap()),
+        );
+        for _ in 0..5 {
+            log.record(&entry("/"));
+        }
+
+        assert!(path.exists());
+        assert!(dir.join("access.log.1").exists());
+        assert!(dir.join("access.log.2").exists());
+        assert!(!dir.join("access.log.3").exists());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+}
@@ -0,0 +1,400 @@
+//! HTTP authentication for parts of a site.
+//!
+//! [`Auth`] is [`Middleware`] holding one [`AuthRule`] per protected path
+//! prefix. Requests under a prefix need either a user and password from an
+//! [`Htpasswd`] file (HTTP Basic) or one of the rule's bearer tokens;
+//! anything else gets `401 Unauthorized` with a `WWW-Authenticate`
+//! challenge.
+
+use std::{collections::HashMap, fs, io, path::Path, sync::Arc};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::{
+    middleware::Middleware,
+    request::{normalize_path, Request},
+    response::{Response, StatusCode},
+};
+
+/// Users and password hashes, one `user:hash` per line as written by
+/// `htpasswd -B`.
+///
+/// Hashes must be bcrypt (`$2y$`, `$2b$`, `$2a$`) or argon2 in PHC form
+/// (`$argon2id$...`). The older MD5, SHA-1 and crypt formats are refused
+/// when the file is read.
+#[derive(Debug, Clone, Default)]
+pub struct Htpasswd {
+    users: HashMap<String, Hash>,
+}
+
+#[derive(Debug, Clone)]
+enum Hash {
+    Bcrypt(String),
+    Argon2(String),
+}
+
+impl Hash {
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Hash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Hash::Argon2(hash) => Argon2::default()
+                .verify_password(password.as_bytes(), hash.as_str())
+                .is_ok(),
+        }
+    }
+}
+
+impl Htpasswd {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Htpasswd> {
+        Htpasswd::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parse the contents of an htpasswd file. Blank lines and lines
+    /// starting with `#` are skipped.
+    pub fn parse(text: &str) -> io::Result<Htpasswd> {
+        let invalid = |line: usize, message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {line}: {message}"),
+            )
+        };
+
+        let mut users = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((user, hash)) = line.split_once(':') else {
+                return Err(invalid(i + 1, "expected user:hash".to_string()));
+            };
+
+            let hash = if ["$2a$", "$2b$", "$2x$", "$2y$"]
+                .iter()
+                .any(|prefix| hash.starts_with(prefix))
+            {
+                Hash::Bcrypt(hash.to_string())
+            } else if hash.starts_with("$argon2") {
+                PasswordHash::new(hash).map_err(|e| invalid(i + 1, format!("{user}: {e}")))?;
+                Hash::Argon2(hash.to_string())
+            } else {
+                return Err(invalid(
+                    i + 1,
+                    format!("{user}: unsupported hash, expected bcrypt (htpasswd -B) or argon2"),
+                ));
+            };
+            users.insert(user.to_string(), hash);
+        }
+
+        Ok(Htpasswd { users })
+    }
+
+    /// Whether `password` is right for `user`.
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(hash) => hash.verify(password),
+            None => {
+                // Hash the password anyway, at the cost the file uses, so
+                // the time taken doesn't tell whether `user` exists.
+                if let Some(hash) = self.users.values().next() {
+                    hash.verify(password);
+                }
+                false
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+/// The credentials accepted under one path prefix.
+#[derive(Debug, Clone)]
+pub struct AuthRule {
+    prefix: String,
+    realm: String,
+    htpasswd: Option<Arc<Htpasswd>>,
+    tokens: Vec<String>,
+}
+
+impl AuthRule {
+    /// Protect `prefix` and everything below it. With no credentials
+    /// added, every request there is refused.
+    pub fn new(prefix: &str) -> AuthRule {
+        let prefix = match prefix.trim_end_matches('/') {
+            "" => "/",
+            trimmed => trimmed,
+        };
+        AuthRule {
+            prefix: prefix.to_string(),
+            realm: "hello".to_string(),
+            htpasswd: None,
+            tokens: Vec::new(),
+        }
+    }
+
+    /// The realm named in the challenge, which browsers show when asking
+    /// for a password. Defaults to `hello`.
+    pub fn realm(mut self, realm: &str) -> AuthRule {
+        self.realm = realm.to_string();
+        self
+    }
+
+    /// Accept HTTP Basic credentials for the users in `htpasswd`.
+    pub fn basic(mut self, htpasswd: Arc<Htpasswd>) -> AuthRule {
+        self.htpasswd = Some(htpasswd);
+        self
+    }
+
+    /// Accept `Authorization: Bearer` with any of `tokens`.
+    pub fn bearer<I, S>(mut self, tokens: I) -> AuthRule
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.tokens.extend(tokens.into_iter().map(Into::into));
+        self
+    }
+
+    /// Whether `path`, already normalized, is at or below the prefix.
+    fn covers(&self, path: &str) -> bool {
+        self.prefix == "/"
+            || path
+                .strip_prefix(self.prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    fn check(&self, request: &Request) -> Result<(), Response> {
+        let credentials = request
+            .headers
+            .get("Authorization")
+            .and_then(|value| value.trim().split_once(' '))
+            .map(|(scheme, rest)| (scheme, rest.trim()));
+
+        let bearer_failed = match credentials {
+            Some((scheme, user_pass)) if scheme.eq_ignore_ascii_case("Basic") => {
+                if let Some((user, password)) = decode_basic(user_pass) {
+                    if self
+                        .htpasswd
+                        .as_ref()
+                        .is_some_and(|h| h.verify(&user, &password))
+                    {
+                        return Ok(());
+                    }
+                    crate::debug!("{}: wrong password for {user:?}", request.path);
+                }
+                false
+            }
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") => {
+                if self
+                    .tokens
+                    .iter()
+                    .any(|t| constant_time_eq(t.as_bytes(), token.as_bytes()))
+                {
+                    return Ok(());
+                }
+                crate::debug!("{}: unknown bearer token", request.path);
+                true
+            }
+            _ => false,
+        };
+
+        Err(self.challenge(bearer_failed))
+    }
+
+    fn challenge(&self, bearer_failed: bool) -> Response {
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut response = Response::new(StatusCode::Unauthorized);
+        if self.htpasswd.is_some() {
+            response.headers.append(
+                "WWW-Authenticate",
+                format!("Basic realm=\"{realm}\", charset=\"UTF-8\""),
+            );
+        }
+        if !self.tokens.is_empty() {
+            let error = if bearer_failed {
+                ", error=\"invalid_token\""
+            } else {
+                ""
+            };
+            response.headers.append(
+                "WWW-Authenticate",
+                format!("Bearer realm=\"{realm}\"{error}"),
+            );
+        }
+        response
+    }
+}
+
+/// Requires credentials on the paths its rules cover. Where rules overlap,
+/// the longest prefix wins.
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    rules: Vec<AuthRule>,
+}
+
+impl Auth {
+    pub fn new() -> Auth {
+        Auth::default()
+    }
+
+    pub fn rule(mut self, rule: AuthRule) -> Auth {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+}
+
+impl Middleware for Auth {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        // A path that doesn't decode might be under any rule.
+        let Some(path) = normalize_path(&request.path) else {
+            return Some(Response::new(StatusCode::BadRequest));
+        };
+        let rule = self
+            .rules
+            .iter()
+            .filter(|rule| rule.covers(&path))
+            .max_by_key(|rule| rule.prefix.len())?;
+        rule.check(request).err()
+    }
+}
+
+fn decode_basic(encoded: &str) -> Option<(String, String)> {
+    let decoded = STANDARD.decode(encoded).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Compare without stopping at the first difference, so response times
+/// don't give away how much of a token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::PasswordHasher;
+
+    fn request(path: &str, authorization: Option<&str>) -> Request {
+        let header =
+            authorization.map_or(String::new(), |value| format!("Authorization: {value}\r\n"));
+        let raw = format!("GET {path} HTTP/1.1\r\n{header}\r\n");
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        format!("Basic {}", STANDARD.encode(format!("{user}:{password}")))
+    }
+
+    fn htpasswd() -> Htpasswd {
+        let bcrypt = bcrypt::hash("hunter2", 4).unwrap();
+        let argon2 = Argon2::default()
+            .hash_password(b"correct horse")
+            .unwrap()
+            .to_string();
+        Htpasswd::parse(&format!("# admins\nalice:{bcrypt}\n\nbob:{argon2}\n")).unwrap()
+    }
+
+    #[test]
+    fn verifies_bcrypt_and_argon2_hashes() {
+        let htpasswd = htpasswd();
+        assert_eq!(htpasswd.len(), 2);
+        assert!(htpasswd.verify("alice", "hunter2"));
+        assert!(!htpasswd.verify("alice", "hunter3"));
+        assert!(htpasswd.verify("bob", "correct horse"));
+        assert!(!htpasswd.verify("bob", "hunter2"));
+        assert!(!htpasswd.verify("carol", "hunter2"));
+
+        let err = Htpasswd::parse("old:$apr1$abc$def\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: old: unsupported hash, expected bcrypt (htpasswd -B) or argon2"
+        );
+        let err = Htpasswd::parse("\nnocolon\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: expected user:hash");
+    }
+
+    #[test]
+    fn challenges_requests_under_protected_prefixes() {
+        let auth = Auth::new().rule(
+            AuthRule::new("/admin/")
+                .realm("Admin \"area\"")
+                .basic(Arc::new(htpasswd()))
+                .bearer(["s3cret"]),
+        );
+
+        assert!(auth.before(&mut request("/", None)).is_none());
+        assert!(auth.before(&mut request("/administrator", None)).is_none());
+
+        for path in [
+            "/admin",
+            "/admin/users",
+            "/%61dmin/",
+            "/x/../admin",
+            "//admin",
+        ] {
+            let response = auth.before(&mut request(path, None)).unwrap();
+            assert_eq!(response.status, StatusCode::Unauthorized, "{path}");
+        }
+
+        for path in ["/%61dmin/x%zz", "/admin%", "/x%zz"] {
+            let response = auth.before(&mut request(path, None)).unwrap();
+            assert_eq!(response.status, StatusCode::BadRequest, "{path}");
+        }
+
+        let response = auth.before(&mut request("/admin", None)).unwrap();
+        let challenges: Vec<&str> = response.headers.get_all("WWW-Authenticate").collect();
+        assert_eq!(
+            challenges,
+            [
+                "Basic realm=\"Admin \\\"area\\\"\", charset=\"UTF-8\"",
+                "Bearer realm=\"Admin \\\"area\\\"\""
+            ]
+        );
+
+        let ok = basic("alice", "hunter2");
+        assert!(auth.before(&mut request("/admin/", Some(&ok))).is_none());
+        let wrong = basic("alice", "nope");
+        assert!(auth.before(&mut request("/admin/", Some(&wrong))).is_some());
+
+        assert!(auth
+            .before(&mut request("/admin/", Some("bearer s3cret")))
+            .is_none());
+        let response = auth
+            .before(&mut request("/admin/", Some("Bearer guess")))
+            .unwrap();
+        assert!(response
+            .headers
+            .get_all("WWW-Authenticate")
+            .any(|c| c.ends_with("error=\"invalid_token\"")));
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let auth = Auth::new()
+            .rule(AuthRule::new("/").bearer(["site"]))
+            .rule(AuthRule::new("/admin").bearer(["admin"]));
+
+        assert!(auth
+            .before(&mut request("/", Some("Bearer site")))
+            .is_none());
+        assert!(auth
+            .before(&mut request("/admin/x", Some("Bearer site")))
+            .is_some());
+        assert!(auth
+            .before(&mut request("/admin/x", Some("Bearer admin")))
+            .is_none());
+    }
+}
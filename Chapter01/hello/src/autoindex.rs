@@ -0,0 +1,333 @@
+use std::{cmp::Ordering, fmt::Write, fs, io, path::Path, time::SystemTime};
+
+use crate::{
+    access_log::json_string,
+    date::DateTime,
+    request::Request,
+    response::{Response, StatusCode},
+    template::escape,
+};
+
+/// One file or subdirectory in a listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    /// Zero for directories.
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// The column a listing is sorted by, from `?sort=`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    fn as_str(self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified",
+        }
+    }
+}
+
+/// The entries of `dir`, skipping hidden files and anything that resolves
+/// outside `root`.
+pub fn read(dir: &Path, root: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        // Follows symlinks, like serving the entry would.
+        let Ok(path) = entry.path().canonicalize() else {
+            continue;
+        };
+        if !path.starts_with(root) {
+            continue;
+        }
+        let Ok(metadata) = fs::metadata(&path) else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Sort directories first, then by `key`, ties broken by name.
+pub fn sort(entries: &mut [Entry], key: SortKey, descending: bool) {
+    entries.sort_by(|a, b| {
+        let by_key = match key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        };
+        let order = by_key.then_with(|| a.name.cmp(&b.name));
+        let order = if descending { order.reverse() } else { order };
+        b.is_dir.cmp(&a.is_dir).then(order)
+    });
+}
+
+/// Render a listing of `entries` for the directory at `url_path`, as JSON
+/// if the client prefers `application/json` and as HTML otherwise.
+///
+/// `?sort=name|size|modified` and `?order=asc|desc` pick the order.
+pub fn listing(request: &Request, url_path: &str, mut entries: Vec<Entry>) -> Response {
+    let key = match request.query_param("sort").as_deref() {
+        Some("size") => SortKey::Size,
+        Some("modified") => SortKey::Modified,
+        _ => SortKey::Name,
+    };
+    let descending = request.query_param("order").as_deref() == Some("desc");
+    sort(&mut entries, key, descending);
+
+    let response = Response::new(StatusCode::Ok).header("Vary", "Accept");
+    if prefers_json(request.headers.get("Accept")) {
+        response.with_body("application/json", json(url_path, &entries))
+    } else {
+        response.with_body(
+            "text/html; charset=utf-8",
+            html(url_path, &entries, key, descending),
+        )
+    }
+}
+
+/// Whether `Accept` ranks `application/json` above `text/html`.
+fn prefers_json(accept: Option<&str>) -> bool {
+    let Some(accept) = accept else {
+        return false;
+    };
+    let (mut json, mut html) = (0.0_f32, 0.0_f32);
+    for item in accept.to_ascii_lowercase().split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or_default();
+        let q = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+            .unwrap_or(0.0);
+        match media_type {
+            "application/json" => json = json.max(q),
+            "text/html" => html = html.max(q),
+            _ => {}
+        }
+    }
+    json > html
+}
+
+fn json(url_path: &str, entries: &[Entry]) -> String {
+    let mut out = format!("{{\"path\":{},\"entries\":[", json_string(url_path));
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let modified = match entry.modified {
+            Some(time) => json_string(&DateTime::from_system_time(time).rfc3339()),
+            None => "null".to_string(),
+        };
+        let _ = write!(
+            out,
+            "{{\"name\":{},\"type\":\"{}\",\"size\":{},\"modified\":{modified}}}",
+            json_string(&entry.name),
+            if entry.is_dir { "directory" } else { "file" },
+            entry.size,
+        );
+    }
+    out.push_str("]}");
+    out
+}
+
+fn html(url_path: &str, entries: &[Entry], key: SortKey, descending: bool) -> String {
+    let title = format!("Index of {}", escape(url_path));
+
+    // Each column header sorts by that column, flipping the order when it
+    // already does.
+    let heading = |column: SortKey, label: &str| {
+        let order = if column == key && !descending {
+            "desc"
+        } else {
+            "asc"
+        };
+        format!(
+            "<th><a href=\"?sort={}&amp;order={order}\">{label}</a></th>",
+            column.as_str()
+        )
+    };
+
+    let mut rows = String::new();
+    if url_path != "/" {
+        rows.push_str("            <tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            human_size(entry.size)
+        };
+        let modified = entry.modified.map_or(String::new(), |time| {
+            let t = DateTime::from_system_time(time);
+            format!(
+                "{}-{:02}-{:02} {:02}:{:02}",
+                t.year, t.month, t.day, t.hour, t.minute
+            )
+        });
+        let _ = writeln!(
+            rows,
+            "            <tr><td><a href=\"{}{slash}\">{}{slash}</a></td><td>{size}</td><td>{modified}</td></tr>",
+            percent_encode(&entry.name),
+            escape(&entry.name),
+        );
+    }
+
+    format!(
+        "<!DOCTYPE html>
+<html lang=\"en\">
+
+<head>
+    <meta charset=\"utf-8\">
+    <title>{title}</title>
+    <link rel=\"stylesheet\" href=\"/style.css\">
+</head>
+
+<body>
+    <h1>{title}</h1>
+    <table>
+        <thead>
+            <tr>{}{}{}</tr>
+        </thead>
+        <tbody>
+{rows}        </tbody>
+    </table>
+</body>
+
+</html>
+",
+        heading(SortKey::Name, "Name"),
+        heading(SortKey::Size, "Size"),
+        heading(SortKey::Modified, "Modified"),
+    )
+}
+
+/// `1.5K`, `20M`: sizes the way `ls -h` shows them.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return bytes.to_string();
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if size < 10.0 {
+        format!("{size:.1}{}", UNITS[unit])
+    } else {
+        format!("{size:.0}{}", UNITS[unit])
+    }
+}
+
+/// Percent-encode a file name for use as one relative URL path segment.
+fn percent_encode(segment: &str) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for b in segment.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            b => {
+                let _ = write!(out, "%{b:02X}");
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn entry(name: &str, is_dir: bool, size: u64, modified: u64) -> Entry {
+        Entry {
+            name: name.to_string(),
+            is_dir,
+            size,
+            modified: Some(UNIX_EPOCH + Duration::from_secs(modified)),
+        }
+    }
+
+    fn get(target: &str, accept: &str) -> Request {
+        let raw = format!("GET {target} HTTP/1.1\r\nAccept: {accept}\r\n\r\n");
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn sorts_directories_first() {
+        let mut entries = vec![
+            entry("b.txt", false, 10, 3),
+            entry("docs", true, 0, 1),
+            entry("a.txt", false, 300, 2),
+        ];
+
+        sort(&mut entries, SortKey::Name, false);
+        assert_eq!(names(&entries), ["docs", "a.txt", "b.txt"]);
+        sort(&mut entries, SortKey::Size, true);
+        assert_eq!(names(&entries), ["docs", "a.txt", "b.txt"]);
+        sort(&mut entries, SortKey::Modified, true);
+        assert_eq!(names(&entries), ["docs", "b.txt", "a.txt"]);
+    }
+
+    #[test]
+    fn renders_html_and_json() {
+        let entries = vec![
+            entry("a <b>.txt", false, 1536, 0),
+            entry("docs", true, 0, 0),
+        ];
+
+        let response = listing(&get("/files/", "text/html"), "/files/", entries.clone());
+        let html = String::from_utf8(response.body.as_bytes().unwrap().to_vec()).unwrap();
+        assert!(html.contains("<title>Index of /files/</title>"));
+        assert!(html.contains("<a href=\"a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a></td><td>1.5K</td><td>1970-01-01 00:00</td>"));
+        assert!(html.contains("<a href=\"docs/\">docs/</a>"));
+        assert!(html.contains("<a href=\"../\">"));
+        assert!(html.find("docs/").unwrap() < html.find("a &lt;b&gt;").unwrap());
+
+        let response = listing(
+            &get(
+                "/files/?sort=size&order=desc",
+                "application/json, text/html;q=0.9",
+            ),
+            "/files/",
+            entries,
+        );
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("application/json")
+        );
+        assert_eq!(
+            response.body.as_bytes().unwrap(),
+            b"{\"path\":\"/files/\",\"entries\":[\
+              {\"name\":\"docs\",\"type\":\"directory\",\"size\":0,\"modified\":\"1970-01-01T00:00:00Z\"},\
+              {\"name\":\"a <b>.txt\",\"type\":\"file\",\"size\":1536,\"modified\":\"1970-01-01T00:00:00Z\"}]}"
+        );
+    }
+}
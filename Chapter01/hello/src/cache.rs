@@ -0,0 +1,150 @@
+use std::{
+    fs::Metadata,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    date::DateTime,
+    request::{Method, Request},
+};
+
+/// A strong validator for a file, built from its size and modification
+/// time the way most servers do: `"1a2b-5f3c9d10"`.
+pub fn etag(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    format!("\"{:x}-{modified:x}\"", metadata.len())
+}
+
+/// Whether the client's cached copy, described by `If-None-Match` or
+/// `If-Modified-Since`, is still current for a resource with these
+/// validators, so a `304 Not Modified` can be sent instead of the body.
+///
+/// `If-None-Match` wins when both are present (RFC 9110 section 13.2.2).
+pub fn not_modified(
+    request: &Request,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> bool {
+    if !matches!(request.method, Method::Get | Method::Head) {
+        return false;
+    }
+
+    if let Some(if_none_match) = request.headers.get("If-None-Match") {
+        let Some(etag) = etag else {
+            return false;
+        };
+        return if_none_match.trim() == "*"
+            || if_none_match
+                .split(',')
+                .any(|candidate| weak_eq(candidate.trim(), etag));
+    }
+
+    match (request.headers.get("If-Modified-Since"), last_modified) {
+        (Some(since), Some(modified)) => DateTime::parse_http(since)
+            .is_some_and(|since| truncate(modified) <= since.to_system_time()),
+        _ => false,
+    }
+}
+
+/// Weak comparison: the tags match once any `W/` prefixes are ignored.
+fn weak_eq(a: &str, b: &str) -> bool {
+    fn opaque(tag: &str) -> &str {
+        tag.strip_prefix("W/").unwrap_or(tag)
+    }
+    opaque(a) == opaque(b)
+}
+
+/// HTTP dates only have whole seconds.
+fn truncate(time: SystemTime) -> SystemTime {
+    DateTime::from_system_time(time).to_system_time()
+}
+
+/// `Cache-Control` values to send, chosen by the longest matching path
+/// prefix:
+///
+/// ```
+/// use hello::cache::CacheControl;
+///
+/// let mut rules = CacheControl::new();
+/// rules
+///     .rule("/", "no-cache")
+///     .rule("/assets/", "public, max-age=31536000, immutable");
+///
+/// assert_eq!(rules.for_path("/assets/app.js"), Some("public, max-age=31536000, immutable"));
+/// assert_eq!(rules.for_path("/index.html"), Some("no-cache"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheControl {
+    rules: Vec<(String, String)>,
+}
+
+impl CacheControl {
+    pub fn new() -> CacheControl {
+        CacheControl::default()
+    }
+
+    pub fn rule(
+        &mut self,
+        prefix: impl Into<String>,
+        value: impl Into<String>,
+    ) -> &mut CacheControl {
+        self.rules.push((prefix.into(), value.into()));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn for_path(&self, path: &str) -> Option<&str> {
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn get(headers: &str) -> Request {
+        let raw = format!("GET / HTTP/1.1\r\n{headers}\r\n");
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn evaluates_preconditions() {
+        let etag = Some("\"abc\"");
+        let modified = Some(UNIX_EPOCH + Duration::from_millis(784_111_777_500));
+
+        assert!(not_modified(&get("If-None-Match: \"abc\"\r\n"), etag, None));
+        assert!(not_modified(
+            &get("If-None-Match: \"x\", W/\"abc\"\r\n"),
+            etag,
+            None
+        ));
+        assert!(not_modified(&get("If-None-Match: *\r\n"), etag, None));
+        assert!(!not_modified(
+            &get("If-None-Match: \"x\"\r\n"),
+            etag,
+            modified
+        ));
+
+        let since = "If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n";
+        assert!(not_modified(&get(since), etag, modified));
+        let earlier = "If-Modified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n";
+        assert!(!not_modified(&get(earlier), etag, modified));
+        // If-None-Match takes precedence.
+        let both = format!("If-None-Match: \"x\"\r\n{since}");
+        assert!(!not_modified(&get(&both), etag, modified));
+
+        assert!(!not_modified(&get(""), etag, modified));
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    str::FromStr,
+};
+
+use crate::{
+    headers::Headers,
+    request::Request,
+    response::{Body, Response, StatusCode},
+};
+
+/// Files bigger than this are sent as they are rather than compressed in
+/// memory; serve precompressed siblings for those instead.
+const MAX_FILE_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The `Content-Type`s compressed unless configured otherwise.
+pub const DEFAULT_MIME_TYPES: &[&str] = &[
+    "text/*",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+/// Content codings `hello` can produce, in order of preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+    /// The token used in `Accept-Encoding` and `Content-Encoding`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// The suffix of a precompressed sibling file: `app.js.br`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                // Quality 5 of 11: most of the gain at a fraction of the CPU.
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                writer.write_all(data)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Encoding, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "br" | "brotli" => Ok(Encoding::Brotli),
+            "gzip" | "x-gzip" => Ok(Encoding::Gzip),
+            _ => Err(format!("expected br or gzip, got {s:?}")),
+        }
+    }
+}
+
+/// Pick the coding to use from those `offered`, honouring the client's
+/// `Accept-Encoding` q-values and falling back on the order of `offered`
+/// to break ties. `None` means send the body as it is.
+pub fn negotiate(accept_encoding: Option<&str>, offered: &[Encoding]) -> Option<Encoding> {
+    let accept = accept_encoding?;
+
+    let mut wildcard = None;
+    let mut weights = Vec::new();
+    for item in accept.to_ascii_lowercase().split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let coding = parts.next().unwrap_or_default();
+        let q = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+            .unwrap_or(0.0);
+
+        if coding == "*" {
+            wildcard = Some(q);
+        } else if let Ok(encoding) = coding.parse::<Encoding>() {
+            weights.push((encoding, q));
+        }
+    }
+
+    let weight = |encoding: Encoding| {
+        weights
+            .iter()
+            .find(|(e, _)| *e == encoding)
+            .map(|(_, q)| *q)
+            .or(wildcard)
+            .unwrap_or(0.0)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in offered {
+        let q = weight(encoding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Note that the response depends on `Accept-Encoding`, so caches keep the
+/// compressed and uncompressed versions apart.
+pub fn vary_on_accept_encoding(headers: &mut Headers) {
+    if headers.has_token("Vary", "Accept-Encoding") || headers.has_token("Vary", "*") {
+        return;
+    }
+    let vary = match headers.get("Vary") {
+        Some(existing) => format!("{existing}, Accept-Encoding"),
+        None => "Accept-Encoding".to_string(),
+    };
+    headers.set("Vary", vary);
+}
+
+/// Compresses response bodies on the fly for clients that accept it.
+///
+/// ```
+/// use hello::compression::Compression;
+///
+/// let compression = Compression::new()
+///     .min_size(512)
+///     .mime_types(["text/*", "application/json"]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: u64,
+    mime_types: Vec<String>,
+    encodings: Vec<Encoding>,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression {
+            min_size: 1024,
+            mime_types: DEFAULT_MIME_TYPES.iter().map(|t| t.to_string()).collect(),
+            encodings: Encoding::ALL.to_vec(),
+        }
+    }
+}
+
+impl Compression {
+    pub fn new() -> Compression {
+        Compression::default()
+    }
+
+    /// Bodies smaller than this many bytes are not worth compressing.
+    /// Defaults to 1 KiB.
+    pub fn min_size(mut self, bytes: u64) -> Compression {
+        self.min_size = bytes;
+        self
+    }
+
+    /// The `Content-Type`s to compress. `text/*` matches every text type;
+    /// parameters such as `charset` are ignored.
+    pub fn mime_types<S: Into<String>>(
+        mut self,
+        types: impl IntoIterator<Item = S>,
+    ) -> Compression {
+        self.mime_types = types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// The codings to offer, most preferred first. Defaults to brotli, then
+    /// gzip.
+    pub fn encodings(mut self, encodings: &[Encoding]) -> Compression {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    fn compresses(&self, content_type: &str) -> bool {
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        self.mime_types.iter().any(|pattern| {
+            let pattern = pattern.to_ascii_lowercase();
+            match pattern.strip_suffix("/*") {
+                Some(kind) => essence.split('/').next() == Some(kind),
+                None => essence == pattern,
+            }
+        })
+    }
+
+    /// Compress `response` for `request` if the client accepts a coding we
+    /// offer and the body is a type and size worth compressing.
+    pub fn apply(&self, request: &Request, response: &mut Response) {
+        let eligible = !response.status.forbids_body()
+            && !matches!(response.body, Body::Stream(_))
+            && response.status != StatusCode::PartialContent
+            && !response.headers.contains("Content-Encoding")
+            && !response.headers.has_token("Cache-Control", "no-transform")
+            && response
+                .headers
+                .get("Content-Type")
+                .is_some_and(|t| self.compresses(t))
+            && response.body.len() >= self.min_size
+            && response.body.len() <= MAX_FILE_SIZE;
+        if !eligible {
+            return;
+        }
+        vary_on_accept_encoding(&mut response.headers);
+
+        let Some(encoding) = negotiate(request.headers.get("Accept-Encoding"), &self.encodings)
+        else {
+            return;
+        };
+        let compressed = body_bytes(&mut response.body).and_then(|data| encoding.compress(data));
+        match compressed {
+            Ok(compressed) if compressed.len() < response.body.len() as usize => {
+                response.headers.set("Content-Encoding", encoding.as_str());
+                // The compressed bytes are a different representation; a weak
+                // tag still lets If-None-Match revalidate it.
+                if let Some(etag) = response.headers.get("ETag") {
+                    if !etag.starts_with("W/") {
+                        let weak = format!("W/{etag}");
+                        response.headers.set("ETag", weak);
+                    }
+                }
+                response.headers.remove("Content-Length");
+                // Ranges would be served from the uncompressed file, not
+                // from these bytes, so don't offer them.
+                response.headers.remove("Accept-Ranges");
+                response.body = Body::Bytes(compressed);
+            }
+            Ok(_) => {}
+            Err(e) => crate::warn!("Problem compressing response: {e}"),
+        }
+    }
+}
+
+/// The whole body in memory. A file body is read in and replaced with the
+/// bytes read, so it can still be sent if compression doesn't pay off.
+fn body_bytes(body: &mut Body) -> io::Result<&[u8]> {
+    if let Body::File { file, offset, len } = body {
+        let mut data = Vec::with_capacity(*len as usize);
+        let mut file: &File = file;
+        file.seek(SeekFrom::Start(*offset))?;
+        file.take(*len).read_to_end(&mut data)?;
+        *body = Body::Bytes(data);
+    }
+    Ok(body.as_bytes().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn request(accept_encoding: &str) -> Request {
+        let raw = format!("GET / HTTP/1.1\r\nAccept-Encoding: {accept_encoding}\r\n\r\n");
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn negotiates_by_quality_then_preference() {
+        let offered = &Encoding::ALL;
+        assert_eq!(negotiate(Some("gzip, br"), offered), Some(Encoding::Brotli));
+        assert_eq!(
+            negotiate(Some("gzip;q=1.0, br;q=0.5"), offered),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(negotiate(Some("br;q=0, *"), offered), Some(Encoding::Gzip));
+        assert_eq!(negotiate(Some("identity"), offered), None);
+        assert_eq!(negotiate(Some("gzip"), &[Encoding::Brotli]), None);
+        assert_eq!(negotiate(None, offered), None);
+    }
+
+    #[test]
+    fn compresses_text_above_the_threshold() {
+        let compression = Compression::new().min_size(100);
+        let text = "hello, world\n".repeat(100);
+
+        let mut response = Response::new(StatusCode::Ok)
+            .header("Accept-Ranges", "bytes")
+            .with_body("text/plain", text.clone());
+        compression.apply(&request("gzip"), &mut response);
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert!(!response.headers.contains("Accept-Ranges"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(response.body.as_bytes().unwrap())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+
+        let mut small = Response::new(StatusCode::Ok).with_body("text/plain", "tiny");
+        compression.apply(&request("gzip"), &mut small);
+        assert!(!small.headers.contains("Content-Encoding"));
+
+        let mut image = Response::new(StatusCode::Ok).with_body("image/png", text.clone());
+        compression.apply(&request("gzip"), &mut image);
+        assert!(!image.headers.contains("Content-Encoding"));
+
+        let mut plain = Response::new(StatusCode::Ok)
+            .header("Vary", "Origin")
+            .with_body("text/html; charset=utf-8", text);
+        compression.apply(&request("identity"), &mut plain);
+        assert!(!plain.headers.contains("Content-Encoding"));
+        assert_eq!(plain.headers.get("Vary"), Some("Origin, Accept-Encoding"));
+    }
+}
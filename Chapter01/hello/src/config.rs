@@ -0,0 +1,1170 @@
+use std::{
+    fmt, fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use toml::Value;
+
+use crate::{
+    access_log, cache::CacheControl, compression, log::Level, rate_limit::Rate,
+    response::StatusCode,
+};
+
+pub const USAGE: &str = "\
+Usage: hello [OPTIONS]
+
+Options:
+  -c, --config <FILE>           TOML config file [default: hello.toml if present]
+  -b, --bind <ADDR>             Address to listen on [default: 127.0.0.1]
+  -p, --port <PORT>             Port to listen on, 0 for any free port [default: 7878]
+  -d, --document-root <DIR>     Directory to serve files from [default: public]
+  -w, --workers <N>             Worker threads [default: available parallelism]
+      --template-dir <DIR>      Directory of page templates [default: templates]
+      --autoindex <BOOL>        List directories without an index.html
+                                [default: false]
+      --drain-period <DUR>      Time to keep accepting, answering /readyz with
+                                503, after a stop signal [default: 0s]
+      --shutdown-timeout <DUR>  Grace period for in-flight requests [default: 30s]
+      --keep-alive-timeout <DUR>
+                                Idle time between requests [default: 5s]
+      --max-requests <N>        Requests per connection [default: 100]
+      --header-timeout <DUR>    Time allowed to send a request's headers
+                                [default: 10s]
+      --read-timeout <DUR>      Longest wait for request data [default: 30s]
+      --write-timeout <DUR>     Longest wait to send response data
+                                [default: 30s]
+      --max-header-size <SIZE>  Request line and headers [default: 8K]
+      --max-headers <N>         Header fields per request [default: 100]
+      --max-body-size <SIZE>    Request body [default: 10M]
+      --max-connections-per-ip <N>
+                                Open connections per client address, 0 for
+                                no limit [default: 64]
+      --log-level <LEVEL>       error, warn, info or debug [default: info]
+      --access-log <DEST>       stdout, off, or a file path [default: stdout]
+      --access-log-format <FMT> common or json [default: common]
+      --access-log-max-size <SIZE>
+                                Rotate the log file past this size, 0 to never
+                                rotate [default: 10M]
+      --access-log-keep <N>     Rotated files to keep [default: 5]
+      --compression <BOOL>      Compress responses with brotli or gzip
+                                [default: true]
+      --compression-min-size <SIZE>
+                                Smallest body worth compressing [default: 1K]
+      --compression-types <LIST>
+                                Comma-separated MIME types to compress, text/*
+                                for all text [default: text/*, JavaScript,
+                                JSON, XML, WebAssembly and SVG]
+      --precompressed <BOOL>    Serve file.br or file.gz in place of file
+                                when present [default: false]
+      --tls-cert <FILE>         PEM certificate chain; enables HTTPS
+      --tls-key <FILE>          PEM private key for --tls-cert
+      --https-port <PORT>       Port to serve HTTPS on [default: 8443]
+      --https-redirect <BOOL>   Answer plain HTTP with redirects to HTTPS
+                                [default: false]
+      --cors-origins <LIST>     Comma-separated origins allowed to call the
+                                server from browsers, * for any [default: none]
+      --security-headers <BOOL> Add nosniff, framing, referrer and content
+                                security policy headers [default: true]
+      --hsts-max-age <DUR>      Strict-Transport-Security on HTTPS responses,
+                                0 for none [default: 0]
+      --metrics-path <PATH>     Where to publish Prometheus metrics, or off
+                                [default: /metrics]
+      --health-checks <BOOL>    Answer liveness probes on /healthz and
+                                readiness probes on /readyz [default: true]
+      --rate-limit <RATE>       Requests per client address, like 10/s, 5/m
+                                or 100/h, or off [default: off]
+      --rate-limit-burst <N>    Requests a client may make at once
+                                [default: the count in --rate-limit]
+  -h, --help                    Print this help
+
+Every option can also be set in the config file using its long name with
+underscores (port = 8080) or in the environment with a HELLO_ prefix
+(HELLO_PORT=8080). Flags override the environment, which overrides the file.
+Durations are whole seconds or a number with an ms, s or m suffix. Sizes are
+bytes or a number with a K, M or G suffix.
+
+Six settings only exist in the config file: a [cache_control] table mapping
+path prefixes to Cache-Control values, an [error_pages] table mapping status
+codes to templates, [[tls_sni]] tables giving the names, cert and key of
+certificates for further host names, [[proxy]] tables passing a path prefix
+on to upstream servers, [[auth]] tables requiring a password or token under
+a path prefix, and a [rate_limit_routes] table mapping path prefixes to
+their own rates.";
+
+const DEFAULT_CONFIG_FILE: &str = "hello.toml";
+
+/// A setting that could not be applied, named as the user wrote it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(key: impl Into<String>, message: impl Into<String>) -> ConfigError {
+        ConfigError {
+            key: key.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub document_root: PathBuf,
+    pub template_dir: PathBuf,
+    /// The template rendered for each error status.
+    pub error_pages: Vec<(StatusCode, String)>,
+    /// List directories that have no `index.html`.
+    pub autoindex: bool,
+    pub workers: usize,
+    /// How long to keep accepting connections after a stop signal, so load
+    /// balancers see readiness fail first.
+    pub drain_period: Duration,
+    pub shutdown_timeout: Duration,
+    pub keep_alive_timeout: Duration,
+    pub max_requests: usize,
+    pub header_timeout: Duration,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub max_header_size: u64,
+    pub max_headers: usize,
+    pub max_body_size: u64,
+    pub max_connections_per_ip: usize,
+    pub log_level: Level,
+    pub access_log: AccessLogDestination,
+    pub access_log_format: access_log::Format,
+    pub access_log_max_size: u64,
+    pub access_log_keep: usize,
+    pub compression: bool,
+    pub compression_min_size: u64,
+    pub compression_types: Vec<String>,
+    pub precompressed: bool,
+    /// `Cache-Control` for static files, by path prefix.
+    pub cache_control: CacheControl,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Certificates for other host names, picked by SNI.
+    pub tls_sni: Vec<SniCertificate>,
+    pub https_port: u16,
+    pub https_redirect: bool,
+    /// Path prefixes passed on to upstream servers.
+    pub proxy: Vec<ProxyRoute>,
+    /// Path prefixes that need credentials.
+    pub auth: Vec<AuthRoute>,
+    /// Origins allowed to make cross-origin requests; empty turns CORS off.
+    pub cors_origins: Vec<String>,
+    pub security_headers: bool,
+    pub hsts_max_age: Duration,
+    /// Where metrics are published; `None` leaves them off.
+    pub metrics_path: Option<String>,
+    pub health_checks: bool,
+    pub rate_limit: Option<Rate>,
+    /// Defaults to the request count of `rate_limit`.
+    pub rate_limit_burst: Option<u32>,
+    /// Path prefixes limited at their own rate instead of `rate_limit`.
+    pub rate_limit_routes: Vec<(String, Rate)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessLogDestination {
+    Off,
+    Stdout,
+    File(PathBuf),
+}
+
+/// One `[[tls_sni]]` table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SniCertificate {
+    pub names: Vec<String>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// One `[[proxy]]` table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyRoute {
+    pub prefix: String,
+    pub upstreams: Vec<String>,
+    /// Remove the prefix from paths before passing them on.
+    pub strip_prefix: bool,
+    /// Send the client's `Host` rather than the upstream's.
+    pub preserve_host: bool,
+    /// Longest wait for an upstream to send or accept data.
+    pub timeout: Duration,
+}
+
+/// One `[[auth]]` table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthRoute {
+    pub prefix: String,
+    pub realm: String,
+    /// An htpasswd file of users allowed in with HTTP Basic.
+    pub htpasswd: Option<PathBuf>,
+    /// Tokens allowed in with `Authorization: Bearer`.
+    pub tokens: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: IpAddr::from([127, 0, 0, 1]),
+            port: 7878,
+            document_root: PathBuf::from("public"),
+            template_dir: PathBuf::from("templates"),
+            error_pages: vec![
+                (StatusCode::BadRequest, "error.html".to_string()),
+                (StatusCode::Unauthorized, "error.html".to_string()),
+                (StatusCode::Forbidden, "error.html".to_string()),
+                (StatusCode::NotFound, "404.html".to_string()),
+                (StatusCode::TooManyRequests, "error.html".to_string()),
+                (StatusCode::InternalServerError, "error.html".to_string()),
+                (StatusCode::BadGateway, "error.html".to_string()),
+                (StatusCode::GatewayTimeout, "error.html".to_string()),
+            ],
+            autoindex: false,
+            workers: thread::available_parallelism().map_or(4, |n| n.get()),
+            drain_period: Duration::ZERO,
+            shutdown_timeout: Duration::from_secs(30),
+            keep_alive_timeout: Duration::from_secs(5),
+            max_requests: 100,
+            header_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_header_size: 8 * 1024,
+            max_headers: 100,
+            max_body_size: 10 * 1024 * 1024,
+            max_connections_per_ip: 64,
+            log_level: Level::Info,
+            access_log: AccessLogDestination::Stdout,
+            access_log_format: access_log::Format::Common,
+            access_log_max_size: 10 * 1024 * 1024,
+            access_log_keep: 5,
+            compression: true,
+            compression_min_size: 1024,
+            compression_types: compression::DEFAULT_MIME_TYPES
+                .iter()
+                .map(|t| t.to_string())
+                .collect(),
+            precompressed: false,
+            cache_control: CacheControl::new(),
+            tls_cert: None,
+            tls_key: None,
+            tls_sni: Vec::new(),
+            https_port: 8443,
+            https_redirect: false,
+            proxy: Vec::new(),
+            auth: Vec::new(),
+            cors_origins: Vec::new(),
+            security_headers: true,
+            hsts_max_age: Duration::ZERO,
+            metrics_path: Some("/metrics".to_string()),
+            health_checks: true,
+            rate_limit: None,
+            rate_limit_burst: None,
+            rate_limit_routes: Vec::new(),
+        }
+    }
+}
+
+/// Where a setting came from, so errors can name it the way the user wrote it.
+#[derive(Clone, Copy)]
+enum Source {
+    File,
+    Env,
+    Flag,
+}
+
+impl Source {
+    fn name(self, key: &str) -> String {
+        match self {
+            Source::File => key.to_string(),
+            Source::Env => format!("HELLO_{}", key.to_ascii_uppercase()),
+            Source::Flag => format!("--{}", key.replace('_', "-")),
+        }
+    }
+}
+
+impl Config {
+    /// Layer the config file, `HELLO_*` environment variables and
+    /// command-line flags over the defaults, in that order.
+    ///
+    /// `args` includes the program name, as `env::args()` does.
+    pub fn build(
+        mut args: impl Iterator<Item = String>,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Config, ConfigError> {
+        args.next();
+
+        let mut flags = Vec::new();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value)),
+                _ => (arg.clone(), None),
+            };
+            let key = flag_key(&flag).ok_or_else(|| ConfigError::new(&flag, "unknown option"))?;
+            let value = match inline {
+                Some(value) => value.to_string(),
+                None => args
+                    .next()
+                    .ok_or_else(|| ConfigError::new(&flag, "missing value"))?,
+            };
+            flags.push((key, value));
+        }
+
+        let vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with("HELLO_"))
+            .collect();
+
+        let explicit_file = flags
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "config")
+            .map(|(_, v)| (Source::Flag, v.clone()))
+            .or_else(|| {
+                vars.iter()
+                    .find(|(name, _)| name == "HELLO_CONFIG")
+                    .map(|(_, v)| (Source::Env, v.clone()))
+            });
+
+        let mut config = Config::default();
+
+        match explicit_file {
+            Some((source, path)) => {
+                let contents = fs::read_to_string(&path)
+                    .map_err(|e| ConfigError::new(source.name("config"), format!("{path}: {e}")))?;
+                config.apply_toml(&contents)?;
+            }
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                let contents = fs::read_to_string(DEFAULT_CONFIG_FILE)
+                    .map_err(|e| ConfigError::new(DEFAULT_CONFIG_FILE, e.to_string()))?;
+                config.apply_toml(&contents)?;
+            }
+            None => {}
+        }
+
+        for (name, value) in &vars {
+            let key = name["HELLO_".len()..].to_ascii_lowercase();
+            if key != "config" {
+                config.set(Source::Env, &key, &Value::String(value.clone()))?;
+            }
+        }
+
+        for (key, value) in &flags {
+            if key != "config" {
+                config.set(Source::Flag, key, &Value::String(value.clone()))?;
+            }
+        }
+
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Whether HTTPS should be served.
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert.is_some()
+    }
+
+    /// Catch settings that only make sense together.
+    fn check(&self) -> Result<(), ConfigError> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(_), None) => return Err(ConfigError::new("tls_key", "required by tls_cert")),
+            (None, Some(_)) => return Err(ConfigError::new("tls_cert", "required by tls_key")),
+            _ => {}
+        }
+        if !self.tls_enabled() {
+            if !self.tls_sni.is_empty() {
+                return Err(ConfigError::new("tls_sni", "requires tls_cert and tls_key"));
+            }
+            if self.https_redirect {
+                return Err(ConfigError::new(
+                    "https_redirect",
+                    "requires tls_cert and tls_key",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the settings in a TOML document.
+    pub fn apply_toml(&mut self, contents: &str) -> Result<(), ConfigError> {
+        let table: toml::Table = contents
+            .parse()
+            .map_err(|e: toml::de::Error| ConfigError::new("config", e.message().to_string()))?;
+
+        for (key, value) in &table {
+            self.set(Source::File, key, value)?;
+        }
+
+        Ok(())
+    }
+
+    fn set(&mut self, source: Source, key: &str, value: &Value) -> Result<(), ConfigError> {
+        let name = source.name(key);
+        let invalid = |message: String| ConfigError::new(&name, message);
+
+        match key {
+            "bind" => {
+                let text = string(value).map_err(invalid)?;
+                self.bind = text
+                    .parse()
+                    .map_err(|_| invalid(format!("expected an IP address, got {text:?}")))?;
+            }
+            "port" => {
+                let port = integer(value).map_err(invalid)?;
+                self.port = u16::try_from(port)
+                    .map_err(|_| invalid(format!("expected a port number, got {port}")))?;
+            }
+            "document_root" => {
+                self.document_root = PathBuf::from(string(value).map_err(invalid)?);
+            }
+            "template_dir" => {
+                self.template_dir = PathBuf::from(string(value).map_err(invalid)?);
+            }
+            "error_pages" => self.error_pages = error_pages(value).map_err(invalid)?,
+            "autoindex" => self.autoindex = boolean(value).map_err(invalid)?,
+            "workers" => self.workers = positive(value).map_err(invalid)?,
+            "drain_period" => self.drain_period = duration(value).map_err(invalid)?,
+            "shutdown_timeout" => self.shutdown_timeout = duration(value).map_err(invalid)?,
+            "keep_alive_timeout" => self.keep_alive_timeout = duration(value).map_err(invalid)?,
+            "max_requests" => self.max_requests = positive(value).map_err(invalid)?,
+            "header_timeout" => self.header_timeout = duration(value).map_err(invalid)?,
+            "read_timeout" => self.read_timeout = duration(value).map_err(invalid)?,
+            "write_timeout" => self.write_timeout = duration(value).map_err(invalid)?,
+            "max_header_size" => self.max_header_size = size(value).map_err(invalid)?,
+            "max_headers" => self.max_headers = positive(value).map_err(invalid)?,
+            "max_body_size" => self.max_body_size = size(value).map_err(invalid)?,
+            "max_connections_per_ip" => {
+                let max = integer(value).map_err(invalid)?;
+                self.max_connections_per_ip = usize::try_from(max)
+                    .map_err(|_| invalid(format!("expected a count, got {max}")))?;
+            }
+            "log_level" => {
+                self.log_level = string(value).map_err(invalid)?.parse().map_err(invalid)?;
+            }
+            "access_log" => {
+                self.access_log = match string(value).map_err(invalid)? {
+                    "off" | "" => AccessLogDestination::Off,
+                    "stdout" | "-" => AccessLogDestination::Stdout,
+                    path => AccessLogDestination::File(PathBuf::from(path)),
+                };
+            }
+            "access_log_format" => {
+                self.access_log_format =
+                    string(value).map_err(invalid)?.parse().map_err(invalid)?;
+            }
+            "access_log_max_size" => self.access_log_max_size = size(value).map_err(invalid)?,
+            "access_log_keep" => {
+                let keep = integer(value).map_err(invalid)?;
+                self.access_log_keep = usize::try_from(keep)
+                    .map_err(|_| invalid(format!("expected a count, got {keep}")))?;
+            }
+            "compression" => self.compression = boolean(value).map_err(invalid)?,
+            "compression_min_size" => self.compression_min_size = size(value).map_err(invalid)?,
+            "compression_types" => self.compression_types = list(value).map_err(invalid)?,
+            "precompressed" => self.precompressed = boolean(value).map_err(invalid)?,
+            "cache_control" => self.cache_control = cache_control(value).map_err(invalid)?,
+            "tls_cert" => self.tls_cert = Some(PathBuf::from(string(value).map_err(invalid)?)),
+            "tls_key" => self.tls_key = Some(PathBuf::from(string(value).map_err(invalid)?)),
+            "tls_sni" => self.tls_sni = sni_certificates(value).map_err(invalid)?,
+            "https_port" => {
+                let port = integer(value).map_err(invalid)?;
+                self.https_port = u16::try_from(port)
+                    .map_err(|_| invalid(format!("expected a port number, got {port}")))?;
+            }
+            "https_redirect" => self.https_redirect = boolean(value).map_err(invalid)?,
+            "proxy" => self.proxy = proxy_routes(value).map_err(invalid)?,
+            "auth" => self.auth = auth_routes(value).map_err(invalid)?,
+            "cors_origins" => self.cors_origins = list(value).map_err(invalid)?,
+            "security_headers" => self.security_headers = boolean(value).map_err(invalid)?,
+            "hsts_max_age" => self.hsts_max_age = duration(value).map_err(invalid)?,
+            "metrics_path" => {
+                self.metrics_path = match string(value).map_err(invalid)? {
+                    "off" | "" => None,
+                    path if path.starts_with('/')
+                        && !path.split('/').any(|s| s.starts_with([':', '*'])) =>
+                    {
+                        Some(path.to_string())
+                    }
+                    path => {
+                        return Err(invalid(format!(
+                            "expected a path like \"/metrics\", got {path:?}"
+                        )))
+                    }
+                };
+            }
+            "health_checks" => self.health_checks = boolean(value).map_err(invalid)?,
+            "rate_limit" => {
+                self.rate_limit = match string(value).map_err(invalid)? {
+                    "off" | "" => None,
+                    rate => Some(rate.parse().map_err(invalid)?),
+                };
+            }
+            "rate_limit_burst" => {
+                let burst = positive(value).map_err(invalid)?;
+                self.rate_limit_burst = Some(
+                    u32::try_from(burst)
+                        .map_err(|_| invalid(format!("expected a count, got {burst}")))?,
+                );
+            }
+            "rate_limit_routes" => {
+                self.rate_limit_routes = rate_limit_routes(value).map_err(invalid)?;
+            }
+            _ => return Err(ConfigError::new(name, "unknown setting")),
+        }
+
+        Ok(())
+    }
+}
+
+fn flag_key(flag: &str) -> Option<String> {
+    let key = match flag {
+        "-c" => "config",
+        "-b" => "bind",
+        "-p" => "port",
+        "-d" => "document-root",
+        "-w" => "workers",
+        _ => flag.strip_prefix("--")?,
+    };
+    let key = key.replace('-', "_");
+
+    let known = [
+        "config",
+        "bind",
+        "port",
+        "document_root",
+        "template_dir",
+        "autoindex",
+        "workers",
+        "drain_period",
+        "shutdown_timeout",
+        "keep_alive_timeout",
+        "max_requests",
+        "header_timeout",
+        "read_timeout",
+        "write_timeout",
+        "max_header_size",
+        "max_headers",
+        "max_body_size",
+        "max_connections_per_ip",
+        "log_level",
+        "access_log",
+        "access_log_format",
+        "access_log_max_size",
+        "access_log_keep",
+        "compression",
+        "compression_min_size",
+        "compression_types",
+        "precompressed",
+        "tls_cert",
+        "tls_key",
+        "https_port",
+        "https_redirect",
+        "cors_origins",
+        "security_headers",
+        "hsts_max_age",
+        "metrics_path",
+        "health_checks",
+        "rate_limit",
+        "rate_limit_burst",
+    ];
+    known.contains(&key.as_str()).then_some(key)
+}
+
+fn string(value: &Value) -> Result<&str, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        other => Err(format!("expected a string, got {}", other.type_str())),
+    }
+}
+
+fn integer(value: &Value) -> Result<i64, String> {
+    match value {
+        Value::Integer(n) => Ok(*n),
+        Value::String(s) => s
+            .trim()
+            .parse()
+            .map_err(|_| format!("expected an integer, got {s:?}")),
+        other => Err(format!("expected an integer, got {}", other.type_str())),
+    }
+}
+
+/// A TOML array of strings, or one comma-separated string.
+fn list(value: &Value) -> Result<Vec<String>, String> {
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .map(|item| string(item).map(String::from))
+            .collect(),
+        Value::String(s) => Ok(s
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect()),
+        other => Err(format!("expected a list, got {}", other.type_str())),
+    }
+}
+
+fn boolean(value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(b) => Ok(*b),
+        Value::String(s) => match s.trim().to_ascii_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Ok(true),
+            "false" | "no" | "off" | "0" => Ok(false),
+            _ => Err(format!("expected true or false, got {s:?}")),
+        },
+        other => Err(format!("expected true or false, got {}", other.type_str())),
+    }
+}
+
+fn positive(value: &Value) -> Result<usize, String> {
+    let n = integer(value)?;
+    usize::try_from(n)
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("expected a positive integer, got {n}"))
+}
+
+fn cache_control(value: &Value) -> Result<CacheControl, String> {
+    let Value::Table(table) = value else {
+        return Err(format!(
+            "expected a table of path prefixes, got {}",
+            value.type_str()
+        ));
+    };
+
+    let mut rules = CacheControl::new();
+    for (prefix, value) in table {
+        if !prefix.starts_with('/') {
+            return Err(format!("path prefixes must start with /, got {prefix:?}"));
+        }
+        rules.rule(prefix, string(value).map_err(|e| format!("{prefix}: {e}"))?);
+    }
+    Ok(rules)
+}
+
+fn rate_limit_routes(value: &Value) -> Result<Vec<(String, Rate)>, String> {
+    let Value::Table(table) = value else {
+        return Err(format!(
+            "expected a table of path prefixes, got {}",
+            value.type_str()
+        ));
+    };
+
+    let mut routes = Vec::new();
+    for (prefix, value) in table {
+        if !prefix.starts_with('/') {
+            return Err(format!("path prefixes must start with /, got {prefix:?}"));
+        }
+        let rate = string(value)
+            .and_then(str::parse)
+            .map_err(|e| format!("{prefix}: {e}"))?;
+        routes.push((prefix.clone(), rate));
+    }
+    Ok(routes)
+}
+
+fn error_pages(value: &Value) -> Result<Vec<(StatusCode, String)>, String> {
+    let Value::Table(table) = value else {
+        return Err(format!(
+            "expected a table of status codes, got {}",
+            value.type_str()
+        ));
+    };
+
+    let mut pages = Vec::new();
+    for (code, value) in table {
+        let status = code
+            .parse()
+            .ok()
+            .and_then(StatusCode::from_code)
+            .filter(|status| status.code() >= 400)
+            .ok_or_else(|| format!("expected an error status code, got {code:?}"))?;
+        let name = string(value).map_err(|e| format!("{code}: {e}"))?;
+        pages.push((status, name.to_string()));
+    }
+    Ok(pages)
+}
+
+fn sni_certificates(value: &Value) -> Result<Vec<SniCertificate>, String> {
+    let Value::Array(tables) = value else {
+        return Err(format!(
+            "expected an array of tables, got {}",
+            value.type_str()
+        ));
+    };
+
+    tables
+        .iter()
+        .map(|table| {
+            let Value::Table(table) = table else {
+                return Err(format!("expected a table, got {}", table.type_str()));
+            };
+            let field = |key: &str| {
+                table
+                    .get(key)
+                    .ok_or_else(|| format!("missing {key}"))
+                    .and_then(|v| string(v).map_err(|e| format!("{key}: {e}")))
+            };
+            let names = match table.get("names") {
+                Some(Value::Array(names)) => names
+                    .iter()
+                    .map(|name| string(name).map(String::from))
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| format!("names: {e}"))?,
+                Some(other) => {
+                    return Err(format!(
+                        "names: expected an array, got {}",
+                        other.type_str()
+                    ))
+                }
+                None => return Err("missing names".to_string()),
+            };
+
+            Ok(SniCertificate {
+                names,
+                cert: PathBuf::from(field("cert")?),
+                key: PathBuf::from(field("key")?),
+            })
+        })
+        .collect()
+}
+
+fn proxy_routes(value: &Value) -> Result<Vec<ProxyRoute>, String> {
+    let Value::Array(tables) = value else {
+        return Err(format!(
+            "expected an array of tables, got {}",
+            value.type_str()
+        ));
+    };
+
+    tables
+        .iter()
+        .map(|table| {
+            let Value::Table(table) = table else {
+                return Err(format!("expected a table, got {}", table.type_str()));
+            };
+
+            let prefix = table
+                .get("prefix")
+                .ok_or_else(|| "missing prefix".to_string())
+                .and_then(|v| string(v).map_err(|e| format!("prefix: {e}")))?;
+            // The prefix becomes a route pattern, so it can't use pattern syntax.
+            if !prefix.starts_with('/')
+                || prefix
+                    .split('/')
+                    .any(|segment| segment.starts_with([':', '*']))
+            {
+                return Err(format!(
+                    "prefix: expected a path like \"/api\", got {prefix:?}"
+                ));
+            }
+            let upstreams = table
+                .get("upstreams")
+                .ok_or_else(|| "missing upstreams".to_string())
+                .and_then(|v| list(v).map_err(|e| format!("upstreams: {e}")))?;
+            if upstreams.is_empty() {
+                return Err("upstreams: expected at least one".to_string());
+            }
+            let flag = |key: &str| {
+                table
+                    .get(key)
+                    .map_or(Ok(false), |v| boolean(v).map_err(|e| format!("{key}: {e}")))
+            };
+            let timeout = table
+                .get("timeout")
+                .map_or(Ok(Duration::from_secs(30)), |v| {
+                    duration(v).map_err(|e| format!("timeout: {e}"))
+                })?;
+
+            Ok(ProxyRoute {
+                prefix: prefix.to_string(),
+                upstreams,
+                strip_prefix: flag("strip_prefix")?,
+                preserve_host: flag("preserve_host")?,
+                timeout,
+            })
+        })
+        .collect()
+}
+
+fn auth_routes(value: &Value) -> Result<Vec<AuthRoute>, String> {
+    let Value::Array(tables) = value else {
+        return Err(format!(
+            "expected an array of tables, got {}",
+            value.type_str()
+        ));
+    };
+
+    tables
+        .iter()
+        .map(|table| {
+            let Value::Table(table) = table else {
+                return Err(format!("expected a table, got {}", table.type_str()));
+            };
+
+            let prefix = table
+                .get("prefix")
+                .ok_or_else(|| "missing prefix".to_string())
+                .and_then(|v| string(v).map_err(|e| format!("prefix: {e}")))?;
+            if !prefix.starts_with('/') {
+                return Err(format!(
+                    "prefix: expected a path like \"/admin\", got {prefix:?}"
+                ));
+            }
+            let realm = table.get("realm").map_or(Ok("hello"), |v| {
+                string(v).map_err(|e| format!("realm: {e}"))
+            })?;
+            let htpasswd = table
+                .get("htpasswd")
+                .map(|v| string(v).map(PathBuf::from))
+                .transpose()
+                .map_err(|e| format!("htpasswd: {e}"))?;
+            let tokens = table
+                .get("tokens")
+                .map_or(Ok(Vec::new()), list)
+                .map_err(|e| format!("tokens: {e}"))?;
+            if htpasswd.is_none() && tokens.is_empty() {
+                return Err(format!("{prefix}: expected htpasswd or tokens"));
+            }
+
+            Ok(AuthRoute {
+                prefix: prefix.to_string(),
+                realm: realm.to_string(),
+                htpasswd,
+                tokens,
+            })
+        })
+        .collect()
+}
+
+fn duration(value: &Value) -> Result<Duration, String> {
+    let text = match value {
+        Value::Integer(n) if *n >= 0 => return Ok(Duration::from_secs(*n as u64)),
+        Value::String(s) => s.trim(),
+        other => return Err(format!("expected a duration, got {}", other.type_str())),
+    };
+
+    let (number, scale) = if let Some(ms) = text.strip_suffix("ms") {
+        (ms, 1)
+    } else if let Some(s) = text.strip_suffix('s') {
+        (s, 1_000)
+    } else if let Some(m) = text.strip_suffix('m') {
+        (m, 60_000)
+    } else {
+        (text, 1_000)
+    };
+
+    let n = number
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("expected a duration like 30, \"500ms\" or \"2m\", got {text:?}"))?;
+    n.checked_mul(scale)
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("duration {text:?} is too long"))
+}
+
+fn size(value: &Value) -> Result<u64, String> {
+    let text = match value {
+        Value::Integer(n) if *n >= 0 => return Ok(*n as u64),
+        Value::String(s) => s.trim(),
+        other => return Err(format!("expected a size, got {}", other.type_str())),
+    };
+
+    let (number, scale) = match text.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&text[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&text[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&text[..i], 1 << 30),
+        _ => (text, 1),
+    };
+
+    let n = number
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| format!("expected a size like 1048576 or \"10M\", got {text:?}"))?;
+    n.checked_mul(scale)
+        .ok_or_else(|| format!("size {text:?} is too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> std::vec::IntoIter<String> {
+        std::iter::once("hello")
+            .chain(list.iter().copied())
+            .map(String::from)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn vars(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn flags_override_env_override_file() {
+        let mut config = Config::default();
+        config
+            .apply_toml("port = 8000\nworkers = 2\nkeep_alive_timeout = \"250ms\"\n")
+            .unwrap();
+        assert_eq!(config.port, 8000);
+        assert_eq!(config.workers, 2);
+        assert_eq!(config.keep_alive_timeout, Duration::from_millis(250));
+
+        let config = Config::build(
+            args(&["--port", "9001", "-b", "0.0.0.0", "--log-level=debug"]),
+            vars(&[
+                ("HELLO_PORT", "9000"),
+                ("HELLO_WORKERS", "3"),
+                ("PATH", "/bin"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.port, 9001);
+        assert_eq!(config.workers, 3);
+        assert_eq!(config.bind, IpAddr::from([0, 0, 0, 0]));
+        assert_eq!(config.log_level, Level::Debug);
+
+        let config = Config::build(
+            args(&[
+                "--access-log",
+                "logs/access.log",
+                "--access-log-max-size",
+                "2M",
+            ]),
+            vars(&[("HELLO_ACCESS_LOG_FORMAT", "json")]),
+        )
+        .unwrap();
+        assert_eq!(
+            config.access_log,
+            AccessLogDestination::File(PathBuf::from("logs/access.log"))
+        );
+        assert_eq!(config.access_log_format, access_log::Format::Json);
+        assert_eq!(config.access_log_max_size, 2 * 1024 * 1024);
+
+        let config = Config::build(
+            args(&["--max-body-size", "1M", "--header-timeout=2s"]),
+            vars(&[("HELLO_MAX_CONNECTIONS_PER_IP", "0")]),
+        )
+        .unwrap();
+        assert_eq!(config.max_body_size, 1024 * 1024);
+        assert_eq!(config.header_timeout, Duration::from_secs(2));
+        assert_eq!(config.max_connections_per_ip, 0);
+
+        let config = Config::build(
+            args(&["--compression-types", "text/html, application/json"]),
+            vars(&[("HELLO_COMPRESSION", "off"), ("HELLO_AUTOINDEX", "true")]),
+        )
+        .unwrap();
+        assert!(!config.compression);
+        assert!(config.autoindex);
+        assert_eq!(config.compression_types, ["text/html", "application/json"]);
+
+        let config = Config::build(
+            args(&["--cors-origins", "https://a.example,https://b.example"]),
+            vars(&[
+                ("HELLO_SECURITY_HEADERS", "false"),
+                ("HELLO_HSTS_MAX_AGE", "60m"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            config.cors_origins,
+            ["https://a.example", "https://b.example"]
+        );
+        assert!(!config.security_headers);
+        assert_eq!(config.hsts_max_age, Duration::from_secs(3600));
+
+        let config = Config::build(
+            args(&["--rate-limit", "5/m"]),
+            vars(&[("HELLO_RATE_LIMIT_BURST", "10")]),
+        )
+        .unwrap();
+        assert_eq!(config.rate_limit, Some(Rate::per_minute(5)));
+        assert_eq!(config.rate_limit_burst, Some(10));
+
+        let config = Config::build(
+            args(&["--metrics-path", "off"]),
+            vars(&[("HELLO_HEALTH_CHECKS", "false")]),
+        )
+        .unwrap();
+        assert_eq!(config.metrics_path, None);
+        assert!(!config.health_checks);
+        let config = Config::build(args(&[]), vars(&[("HELLO_DRAIN_PERIOD", "10s")])).unwrap();
+        assert_eq!(config.drain_period, Duration::from_secs(10));
+        let config = Config::build(args(&[]), vars(&[("HELLO_METRICS_PATH", "/_stats")])).unwrap();
+        assert_eq!(config.metrics_path.as_deref(), Some("/_stats"));
+    }
+
+    #[test]
+    fn errors_name_the_bad_key() {
+        let err = Config::build(args(&["--port", "http"]), vars(&[])).unwrap_err();
+        assert_eq!(err.key, "--port");
+
+        let err = Config::build(args(&[]), vars(&[("HELLO_WORKERS", "0")])).unwrap_err();
+        assert_eq!(err.key, "HELLO_WORKERS");
+
+        let err = Config::build(args(&["--colour", "red"]), vars(&[])).unwrap_err();
+        assert_eq!(err.key, "--colour");
+
+        let err = Config::build(
+            args(&["--keep-alive-timeout", "18446744073709551615s"]),
+            vars(&[]),
+        )
+        .unwrap_err();
+        assert_eq!(err.key, "--keep-alive-timeout");
+        let err = Config::build(
+            args(&["--max-body-size", "18446744073709551615G"]),
+            vars(&[]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--max-body-size: size \"18446744073709551615G\" is too large"
+        );
+
+        let mut config = Config::default();
+        let err = config
+            .apply_toml("shutdown_timeout = \"soon\"")
+            .unwrap_err();
+        assert_eq!(err.key, "shutdown_timeout");
+        let err = config.apply_toml("prot = 80").unwrap_err();
+        assert_eq!(err.key, "prot");
+        let err = config.apply_toml("port = 70000").unwrap_err();
+        assert_eq!(err.to_string(), "port: expected a port number, got 70000");
+
+        let err = Config::build(args(&["--tls-cert", "cert.pem"]), vars(&[])).unwrap_err();
+        assert_eq!(err.to_string(), "tls_key: required by tls_cert");
+        let err = config
+            .apply_toml("[[tls_sni]]\nnames = [\"a.example\"]\ncert = \"a.pem\"\n")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "tls_sni: missing key");
+        let err = config
+            .apply_toml("[[proxy]]\nprefix = \"/api/:id\"\nupstreams = [\"b:80\"]\n")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "proxy: prefix: expected a path like \"/api\", got \"/api/:id\""
+        );
+        let err = config.apply_toml("rate_limit = \"fast\"").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "rate_limit: expected a rate like \"10/s\", \"5/m\" or \"100/h\", got \"fast\""
+        );
+        let err = config
+            .apply_toml("[[auth]]\nprefix = \"/admin\"\n")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "auth: /admin: expected htpasswd or tokens");
+        let err = config
+            .apply_toml("[error_pages]\n200 = \"ok.html\"\n")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "error_pages: expected an error status code, got \"200\""
+        );
+    }
+
+    #[test]
+    fn reads_tables() {
+        let mut config = Config::default();
+        config
+            .apply_toml(
+                "tls_cert = \"default.pem\"\n\
+                 tls_key = \"default.key\"\n\
+                 https_redirect = true\n\
+                 [cache_control]\n\
+                 \"/assets/\" = \"max-age=600\"\n\
+                 [error_pages]\n\
+                 404 = \"missing.html\"\n\
+                 [[tls_sni]]\n\
+                 names = [\"a.example\", \"*.a.example\"]\n\
+                 cert = \"a.pem\"\n\
+                 key = \"a.key\"\n\
+                 [[proxy]]\n\
+                 prefix = \"/api\"\n\
+                 upstreams = [\"http://127.0.0.1:9000\", \"127.0.0.1:9001\"]\n\
+                 strip_prefix = true\n\
+                 timeout = \"5s\"\n\
+                 [[auth]]\n\
+                 prefix = \"/admin\"\n\
+                 htpasswd = \"admins.htpasswd\"\n\
+                 [[auth]]\n\
+                 prefix = \"/api\"\n\
+                 realm = \"API\"\n\
+                 tokens = [\"t1\", \"t2\"]\n\
+                 [rate_limit_routes]\n\
+                 \"/login\" = \"5/m\"\n",
+            )
+            .unwrap();
+
+        assert!(config.tls_enabled() && config.https_redirect);
+        assert_eq!(
+            config.cache_control.for_path("/assets/a.css"),
+            Some("max-age=600")
+        );
+        assert_eq!(
+            config.error_pages,
+            [(StatusCode::NotFound, "missing.html".to_string())]
+        );
+        assert_eq!(
+            config.tls_sni,
+            vec![SniCertificate {
+                names: vec!["a.example".to_string(), "*.a.example".to_string()],
+                cert: PathBuf::from("a.pem"),
+                key: PathBuf::from("a.key"),
+            }]
+        );
+        assert_eq!(
+            config.proxy,
+            vec![ProxyRoute {
+                prefix: "/api".to_string(),
+                upstreams: vec![
+                    "http://127.0.0.1:9000".to_string(),
+                    "127.0.0.1:9001".to_string()
+                ],
+                strip_prefix: true,
+                preserve_host: false,
+                timeout: Duration::from_secs(5),
+            }]
+        );
+        assert_eq!(
+            config.auth,
+            vec![
+                AuthRoute {
+                    prefix: "/admin".to_string(),
+                    realm: "hello".to_string(),
+                    htpasswd: Some(PathBuf::from("admins.htpasswd")),
+                    tokens: Vec::new(),
+                },
+                AuthRoute {
+                    prefix: "/api".to_string(),
+                    realm: "API".to_string(),
+                    htpasswd: None,
+                    tokens: vec!["t1".to_string(), "t2".to_string()],
+                },
+            ]
+        );
+        assert_eq!(
+            config.rate_limit_routes,
+            [("/login".to_string(), Rate::per_minute(5))]
+        );
+    }
+}
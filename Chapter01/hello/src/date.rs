@@ -0,0 +1,230 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+/// A UTC calendar time, accurate to the second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// Days since Sunday.
+    pub weekday: u32,
+}
+
+impl DateTime {
+    pub fn from_system_time(time: SystemTime) -> DateTime {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+        let (year, month, day) = civil_from_days(days);
+
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u32,
+            minute: (rem % 3600 / 60) as u32,
+            second: (rem % 60) as u32,
+            // 1970-01-01 was a Thursday.
+            weekday: (days + 4).rem_euclid(7) as u32,
+        }
+    }
+
+    /// Parse any of the three date formats HTTP recipients must accept
+    /// (RFC 9110 section 5.6.7):
+    ///
+    /// - `Sun, 06 Nov 1994 08:49:37 GMT` (IMF-fixdate)
+    /// - `Sunday, 06-Nov-94 08:49:37 GMT` (RFC 850)
+    /// - `Sun Nov  6 08:49:37 1994` (asctime)
+    ///
+    /// Years outside 1970 to 9999 are refused.
+    pub fn parse_http(s: &str) -> Option<DateTime> {
+        let (weekday, rest) = s.trim().split_once([',', ' '])?;
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+
+        let (day, month, year, time) = match fields[..] {
+            [day, month, year, time, "GMT"] if s.contains(',') && weekday.len() == 3 => {
+                (day, month, year.parse().ok()?, time)
+            }
+            [date, time, "GMT"] if s.contains(',') => {
+                let mut parts = date.split('-');
+                let (day, month, year) = (parts.next()?, parts.next()?, parts.next()?);
+                // RFC 850 years have two digits; nothing predates 1970 here.
+                if year.len() != 2 {
+                    return None;
+                }
+                let year: i64 = year.parse().ok()?;
+                let year = if year < 70 { 2000 + year } else { 1900 + year };
+                (day, month, year, time)
+            }
+            [month, day, time, year] => (day, month, year.parse().ok()?, time),
+            _ => return None,
+        };
+        // Anything else is bogus, and far-off years would overflow the
+        // arithmetic below.
+        if !(1970..=9999).contains(&year) {
+            return None;
+        }
+
+        let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+        let day: u32 = day.parse().ok()?;
+        let mut clock = time.split(':').map(|n| n.parse::<u32>().ok());
+        let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+        if clock.next().is_some()
+            || !(1..=days_in_month(year, month)).contains(&day)
+            || hour > 23
+            || minute > 59
+            || second > 60
+        {
+            return None;
+        }
+
+        let days = days_from_civil(year, month, day);
+        Some(DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second: second.min(59),
+            weekday: (days + 4).rem_euclid(7) as u32,
+        })
+    }
+
+    pub fn to_system_time(&self) -> SystemTime {
+        let secs = days_from_civil(self.year, self.month, self.day) * 86_400
+            + i64::from(self.hour * 3600 + self.minute * 60 + self.second);
+        match u64::try_from(secs) {
+            Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+            Err(_) => UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()),
+        }
+    }
+
+    /// `10/Oct/2000:13:55:36 +0000`, as used by the Common Log Format.
+    pub fn clf(&self) -> String {
+        format!(
+            "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// `Tue, 10 Oct 2000 13:55:36 GMT`, the IMF-fixdate form HTTP uses.
+    pub fn http(&self) -> String {
+        format!(
+            "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            WEEKDAYS[self.weekday as usize],
+            self.day,
+            MONTHS[self.month as usize - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// `2000-10-10T13:55:36Z`
+    pub fn rfc3339(&self) -> String {
+        format!(
+            "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) triple.
+///
+/// Howard Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+/// The inverse of [`civil_from_days`].
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn formats_known_instants() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        let dt = DateTime::from_system_time(time);
+
+        assert_eq!(dt.clf(), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(dt.rfc3339(), "2000-10-10T13:55:36Z");
+        assert_eq!(dt.http(), "Tue, 10 Oct 2000 13:55:36 GMT");
+    }
+
+    #[test]
+    fn parses_all_three_http_formats() {
+        let expected = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        for text in [
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            let dt = DateTime::parse_http(text).unwrap();
+            assert_eq!(dt.to_system_time(), expected, "{text}");
+            assert_eq!(dt.http(), "Sun, 06 Nov 1994 08:49:37 GMT");
+        }
+
+        for text in [
+            "",
+            "Sun, 06 Nov 1994 08:49:37",
+            "Sun, 31 Feb 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 25:49:37 GMT",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 9223372036854775807 08:49:37 GMT",
+            "Sun, 06 Nov 99999999999999 08:49:37 GMT",
+            "Sun, 06 Nov 10000 08:49:37 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+            "Sunday, 06-Nov-9223372036854775807 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 99999999999999",
+        ] {
+            assert_eq!(DateTime::parse_http(text), None, "{text}");
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::{any::Any, fmt, io};
+
+use crate::{request::ParseError, response::StatusCode};
+
+/// Why serving a connection stopped early.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to the socket failed.
+    Io(io::Error),
+    /// The client sent something that is not a valid request.
+    Parse(ParseError),
+    /// A handler panicked while building the response.
+    Handler(String),
+}
+
+impl Error {
+    /// The status to answer with, or `None` if the connection should just
+    /// be dropped because the client can no longer be reached.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::Io(_) => None,
+            Error::Parse(e) => e.status(),
+            Error::Handler(_) => Some(StatusCode::InternalServerError),
+        }
+    }
+
+    /// Whether this is routine client behaviour, such as hanging up, rather
+    /// than something an operator should see.
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            Error::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut
+            ),
+            Error::Parse(e) => !e.is_malformed(),
+            Error::Handler(_) => false,
+        }
+    }
+
+    pub(crate) fn from_panic(payload: Box<dyn Any + Send>) -> Error {
+        let message = if let Some(s) = payload.downcast_ref::<&str>() {
+            s.to_string()
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            s.clone()
+        } else {
+            "unknown panic payload".to_string()
+        };
+        Error::Handler(message)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+            Error::Parse(e) => write!(f, "bad request: {e}"),
+            Error::Handler(message) => write!(f, "handler panicked: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Parse(e) => Some(e),
+            Error::Handler(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Error {
+        match e {
+            ParseError::Io(e) => Error::Io(e),
+            e => Error::Parse(e),
+        }
+    }
+}
@@ -0,0 +1,100 @@
+/// An ordered list of HTTP header fields with case-insensitive lookup.
+///
+/// Names keep the case they were received or inserted with, so responses go
+/// out exactly as handlers wrote them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// The first value for `name`, if any.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Whether any comma-separated element of `name` equals `token`,
+    /// ignoring ASCII case. Useful for `Connection` and `Transfer-Encoding`.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Add a field, keeping any existing fields with the same name.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.fields.push((name.into(), value.into()));
+    }
+
+    /// Replace every field named `name` with a single one.
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.fields.push((name, value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_ignores_case() {
+        let mut headers = Headers::new();
+        headers.append("Content-Type", "text/html");
+        headers.append("Accept", "text/html");
+        headers.append("accept", "application/json");
+
+        assert_eq!(headers.get("content-type"), Some("text/html"));
+        assert_eq!(
+            headers.get_all("ACCEPT").collect::<Vec<_>>(),
+            vec!["text/html", "application/json"]
+        );
+
+        headers.set("ACCEPT", "*/*");
+        assert_eq!(headers.get_all("accept").collect::<Vec<_>>(), vec!["*/*"]);
+    }
+
+    #[test]
+    fn finds_tokens_in_lists() {
+        let mut headers = Headers::new();
+        headers.append("Connection", "Upgrade, Keep-Alive");
+
+        assert!(headers.has_token("connection", "keep-alive"));
+        assert!(!headers.has_token("connection", "close"));
+    }
+}
@@ -0,0 +1,192 @@
+//! Liveness and readiness probes.
+//!
+//! [`Health::liveness`] answers `200` whenever the server can answer at
+//! all. [`Health::readiness`] runs every check and answers `503` if any of
+//! them fails, so a load balancer stops sending traffic while the server
+//! drains or its files are out of reach:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use hello::{health::Health, router::Router, server::Server};
+//!
+//! let server = Server::bind("127.0.0.1:0").unwrap();
+//! let health = Arc::new(
+//!     Health::new()
+//!         .shutdown(server.shutdown_handle())
+//!         .readable_dir("document_root", "public"),
+//! );
+//!
+//! let mut router = Router::new();
+//! let live = Arc::clone(&health);
+//! router
+//!     .get("/healthz", move |_| live.liveness())
+//!     .get("/readyz", move |_| health.readiness());
+//! server.serve(router);
+//! ```
+//!
+//! Both bodies are JSON describing each check:
+//!
+//! ```text
+//! {"status":"fail","checks":{"shutdown":{"status":"ok"},
+//!  "document_root":{"status":"fail","error":"public: Permission denied (os error 13)"}}}
+//! ```
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    access_log::json_string,
+    response::{Response, StatusCode},
+    shutdown::Shutdown,
+};
+
+/// A named readiness check: `Err` says what is wrong.
+type Check = Box<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+/// The checks behind `/healthz` and `/readyz`.
+#[derive(Default)]
+pub struct Health {
+    checks: Vec<(String, Check)>,
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health::default()
+    }
+
+    /// Add a readiness check named `name`.
+    pub fn check(
+        mut self,
+        name: &str,
+        check: impl Fn() -> Result<(), String> + Send + Sync + 'static,
+    ) -> Health {
+        self.checks.push((name.to_string(), Box::new(check)));
+        self
+    }
+
+    /// Report not ready once `shutdown` is triggered, while in-flight
+    /// requests drain. Give the server a
+    /// [`drain_period`](crate::server::Server::drain_period) so probes still
+    /// get through to see it.
+    pub fn shutdown(self, shutdown: Shutdown) -> Health {
+        self.check("shutdown", move || {
+            if shutdown.is_triggered() {
+                Err("shutting down".to_string())
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    /// Report not ready while the directory at `path` can't be listed.
+    pub fn readable_dir(self, name: &str, path: impl Into<PathBuf>) -> Health {
+        let path = path.into();
+        self.check(name, move || readable(&path))
+    }
+
+    /// A `200`: the server is up and answering.
+    pub fn liveness(&self) -> Response {
+        json(
+            StatusCode::Ok,
+            "{\"status\":\"ok\",\"checks\":{}}".to_string(),
+        )
+    }
+
+    /// A `200` if every check passes and a `503` otherwise.
+    pub fn readiness(&self) -> Response {
+        let mut ready = true;
+        let mut checks = Vec::with_capacity(self.checks.len());
+        for (name, check) in &self.checks {
+            let result = match check() {
+                Ok(()) => "{\"status\":\"ok\"}".to_string(),
+                Err(err) => {
+                    ready = false;
+                    format!("{{\"status\":\"fail\",\"error\":{}}}", json_string(&err))
+                }
+            };
+            checks.push(format!("{}:{result}", json_string(name)));
+        }
+
+        let (status, word) = if ready {
+            (StatusCode::Ok, "ok")
+        } else {
+            (StatusCode::ServiceUnavailable, "fail")
+        };
+        let body = format!(
+            "{{\"status\":\"{word}\",\"checks\":{{{}}}}}",
+            checks.join(",")
+        );
+        json(status, body)
+    }
+}
+
+fn readable(path: &Path) -> Result<(), String> {
+    fs::read_dir(path)
+        .map(|_| ())
+        .map_err(|err| format!("{}: {err}", path.display()))
+}
+
+/// A probe answer, never cached: a stale `200` is worse than none.
+fn json(status: StatusCode, body: String) -> Response {
+    Response::new(status)
+        .header("Cache-Control", "no-store")
+        .with_body("application/json", body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn body(response: &Response) -> String {
+        String::from_utf8(response.body.as_bytes().unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn describes_each_check() {
+        let health = Health::new()
+            .check("cache", || Ok(()))
+            .readable_dir("document_root", "no/such/dir");
+
+        let response = health.readiness();
+        assert_eq!(response.status, StatusCode::ServiceUnavailable);
+        let text = body(&response);
+        assert!(text.starts_with(
+            "{\"status\":\"fail\",\"checks\":{\"cache\":{\"status\":\"ok\"},\
+             \"document_root\":{\"status\":\"fail\",\"error\":\"no/such/dir: "
+        ));
+        assert!(text.ends_with("\"}}}"));
+
+        // Liveness doesn't depend on the checks.
+        let response = health.liveness();
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(body(&response), "{\"status\":\"ok\",\"checks\":{}}");
+    }
+
+    #[test]
+    fn not_ready_once_shutting_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let shutdown = Shutdown::new(&listener).unwrap();
+        let health = Health::new()
+            .shutdown(shutdown.clone())
+            .readable_dir("document_root", ".");
+
+        let response = health.readiness();
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(
+            body(&response),
+            "{\"status\":\"ok\",\"checks\":{\"shutdown\":{\"status\":\"ok\"},\
+             \"document_root\":{\"status\":\"ok\"}}}"
+        );
+
+        shutdown.trigger();
+        let response = health.readiness();
+        assert_eq!(response.status, StatusCode::ServiceUnavailable);
+        assert!(body(&response)
+            .contains("\"shutdown\":{\"status\":\"fail\",\"error\":\"shutting down\"}"));
+    }
+}
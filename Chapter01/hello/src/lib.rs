@@ -0,0 +1,254 @@
+//! A small multithreaded HTTP/1.1 server.
+//!
+//! The `hello` binary is one user of this library; other programs can embed
+//! the server with their own handlers:
+//!
+//! ```no_run
+//! use hello::{
+//!     response::{Response, StatusCode},
+//!     router::Router,
+//!     server::Server,
+//! };
+//!
+//! let mut router = Router::new();
+//! router.get("/users/:id", |req| {
+//!     let id = req.param("id").unwrap_or_default().to_string();
+//!     Response::new(StatusCode::Ok).with_body("text/plain", id)
+//! });
+//!
+//! Server::bind("127.0.0.1:0").unwrap().serve(router);
+//! ```
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub mod access_log;
+pub mod auth;
+pub mod autoindex;
+pub mod cache;
+pub mod compression;
+pub mod config;
+pub mod date;
+pub mod error;
+pub mod headers;
+pub mod health;
+pub mod log;
+pub mod metrics;
+pub mod middleware;
+pub mod mime;
+pub mod proxy;
+pub mod range;
+pub mod rate_limit;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod server;
+pub mod shutdown;
+pub mod static_files;
+pub mod template;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod websocket;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed-size pool of worker threads pulling jobs from a bounded queue.
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    sender: Option<mpsc::SyncSender<Job>>,
+}
+
+impl ThreadPool {
+    /// Create a new ThreadPool.
+    ///
+    /// The size is the number of threads in the pool. At most `size` jobs
+    /// can be queued while all workers are busy; `execute` blocks after that.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if the size is zero.
+    pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::with_queue(size, size)
+    }
+
+    /// Create a new ThreadPool with an explicit queue capacity.
+    ///
+    /// # Panics
+    ///
+    /// The `with_queue` function will panic if the size is zero.
+    pub fn with_queue(size: usize, queue: usize) -> ThreadPool {
+        assert!(size > 0);
+
+        let (sender, receiver) = mpsc::sync_channel(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let mut workers = Vec::with_capacity(size);
+
+        for id in 0..size {
+            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        }
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let job = Box::new(f);
+
+        self.sender.as_ref().unwrap().send(job).unwrap();
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Stop accepting jobs and wait up to `timeout` for queued and running
+    /// jobs to finish.
+    ///
+    /// Returns `false` if some workers were still busy when the timeout
+    /// expired; those threads are detached rather than joined.
+    pub fn shutdown(mut self, timeout: Duration) -> bool {
+        drop(self.sender.take());
+
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if self.workers.iter().all(Worker::is_finished) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut clean = true;
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                if thread.is_finished() {
+                    thread.join().unwrap();
+                } else {
+                    clean = false;
+                }
+            }
+        }
+
+        clean
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                thread.join().unwrap();
+            }
+        }
+    }
+}
+
+struct Worker {
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
+        let thread = thread::Builder::new()
+            .name(format!("worker-{id}"))
+            .spawn(move || loop {
+                let message = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+
+                match message {
+                    // A panicking job must not take the worker down with it.
+                    Ok(job) => {
+                        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                    }
+                    Err(_) => break,
+                }
+            })
+            .unwrap();
+
+        Worker {
+            thread: Some(thread),
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(|t| t.is_finished())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn runs_every_job() {
+        let counter = Arc::new(AtomicUsize::new(0));
+
+        {
+            let pool = ThreadPool::new(4);
+            for _ in 0..32 {
+                let counter = Arc::clone(&counter);
+                pool.execute(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        }
+
+        assert_eq!(counter.load(Ordering::SeqCst), 32);
+    }
+
+    #[test]
+    fn shutdown_waits_for_running_jobs() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(2);
+        for _ in 0..2 {
+            let counter = Arc::clone(&counter);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(50));
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn shutdown_gives_up_after_timeout() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| thread::sleep(Duration::from_millis(500)));
+
+        assert!(!pool.shutdown(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic!("job failed"));
+
+        let done = Arc::clone(&counter);
+        pool.execute(move || {
+            done.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_size_panics() {
+        ThreadPool::new(0);
+    }
+}
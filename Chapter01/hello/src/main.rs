@@ -1,31 +1,357 @@
-use std::{
-    fs,
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
+use std::{env, process, sync::Arc, thread};
+
+#[cfg(feature = "tls")]
+use hello::tls::{Certificates, HttpsRedirect, ServerConfig};
+use hello::{
+    access_log::{AccessLog, RotatingFile, Sink},
+    auth::{Auth, AuthRule, Htpasswd},
+    compression::Compression,
+    config::{AccessLogDestination, Config, USAGE},
+    error,
+    health::Health,
+    info, log,
+    metrics::Metrics,
+    middleware::{CatchPanic, Chain, Cors, RequestId, SecurityHeaders, Timing},
+    proxy::Proxy,
+    rate_limit::RateLimit,
+    request::{Limits, Method},
+    response::{Response, StatusCode},
+    router::{Handler, Router},
+    server::Server,
+    static_files::{Reject, StaticFiles},
+    template::{ErrorPages, Templates, Vars},
 };
+
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+    if env::args()
+        .skip(1)
+        .any(|arg| arg == "-h" || arg == "--help")
+    {
+        println!("{USAGE}");
+        return;
+    }
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        handle_connection(stream);
+    let config = Config::build(env::args(), env::vars()).unwrap_or_else(|err| {
+        eprintln!("Problem parsing configuration: {err}");
+        process::exit(2);
+    });
+    log::set_max_level(config.log_level);
+
+    let files = StaticFiles::new(&config.document_root).unwrap_or_else(|err| {
+        eprintln!(
+            "Problem parsing configuration: document_root: {}: {err}",
+            config.document_root.display()
+        );
+        process::exit(2);
+    });
+    let files = files
+        .precompressed(config.precompressed)
+        .autoindex(config.autoindex)
+        .cache_control(config.cache_control.clone());
+    let files = Arc::new(files);
+
+    let templates = Templates::load(&config.template_dir).unwrap_or_else(|err| {
+        eprintln!(
+            "Problem parsing configuration: template_dir: {}: {err}",
+            config.template_dir.display()
+        );
+        process::exit(2);
+    });
+    let templates = Arc::new(templates);
+    let mut error_pages = ErrorPages::new(Arc::clone(&templates));
+    for (status, name) in &config.error_pages {
+        if !templates.contains(name) {
+            eprintln!(
+                "Problem parsing configuration: error_pages: no template {name:?} in {}",
+                config.template_dir.display()
+            );
+            process::exit(2);
+        }
+        error_pages = error_pages.page(*status, name);
     }
-}
 
-fn handle_connection(mut stream: TcpStream) {
-    let buf_reader = BufReader::new(&stream);
-    let request_line = buf_reader.lines().next().unwrap().unwrap();
+    let access_log = match &config.access_log {
+        AccessLogDestination::Off => None,
+        AccessLogDestination::Stdout => Some(Sink::Stdout),
+        AccessLogDestination::File(path) => {
+            let file = RotatingFile::open(path, config.access_log_max_size, config.access_log_keep)
+                .unwrap_or_else(|err| {
+                    eprintln!(
+                        "Problem parsing configuration: access_log: {}: {err}",
+                        path.display()
+                    );
+                    process::exit(2);
+                });
+            Some(Sink::File(file))
+        }
+    };
+    let access_log =
+        access_log.map(|sink| Arc::new(AccessLog::new(config.access_log_format, sink)));
+
+    #[cfg(not(feature = "tls"))]
+    if config.tls_enabled() {
+        eprintln!(
+            "Problem parsing configuration: tls_cert: hello was built without the tls feature"
+        );
+        process::exit(2);
+    }
+    #[cfg(feature = "tls")]
+    let tls = config.tls_enabled().then(|| certificates(&config));
 
-    let (status_line, filename) = if request_line == "GET / HTTP/1.1" {
-        ("HTTP/1.1 200 OK", "hello.html")
+    let metrics = config
+        .metrics_path
+        .as_ref()
+        .map(|_| Arc::new(Metrics::new()));
+    let http = server(&config, config.port, &access_log, &error_pages, &metrics);
+    #[cfg(feature = "tls")]
+    let https = tls.map(|tls| {
+        server(
+            &config,
+            config.https_port,
+            &access_log,
+            &error_pages,
+            &metrics,
+        )
+        .tls(tls)
+    });
+    #[cfg(not(feature = "tls"))]
+    let https: Option<Server> = None;
+
+    let mut router = Router::new();
+    if config.health_checks {
+        // Both servers shut down together, so one handle speaks for both.
+        let health = Arc::new(
+            Health::new()
+                .shutdown(http.shutdown_handle())
+                .readable_dir("document_root", &config.document_root),
+        );
+        let live = Arc::clone(&health);
+        router
+            .get("/healthz", move |_| live.liveness())
+            .get("/readyz", move |_| health.readiness());
+    }
+    if let (Some(path), Some(metrics)) = (&config.metrics_path, &metrics) {
+        let metrics = Arc::clone(metrics);
+        router.get(path, move |_| metrics.response());
+    }
+    for route in &config.proxy {
+        let proxy = Proxy::new(&route.upstreams).unwrap_or_else(|err| {
+            eprintln!(
+                "Problem parsing configuration: proxy: {}: {err}",
+                route.prefix
+            );
+            process::exit(2);
+        });
+        let mut proxy = proxy
+            .preserve_host(route.preserve_host)
+            .timeout(route.timeout);
+        if route.strip_prefix {
+            proxy = proxy.strip_prefix(&route.prefix);
+        }
+        let proxy = Arc::new(proxy);
+
+        let prefix = route.prefix.trim_end_matches('/');
+        if !prefix.is_empty() {
+            router.any(prefix, Arc::clone(&proxy));
+        }
+        router.any(&format!("{prefix}/*rest"), proxy);
+    }
+    router
+        .get("/", move |req| {
+            let vars = Vars::new().var("name", req.query_param("name"));
+            templates
+                .page(StatusCode::Ok, "hello.html", &vars)
+                .unwrap_or_else(|err| {
+                    error!("Problem rendering hello.html: {err}");
+                    Response::new(StatusCode::InternalServerError)
+                })
+        })
+        .get("/*path", move |req| {
+            // Error statuses are left empty for the error pages to fill in.
+            match files.serve(req, req.param("path").unwrap_or_default()) {
+                Ok(response) => response,
+                Err(Reject::NotFound) => Response::new(StatusCode::NotFound),
+                Err(Reject::Forbidden) => Response::new(StatusCode::Forbidden),
+                Err(Reject::BadRequest) => Response::new(StatusCode::BadRequest),
+            }
+        });
+
+    // Outermost first: every response gets an ID and timing, even a 500
+    // from a panic caught further in, and clients over their rate are
+    // turned away before anything else is done for them.
+    let mut site = Chain::new(router).with(RequestId::new());
+    let rate_limit = rate_limit(&config);
+    if !rate_limit.is_empty() {
+        site = site.with(rate_limit);
+    }
+    site = site.with(Timing::new());
+    if !config.cors_origins.is_empty() {
+        site = site.with(
+            Cors::new(&config.cors_origins)
+                .methods(&[
+                    Method::Get,
+                    Method::Head,
+                    Method::Post,
+                    Method::Put,
+                    Method::Delete,
+                ])
+                .expose_headers(&["X-Request-Id"]),
+        );
+    }
+    if config.security_headers {
+        site = site.with(SecurityHeaders::new().hsts(config.hsts_max_age));
+    }
+    let auth = auth(&config);
+    if !auth.is_empty() {
+        site = site.with(auth);
+    }
+    let site = Arc::new(site.with(CatchPanic));
+
+    let shutdowns: Vec<_> = [Some(&http), https.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(Server::shutdown_handle)
+        .collect();
+    if let Err(err) = ctrlc::set_handler(move || shutdowns.iter().for_each(|s| s.trigger())) {
+        eprintln!("Problem installing signal handler: {err}");
+        process::exit(1);
+    }
+
+    if let Ok(addr) = http.local_addr() {
+        info!("Listening on http://{addr}");
+    }
+    let https = https.map(|https| {
+        if let Ok(addr) = https.local_addr() {
+            info!("Listening on https://{addr}");
+        }
+        let site = site.clone();
+        thread::spawn(move || run(https, site))
+    });
+
+    #[cfg(feature = "tls")]
+    let clean = if config.https_redirect {
+        run(http, HttpsRedirect::new(config.https_port))
     } else {
-        ("HTTP/1.1 404 NOT FOUND", "404.html")
+        run(http, site)
     };
+    #[cfg(not(feature = "tls"))]
+    let clean = run(http, site);
+    let clean = https.is_none_or(|https| https.join().unwrap_or(false)) && clean;
+
+    info!("Shutting down.");
+
+    if !clean {
+        error!("Timed out waiting for in-flight requests");
+        process::exit(1);
+    }
+}
+
+fn server(
+    config: &Config,
+    port: u16,
+    access_log: &Option<Arc<AccessLog>>,
+    error_pages: &ErrorPages,
+    metrics: &Option<Arc<Metrics>>,
+) -> Server {
+    let mut server = Server::bind((config.bind, port))
+        .unwrap_or_else(|err| {
+            eprintln!("Problem binding {}:{port}: {err}", config.bind);
+            process::exit(1);
+        })
+        .workers(config.workers)
+        .drain_period(config.drain_period)
+        .shutdown_timeout(config.shutdown_timeout)
+        .keep_alive_timeout(config.keep_alive_timeout)
+        .max_requests_per_connection(config.max_requests)
+        .header_timeout(config.header_timeout)
+        .read_timeout(config.read_timeout)
+        .write_timeout(config.write_timeout)
+        .limits(Limits {
+            max_header_size: usize::try_from(config.max_header_size).unwrap_or(usize::MAX),
+            max_headers: config.max_headers,
+            max_body_size: config.max_body_size,
+        })
+        .max_connections_per_ip(config.max_connections_per_ip)
+        .error_pages(error_pages.clone());
+    if let Some(log) = access_log {
+        server = server.access_log(Arc::clone(log));
+    }
+    if let Some(metrics) = metrics {
+        server = server.metrics(Arc::clone(metrics));
+    }
+    if config.compression {
+        server = server.compression(
+            Compression::new()
+                .min_size(config.compression_min_size)
+                .mime_types(&config.compression_types),
+        );
+    }
+    server
+}
+
+fn rate_limit(config: &Config) -> RateLimit {
+    let mut limit = RateLimit::new();
+    if let Some(rate) = config.rate_limit {
+        limit = limit.limit(rate);
+        if let Some(burst) = config.rate_limit_burst {
+            limit = limit.burst(burst);
+        }
+    }
+    for (prefix, rate) in &config.rate_limit_routes {
+        limit = limit.route(prefix, *rate);
+    }
+    limit
+}
 
-    let contents = fs::read_to_string(filename).unwrap();
-    let length = contents.len();
+fn auth(config: &Config) -> Auth {
+    let mut auth = Auth::new();
+    for route in &config.auth {
+        let mut rule = AuthRule::new(&route.prefix)
+            .realm(&route.realm)
+            .bearer(&route.tokens);
+        if let Some(path) = &route.htpasswd {
+            let htpasswd = Htpasswd::load(path).unwrap_or_else(|err| {
+                eprintln!(
+                    "Problem parsing configuration: auth: {}: {err}",
+                    path.display()
+                );
+                process::exit(2);
+            });
+            rule = rule.basic(Arc::new(htpasswd));
+        }
+        auth = auth.rule(rule);
+    }
+    auth
+}
 
-    let response = format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}");
+fn run(server: Server, handler: impl Handler) -> bool {
+    #[cfg(feature = "async")]
+    return server.serve_async(handler);
+    #[cfg(not(feature = "async"))]
+    server.serve(handler)
+}
+
+#[cfg(feature = "tls")]
+fn certificates(config: &Config) -> Arc<ServerConfig> {
+    fn invalid(key: &str, err: std::io::Error) -> ! {
+        eprintln!("Problem parsing configuration: {key}: {err}");
+        process::exit(2);
+    }
+
+    let mut certificates = Certificates::new();
+    if let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) {
+        if let Err(err) = certificates.add(&[], cert, key) {
+            invalid("tls_cert", err);
+        }
+    }
+    for sni in &config.tls_sni {
+        let names: Vec<&str> = sni.names.iter().map(String::as_str).collect();
+        if let Err(err) = certificates.add(&names, &sni.cert, &sni.key) {
+            invalid("tls_sni", err);
+        }
+    }
 
-    stream.write_all(response.as_bytes()).unwrap();
+    certificates
+        .server_config()
+        .unwrap_or_else(|err| invalid("tls_cert", err))
 }
@@ -0,0 +1,323 @@
+//! Counters and histograms in the Prometheus text exposition format.
+//!
+//! A [`Server`](crate::server::Server) given a [`Metrics`] records every
+//! request it answers and every connection it serves; a handler returning
+//! [`Metrics::response`] publishes them:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use hello::{metrics::Metrics, router::Router, server::Server};
+//!
+//! let metrics = Arc::new(Metrics::new());
+//! let mut router = Router::new();
+//! let published = Arc::clone(&metrics);
+//! router.get("/metrics", move |_| published.response());
+//!
+//! Server::bind("127.0.0.1:0")
+//!     .unwrap()
+//!     .metrics(metrics)
+//!     .serve(router);
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    request::Request,
+    response::{Response, StatusCode},
+};
+
+/// Upper bounds, in seconds, of the request duration buckets.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Upper bounds, in bytes, of the response size buckets.
+const SIZE_BUCKETS: [f64; 7] = [
+    100.0,
+    1_000.0,
+    10_000.0,
+    100_000.0,
+    1_000_000.0,
+    10_000_000.0,
+    100_000_000.0,
+];
+
+/// The route label for requests no route matched, so stray paths don't
+/// each get a series of their own.
+const UNMATCHED: &str = "unmatched";
+
+/// Observations counted into fixed buckets.
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, not yet cumulative; the last is `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{labels}}} {cumulative}");
+    }
+}
+
+#[derive(Debug, Default)]
+struct Requests {
+    counts: BTreeMap<(String, &'static str, u16), u64>,
+    latency: BTreeMap<String, Histogram>,
+    sizes: BTreeMap<String, Histogram>,
+}
+
+/// What the server has done since it started.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: Mutex<Requests>,
+    connections: AtomicU64,
+    active: AtomicI64,
+    queued: AtomicI64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Count a request answered with `status` after `latency`, having sent
+    /// `bytes` of body. One that couldn't be read is counted with method
+    /// `-`.
+    pub fn record(&self, request: &Request, status: StatusCode, bytes: u64, latency: Duration) {
+        let route = request.route.as_deref().unwrap_or(UNMATCHED);
+        let method = match request.is_unread() {
+            true => "-",
+            false => request.method.as_str(),
+        };
+        let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+
+        *requests
+            .counts
+            .entry((route.to_string(), method, status.code()))
+            .or_insert(0) += 1;
+        requests
+            .latency
+            .entry(route.to_string())
+            .or_insert_with(|| Histogram::new(&LATENCY_BUCKETS))
+            .observe(latency.as_secs_f64());
+        requests
+            .sizes
+            .entry(route.to_string())
+            .or_insert_with(|| Histogram::new(&SIZE_BUCKETS))
+            .observe(bytes as f64);
+    }
+
+    /// Note a connection accepted and waiting for a worker thread.
+    pub(crate) fn connection_queued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Note a queued connection picked up by a worker thread.
+    pub(crate) fn connection_dequeued(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection as open until the returned guard is dropped.
+    pub(crate) fn connection_opened(&self) -> OpenConnection<'_> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(self)
+    }
+
+    /// Everything recorded, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        out.push_str(
+            "# HELP hello_http_requests_total Requests answered, by route, method and status.\n\
+             # TYPE hello_http_requests_total counter\n",
+        );
+        for ((route, method, status), count) in &requests.counts {
+            let _ = writeln!(
+                out,
+                "hello_http_requests_total{{route=\"{}\",method=\"{method}\",status=\"{status}\"}} {count}",
+                escape(route)
+            );
+        }
+
+        out.push_str(
+            "# HELP hello_http_request_duration_seconds Time from reading a request to sending its response.\n\
+             # TYPE hello_http_request_duration_seconds histogram\n",
+        );
+        for (route, histogram) in &requests.latency {
+            let labels = format!("route=\"{}\"", escape(route));
+            histogram.write(&mut out, "hello_http_request_duration_seconds", &labels);
+        }
+
+        out.push_str(
+            "# HELP hello_http_response_size_bytes Response body bytes sent.\n\
+             # TYPE hello_http_response_size_bytes histogram\n",
+        );
+        for (route, histogram) in &requests.sizes {
+            let labels = format!("route=\"{}\"", escape(route));
+            histogram.write(&mut out, "hello_http_response_size_bytes", &labels);
+        }
+        drop(requests);
+
+        let _ = write!(
+            out,
+            "# HELP hello_connections_total Connections accepted.\n\
+             # TYPE hello_connections_total counter\n\
+             hello_connections_total {}\n\
+             # HELP hello_connections_active Connections being served.\n\
+             # TYPE hello_connections_active gauge\n\
+             hello_connections_active {}\n\
+             # HELP hello_worker_queue_depth Connections waiting for a worker thread; always 0 on the async server.\n\
+             # TYPE hello_worker_queue_depth gauge\n\
+             hello_worker_queue_depth {}\n",
+            self.connections.load(Ordering::Relaxed),
+            self.active.load(Ordering::Relaxed),
+            self.queued.load(Ordering::Relaxed),
+        );
+
+        out
+    }
+
+    /// A `200` carrying [`render`](Metrics::render).
+    pub fn response(&self) -> Response {
+        Response::new(StatusCode::Ok)
+            .with_body("text/plain; version=0.0.4; charset=utf-8", self.render())
+    }
+}
+
+/// An open connection, counted in `hello_connections_active` until
+/// dropped.
+pub(crate) struct OpenConnection<'a>(&'a Metrics);
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Escape a label value: backslashes, quotes and newlines.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(raw: &str, route: Option<&str>) -> Request {
+        let mut request = Request::read_from(&mut raw.as_bytes()).unwrap();
+        request.route = route.map(String::from);
+        request
+    }
+
+    #[test]
+    fn counts_requests_by_route_and_status() {
+        let metrics = Metrics::new();
+        let get = request("GET /users/7 HTTP/1.1\r\n\r\n", Some("/users/:id"));
+        metrics.record(&get, StatusCode::Ok, 512, Duration::from_millis(3));
+        metrics.record(&get, StatusCode::Ok, 2048, Duration::from_millis(30));
+        metrics.record(&get, StatusCode::NotFound, 0, Duration::from_secs(20));
+        let stray = request("GET /nope HTTP/1.1\r\n\r\n", None);
+        metrics.record(&stray, StatusCode::NotFound, 0, Duration::ZERO);
+
+        let text = metrics.render();
+        let has = |line: &str| text.lines().any(|l| l == line);
+        assert!(has(
+            "hello_http_requests_total{route=\"/users/:id\",method=\"GET\",status=\"200\"} 2"
+        ));
+        assert!(has(
+            "hello_http_requests_total{route=\"/users/:id\",method=\"GET\",status=\"404\"} 1"
+        ));
+        assert!(has(
+            "hello_http_requests_total{route=\"unmatched\",method=\"GET\",status=\"404\"} 1"
+        ));
+
+        // Buckets are cumulative and end with +Inf.
+        assert!(has(
+            "hello_http_request_duration_seconds_bucket{route=\"/users/:id\",le=\"0.005\"} 1"
+        ));
+        assert!(has(
+            "hello_http_request_duration_seconds_bucket{route=\"/users/:id\",le=\"0.05\"} 2"
+        ));
+        assert!(has(
+            "hello_http_request_duration_seconds_bucket{route=\"/users/:id\",le=\"10\"} 2"
+        ));
+        assert!(has(
+            "hello_http_request_duration_seconds_bucket{route=\"/users/:id\",le=\"+Inf\"} 3"
+        ));
+        assert!(has(
+            "hello_http_request_duration_seconds_count{route=\"/users/:id\"} 3"
+        ));
+        assert!(has(
+            "hello_http_response_size_bytes_sum{route=\"/users/:id\"} 2560"
+        ));
+        assert!(has(
+            "hello_http_response_size_bytes_bucket{route=\"/users/:id\",le=\"1000\"} 2"
+        ));
+    }
+
+    #[test]
+    fn tracks_connections() {
+        let metrics = Metrics::new();
+        metrics.connection_queued();
+        metrics.connection_queued();
+        metrics.connection_dequeued();
+        let open = metrics.connection_opened();
+        drop(metrics.connection_opened());
+
+        let text = metrics.render();
+        assert!(text.contains("\nhello_connections_total 2\n"));
+        assert!(text.contains("\nhello_connections_active 1\n"));
+        assert!(text.contains("\nhello_worker_queue_depth 1\n"));
+        drop(open);
+        assert!(metrics.render().contains("\nhello_connections_active 0\n"));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,691 @@
+//! Cross-cutting behaviour wrapped around a handler.
+//!
+//! A [`Chain`] runs each [`Middleware`] in the order it was added, outermost
+//! first, then the handler:
+//!
+//! ```no_run
+//! use hello::{
+//!     middleware::{CatchPanic, Chain, RequestId, SecurityHeaders, Timing},
+//!     response::{Response, StatusCode},
+//!     router::Router,
+//!     server::Server,
+//! };
+//!
+//! let mut router = Router::new();
+//! router.get("/", |_| Response::new(StatusCode::Ok));
+//!
+//! let site = Chain::new(router)
+//!     .with(RequestId::new())
+//!     .with(Timing::new())
+//!     .with(SecurityHeaders::new())
+//!     .with(CatchPanic);
+//!
+//! Server::bind("127.0.0.1:0").unwrap().serve(site);
+//! ```
+
+use std::{
+    hash::{BuildHasher, RandomState},
+    panic::{self, AssertUnwindSafe},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::Error,
+    request::{Method, Request},
+    response::{Response, StatusCode},
+    router::Handler,
+};
+
+/// Something to do around every request, such as adding headers.
+///
+/// Most middleware only needs [`before`](Middleware::before) and
+/// [`after`](Middleware::after). Those that must see the call itself, to
+/// time it or catch its panics, override [`wrap`](Middleware::wrap)
+/// instead.
+pub trait Middleware: Send + Sync + 'static {
+    /// Runs before the rest of the chain. Returning a response
+    /// short-circuits it: the handler and any middleware added later are
+    /// skipped, and that response goes back out through `after`.
+    fn before(&self, _request: &mut Request) -> Option<Response> {
+        None
+    }
+
+    /// Runs on the way out, on whatever response the rest of the chain or
+    /// `before` produced.
+    fn after(&self, _request: &Request, _response: &mut Response) {}
+
+    /// Run this middleware around `next`, the rest of the chain.
+    fn wrap(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let mut response = match self.before(request) {
+            Some(response) => response,
+            None => next.run(request),
+        };
+        self.after(request, &mut response);
+        response
+    }
+}
+
+/// The rest of a [`Chain`], from some middleware inwards.
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &mut Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.wrap(
+                request,
+                Next {
+                    middleware: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.handle(request),
+        }
+    }
+}
+
+/// A handler wrapped in middleware.
+pub struct Chain {
+    middleware: Vec<Box<dyn Middleware>>,
+    handler: Box<dyn Handler>,
+}
+
+impl Chain {
+    pub fn new(handler: impl Handler) -> Chain {
+        Chain {
+            middleware: Vec::new(),
+            handler: Box::new(handler),
+        }
+    }
+
+    /// Add `middleware` inside everything added so far, so it sees requests
+    /// after them and responses before them.
+    pub fn with(mut self, middleware: impl Middleware) -> Chain {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+}
+
+impl Handler for Chain {
+    fn handle(&self, request: &mut Request) -> Response {
+        Next {
+            middleware: &self.middleware,
+            handler: self.handler.as_ref(),
+        }
+        .run(request)
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        self.handler.streams_body(request)
+    }
+}
+
+/// Gives every request an ID, in a request header handlers (and proxied
+/// upstreams) can read and in the same response header.
+///
+/// An ID the client sent is kept if it is short and printable.
+pub struct RequestId {
+    header: String,
+    keys: RandomState,
+    next: AtomicU64,
+}
+
+impl RequestId {
+    /// Longest ID accepted from a client.
+    const MAX_LEN: usize = 128;
+
+    pub fn new() -> RequestId {
+        RequestId {
+            header: "X-Request-Id".to_string(),
+            keys: RandomState::new(),
+            next: AtomicU64::new(0),
+        }
+    }
+
+    /// The header carrying the ID. Defaults to `X-Request-Id`.
+    pub fn header(mut self, name: &str) -> RequestId {
+        self.header = name.to_string();
+        self
+    }
+
+    /// A fresh ID: a counter hashed with per-process random keys, so IDs
+    /// don't repeat and don't reveal how busy the server is.
+    fn generate(&self) -> String {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        format!("{:016x}", self.keys.hash_one(n))
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> RequestId {
+        RequestId::new()
+    }
+}
+
+impl Middleware for RequestId {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let valid = request.headers.get(&self.header).is_some_and(|id| {
+            !id.is_empty() && id.len() <= Self::MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+        });
+        if !valid {
+            request.headers.set(self.header.as_str(), self.generate());
+        }
+        None
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        if let Some(id) = request.headers.get(&self.header) {
+            response.headers.set(self.header.as_str(), id);
+        }
+    }
+}
+
+/// Lets pages on other origins call this server from the browser.
+///
+/// Preflight requests (`OPTIONS` with `Access-Control-Request-Method`) are
+/// answered here with `204 No Content` and never reach the handler. Other
+/// requests from an allowed origin get `Access-Control-Allow-Origin` added
+/// to their response.
+pub struct Cors {
+    /// Empty allows any origin.
+    origins: Vec<String>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    expose: Vec<String>,
+    credentials: bool,
+    max_age: Duration,
+}
+
+impl Cors {
+    /// Allow `origins`, such as `https://app.example.com`. `*`, or no origins
+    /// at all, allows any.
+    pub fn new<I, S>(origins: I) -> Cors
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let origins: Vec<String> = origins.into_iter().map(Into::into).collect();
+        Cors {
+            origins: if origins.iter().any(|o| o == "*") {
+                Vec::new()
+            } else {
+                origins
+            },
+            methods: vec![Method::Get, Method::Head, Method::Post],
+            headers: vec!["Content-Type".to_string(), "Authorization".to_string()],
+            expose: Vec::new(),
+            credentials: false,
+            max_age: Duration::from_secs(600),
+        }
+    }
+
+    /// Methods preflights may ask for. Defaults to `GET`, `HEAD` and `POST`.
+    pub fn methods(mut self, methods: &[Method]) -> Cors {
+        self.methods = methods.to_vec();
+        self
+    }
+
+    /// Request headers preflights may ask for. Defaults to `Content-Type`
+    /// and `Authorization`.
+    pub fn allow_headers(mut self, headers: &[&str]) -> Cors {
+        self.headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// Response headers scripts may read beyond the basic ones.
+    pub fn expose_headers(mut self, headers: &[&str]) -> Cors {
+        self.expose = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// Allow cookies and `Authorization` on cross-origin requests.
+    pub fn allow_credentials(mut self, allow: bool) -> Cors {
+        self.credentials = allow;
+        self
+    }
+
+    /// How long browsers may cache a preflight answer. Defaults to 10
+    /// minutes.
+    pub fn max_age(mut self, max_age: Duration) -> Cors {
+        self.max_age = max_age;
+        self
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins.is_empty() || self.origins.iter().any(|o| o == origin)
+    }
+
+    fn preflight(&self, request: &Request) -> Response {
+        let mut response = Response::new(StatusCode::NoContent);
+        let method = request
+            .headers
+            .get("Access-Control-Request-Method")
+            .unwrap_or_default();
+        let asked: Vec<&str> = request
+            .headers
+            .get_all("Access-Control-Request-Headers")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        let headers_allowed = asked
+            .iter()
+            .all(|name| self.headers.iter().any(|h| h.eq_ignore_ascii_case(name)));
+        if !self.methods.iter().any(|m| m.as_str() == method) || !headers_allowed {
+            // Without the allow headers the browser refuses the request.
+            return response;
+        }
+
+        let methods: Vec<&str> = self.methods.iter().map(Method::as_str).collect();
+        response
+            .headers
+            .set("Access-Control-Allow-Methods", methods.join(", "));
+        if !self.headers.is_empty() {
+            response
+                .headers
+                .set("Access-Control-Allow-Headers", self.headers.join(", "));
+        }
+        response
+            .headers
+            .set("Access-Control-Max-Age", self.max_age.as_secs().to_string());
+        response
+    }
+}
+
+impl Middleware for Cors {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let is_preflight = request.method == Method::Options
+            && request.headers.contains("Origin")
+            && request.headers.contains("Access-Control-Request-Method");
+        is_preflight.then(|| self.preflight(request))
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        let Some(origin) = request.headers.get("Origin") else {
+            return;
+        };
+        if !self.allows(origin) {
+            return;
+        }
+
+        // `*` can't be combined with credentials, so echo the origin then.
+        if self.origins.is_empty() && !self.credentials {
+            response.headers.set("Access-Control-Allow-Origin", "*");
+        } else {
+            response.headers.set("Access-Control-Allow-Origin", origin);
+            let headers = &mut response.headers;
+            if !headers.has_token("Vary", "Origin") && !headers.has_token("Vary", "*") {
+                let vary = match headers.get("Vary") {
+                    Some(existing) => format!("{existing}, Origin"),
+                    None => "Origin".to_string(),
+                };
+                headers.set("Vary", vary);
+            }
+        }
+        if self.credentials {
+            response
+                .headers
+                .set("Access-Control-Allow-Credentials", "true");
+        }
+        if !self.expose.is_empty() {
+            response
+                .headers
+                .set("Access-Control-Expose-Headers", self.expose.join(", "));
+        }
+    }
+}
+
+/// Adds headers that make browsers more careful with the site's pages,
+/// unless the handler already set them.
+pub struct SecurityHeaders {
+    headers: Vec<(String, String)>,
+    hsts: Option<Duration>,
+}
+
+impl SecurityHeaders {
+    /// `nosniff`, no framing, a same-origin content security policy and a
+    /// referrer policy that keeps paths on this site.
+    pub fn new() -> SecurityHeaders {
+        let headers = [
+            ("X-Content-Type-Options", "nosniff"),
+            ("X-Frame-Options", "DENY"),
+            ("Content-Security-Policy", "default-src 'self'"),
+            ("Referrer-Policy", "strict-origin-when-cross-origin"),
+        ];
+        SecurityHeaders {
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            hsts: None,
+        }
+    }
+
+    /// Set `name` to `value` in place of the default, or add it.
+    pub fn header(mut self, name: &str, value: &str) -> SecurityHeaders {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Leave out one of the default headers.
+    pub fn without(mut self, name: &str) -> SecurityHeaders {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self
+    }
+
+    /// Tell browsers to use HTTPS only for `max_age`. Only sent on HTTPS
+    /// responses; off by default, since it can't be taken back early.
+    pub fn hsts(mut self, max_age: Duration) -> SecurityHeaders {
+        self.hsts = Some(max_age).filter(|age| !age.is_zero());
+        self
+    }
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders::new()
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn after(&self, request: &Request, response: &mut Response) {
+        for (name, value) in &self.headers {
+            if !response.headers.contains(name) {
+                response.headers.set(name.as_str(), value.as_str());
+            }
+        }
+        if let Some(max_age) = self.hsts.filter(|_| request.https) {
+            response.headers.set(
+                "Strict-Transport-Security",
+                format!("max-age={}", max_age.as_secs()),
+            );
+        }
+    }
+}
+
+/// Reports how long the rest of the chain took in a `Server-Timing`
+/// header, and optionally logs requests that were slow.
+pub struct Timing {
+    slow: Option<Duration>,
+}
+
+impl Timing {
+    pub fn new() -> Timing {
+        Timing { slow: None }
+    }
+
+    /// Log a warning for requests that take longer than `threshold`.
+    pub fn log_slower_than(mut self, threshold: Duration) -> Timing {
+        self.slow = Some(threshold);
+        self
+    }
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        Timing::new()
+    }
+}
+
+impl Middleware for Timing {
+    fn wrap(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let started = Instant::now();
+        let mut response = next.run(request);
+        let elapsed = started.elapsed();
+
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        response
+            .headers
+            .append("Server-Timing", format!("app;dur={ms:.3}"));
+        if self.slow.is_some_and(|slow| elapsed > slow) {
+            crate::warn!(
+                "Slow request: {} {} took {ms:.0}ms",
+                request.method,
+                request.path
+            );
+        }
+        response
+    }
+}
+
+/// Turns a panic in the rest of the chain into a `500 Internal Server
+/// Error`.
+///
+/// The server does this for the whole handler anyway, but then closes the
+/// connection and skips the middleware. Inside a chain, the middleware
+/// added before this still sees the response, and the connection stays
+/// open.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    fn wrap(&self, request: &mut Request, next: Next<'_>) -> Response {
+        match panic::catch_unwind(AssertUnwindSafe(|| next.run(request))) {
+            Ok(response) => response,
+            Err(payload) => {
+                let error = Error::from_panic(payload);
+                crate::error!(
+                    "Problem serving {} {}: {error}",
+                    request.method,
+                    request.path
+                );
+                Response::new(StatusCode::InternalServerError)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn request(raw: &str) -> Request {
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    /// Records the order its hooks run in.
+    struct Trace {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        stop: bool,
+    }
+
+    impl Middleware for Trace {
+        fn before(&self, _request: &mut Request) -> Option<Response> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} before", self.name));
+            self.stop.then(|| Response::new(StatusCode::Forbidden))
+        }
+
+        fn after(&self, _request: &Request, _response: &mut Response) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} after", self.name));
+        }
+    }
+
+    #[test]
+    fn runs_middleware_in_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let trace = |name, stop| Trace {
+            name,
+            log: Arc::clone(&log),
+            stop,
+        };
+        let handler_log = Arc::clone(&log);
+        let handler = move |_: &mut Request| {
+            handler_log.lock().unwrap().push("handler".to_string());
+            Response::new(StatusCode::Ok)
+        };
+
+        let chain = Chain::new(handler)
+            .with(trace("outer", false))
+            .with(trace("inner", false));
+        let response = chain.handle(&mut request("GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                "outer before",
+                "inner before",
+                "handler",
+                "inner after",
+                "outer after"
+            ]
+        );
+
+        log.lock().unwrap().clear();
+        let chain = Chain::new(|_: &mut Request| -> Response { unreachable!() })
+            .with(trace("outer", false))
+            .with(trace("guard", true))
+            .with(trace("inner", false));
+        let response = chain.handle(&mut request("GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.status, StatusCode::Forbidden);
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["outer before", "guard before", "guard after", "outer after"]
+        );
+    }
+
+    #[test]
+    fn assigns_request_ids() {
+        let chain = Chain::new(|req: &mut Request| {
+            let id = req.headers.get("X-Request-Id").unwrap_or_default();
+            Response::new(StatusCode::Ok).with_body("text/plain", id.to_string())
+        })
+        .with(RequestId::new());
+
+        let first = chain.handle(&mut request("GET / HTTP/1.1\r\n\r\n"));
+        let second = chain.handle(&mut request("GET / HTTP/1.1\r\n\r\n"));
+        let id = first.headers.get("X-Request-Id").unwrap();
+        assert_eq!(id.len(), 16);
+        assert_eq!(first.body.as_bytes(), Some(id.as_bytes()));
+        assert_ne!(second.headers.get("X-Request-Id"), Some(id));
+
+        let kept = chain.handle(&mut request(
+            "GET / HTTP/1.1\r\nX-Request-Id: abc-123\r\n\r\n",
+        ));
+        assert_eq!(kept.headers.get("X-Request-Id"), Some("abc-123"));
+
+        let replaced = chain.handle(&mut request(
+            "GET / HTTP/1.1\r\nX-Request-Id: has spaces\r\n\r\n",
+        ));
+        assert_ne!(replaced.headers.get("X-Request-Id"), Some("has spaces"));
+    }
+
+    #[test]
+    fn answers_cors_requests() {
+        let chain = Chain::new(|_: &mut Request| Response::new(StatusCode::Ok)).with(
+            Cors::new(["https://app.example"])
+                .methods(&[Method::Get, Method::Put])
+                .expose_headers(&["X-Request-Id"]),
+        );
+
+        let preflight = chain.handle(&mut request(
+            "OPTIONS /items HTTP/1.1\r\nOrigin: https://app.example\r\n\
+             Access-Control-Request-Method: PUT\r\n\
+             Access-Control-Request-Headers: content-type\r\n\r\n",
+        ));
+        assert_eq!(preflight.status, StatusCode::NoContent);
+        let header = |name| preflight.headers.get(name);
+        assert_eq!(
+            header("Access-Control-Allow-Origin"),
+            Some("https://app.example")
+        );
+        assert_eq!(header("Access-Control-Allow-Methods"), Some("GET, PUT"));
+        assert_eq!(
+            header("Access-Control-Allow-Headers"),
+            Some("Content-Type, Authorization")
+        );
+        assert_eq!(header("Access-Control-Max-Age"), Some("600"));
+        assert_eq!(header("Vary"), Some("Origin"));
+
+        let refused = chain.handle(&mut request(
+            "OPTIONS /items HTTP/1.1\r\nOrigin: https://app.example\r\n\
+             Access-Control-Request-Method: DELETE\r\n\r\n",
+        ));
+        assert!(!refused.headers.contains("Access-Control-Allow-Methods"));
+
+        let simple = chain.handle(&mut request(
+            "GET /items HTTP/1.1\r\nOrigin: https://app.example\r\n\r\n",
+        ));
+        assert_eq!(simple.status, StatusCode::Ok);
+        assert_eq!(
+            simple.headers.get("Access-Control-Expose-Headers"),
+            Some("X-Request-Id")
+        );
+
+        let other = chain.handle(&mut request(
+            "GET /items HTTP/1.1\r\nOrigin: https://evil.example\r\n\r\n",
+        ));
+        assert!(!other.headers.contains("Access-Control-Allow-Origin"));
+
+        let any =
+            Chain::new(|_: &mut Request| Response::new(StatusCode::Ok)).with(Cors::new(["*"]));
+        let response = any.handle(&mut request(
+            "GET / HTTP/1.1\r\nOrigin: https://anywhere.example\r\n\r\n",
+        ));
+        assert_eq!(
+            response.headers.get("Access-Control-Allow-Origin"),
+            Some("*")
+        );
+        assert!(!response.headers.contains("Vary"));
+    }
+
+    #[test]
+    fn adds_security_headers() {
+        let chain = Chain::new(|_: &mut Request| {
+            Response::new(StatusCode::Ok).header("X-Frame-Options", "SAMEORIGIN")
+        })
+        .with(
+            SecurityHeaders::new()
+                .without("Content-Security-Policy")
+                .header("Referrer-Policy", "no-referrer")
+                .hsts(Duration::from_secs(3600)),
+        );
+
+        let response = chain.handle(&mut request("GET / HTTP/1.1\r\n\r\n"));
+        let header = |name| response.headers.get(name);
+        assert_eq!(header("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(header("X-Frame-Options"), Some("SAMEORIGIN"));
+        assert_eq!(header("Referrer-Policy"), Some("no-referrer"));
+        assert_eq!(header("Content-Security-Policy"), None);
+        assert_eq!(header("Strict-Transport-Security"), None);
+
+        let mut secure = request("GET / HTTP/1.1\r\n\r\n");
+        secure.https = true;
+        let response = chain.handle(&mut secure);
+        assert_eq!(
+            response.headers.get("Strict-Transport-Security"),
+            Some("max-age=3600")
+        );
+    }
+
+    #[test]
+    fn times_requests() {
+        let chain = Chain::new(|_: &mut Request| Response::new(StatusCode::Ok)).with(Timing::new());
+        let response = chain.handle(&mut request("GET / HTTP/1.1\r\n\r\n"));
+        let timing = response.headers.get("Server-Timing").unwrap();
+        let ms: f64 = timing.strip_prefix("app;dur=").unwrap().parse().unwrap();
+        assert!(ms >= 0.0);
+    }
+
+    #[test]
+    fn catches_panics_inside_the_chain() {
+        let chain = Chain::new(|_: &mut Request| -> Response { panic!("boom") })
+            .with(RequestId::new())
+            .with(CatchPanic);
+
+        let response = chain.handle(&mut request("GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.status, StatusCode::InternalServerError);
+        assert!(response.headers.contains("X-Request-Id"));
+    }
+}
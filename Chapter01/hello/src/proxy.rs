@@ -0,0 +1,559 @@
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    headers::Headers,
+    request::{body_length, read_headers, read_line, Chunked, Limits, Method, ParseError, Request},
+    response::{Response, StatusCode, Stream},
+    router::Handler,
+};
+
+/// Headers that describe one connection rather than the message, and so
+/// are never passed on (RFC 9110 section 7.6.1).
+const HOP_BY_HOP: &[&str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Upstream response heads, and chunk size lines, bigger than this are
+/// treated as malformed.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Passes requests on to upstream HTTP servers and relays their responses.
+///
+/// Requests are spread across the upstreams round-robin. An upstream that
+/// fails [`max_fails`](Proxy::max_fails) times in a row, by refusing
+/// connections, timing out or answering with garbage, is left out of the
+/// rotation for [`fail_timeout`](Proxy::fail_timeout). A request that
+/// can't be delivered is tried on the next upstream; one that was sent is
+/// not, since it may not be safe to repeat. When no upstream answers, the
+/// client gets `504 Gateway Timeout` if it timed out and `502 Bad Gateway`
+/// otherwise.
+///
+/// `Host` is rewritten to the upstream's, and `X-Forwarded-For`,
+/// `X-Forwarded-Proto` and `X-Forwarded-Host` describe the original
+/// request. Bodies are streamed both ways as they arrive: the server
+/// leaves request bodies on the connection for the proxy to copy upstream,
+/// though still within its [`Limits`], and chunked ones stay chunked. A
+/// client that stops sending its body part way gets the status for that,
+/// and the upstream is not held to blame.
+///
+/// Upgrades, WebSocket ones included, are not tunnelled: `Upgrade` is not
+/// passed on, and an upstream that switches protocols anyway is answered
+/// for with `502 Bad Gateway`.
+///
+/// ```
+/// use hello::{proxy::Proxy, router::Router};
+///
+/// let proxy = Proxy::new(["http://127.0.0.1:9000", "127.0.0.1:9001"])
+///     .unwrap()
+///     .strip_prefix("/api");
+/// let mut router = Router::new();
+/// router.any("/api/*rest", proxy);
+/// ```
+pub struct Proxy {
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+    strip_prefix: Option<String>,
+    preserve_host: bool,
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_fails: usize,
+    fail_timeout: Duration,
+}
+
+struct Upstream {
+    /// `host:port`, to connect to and to send as `Host`.
+    authority: String,
+    /// Failures since the last success.
+    fails: AtomicUsize,
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Proxy {
+    /// A proxy to `upstreams`, each `http://host:port` or just `host:port`.
+    /// The port defaults to 80.
+    pub fn new<S: AsRef<str>>(upstreams: impl IntoIterator<Item = S>) -> io::Result<Proxy> {
+        let upstreams = upstreams
+            .into_iter()
+            .map(|spec| Upstream::parse(spec.as_ref()))
+            .collect::<io::Result<Vec<_>>>()?;
+        if upstreams.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no upstreams given",
+            ));
+        }
+
+        Ok(Proxy {
+            upstreams,
+            next: AtomicUsize::new(0),
+            strip_prefix: None,
+            preserve_host: false,
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+            max_fails: 3,
+            fail_timeout: Duration::from_secs(10),
+        })
+    }
+
+    /// Remove `prefix` from request paths before passing them on, so
+    /// `/api/users` reaches the upstream as `/users`.
+    pub fn strip_prefix(mut self, prefix: impl Into<String>) -> Proxy {
+        let prefix: String = prefix.into();
+        self.strip_prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    /// Send the client's `Host` header rather than the upstream's.
+    /// Off by default.
+    pub fn preserve_host(mut self, preserve: bool) -> Proxy {
+        self.preserve_host = preserve;
+        self
+    }
+
+    /// How long to wait for an upstream to accept a connection. Defaults
+    /// to 5 seconds.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Proxy {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// How long one read from or write to an upstream may wait. Defaults
+    /// to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Proxy {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Failures in a row that take an upstream out of the rotation.
+    /// Defaults to 3; zero never takes one out.
+    pub fn max_fails(mut self, max: usize) -> Proxy {
+        self.max_fails = max;
+        self
+    }
+
+    /// How long a failing upstream is left out of the rotation. Defaults
+    /// to 10 seconds.
+    pub fn fail_timeout(mut self, timeout: Duration) -> Proxy {
+        self.fail_timeout = timeout;
+        self
+    }
+
+    /// The upstreams to try for the next request, in round-robin order,
+    /// leaving out those marked down unless every one of them is.
+    fn candidates(&self) -> Vec<&Upstream> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let count = self.upstreams.len();
+        let ordered: Vec<&Upstream> = (0..count)
+            .map(|i| &self.upstreams[(start + i) % count])
+            .collect();
+
+        let now = Instant::now();
+        let up: Vec<&Upstream> = ordered.iter().copied().filter(|u| u.is_up(now)).collect();
+        if up.is_empty() {
+            ordered
+        } else {
+            up
+        }
+    }
+
+    fn failed(&self, upstream: &Upstream) {
+        let fails = upstream.fails.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.max_fails > 0 && fails >= self.max_fails {
+            upstream.fails.store(0, Ordering::Relaxed);
+            *upstream.down() = Some(Instant::now() + self.fail_timeout);
+            crate::warn!(
+                "Upstream {} failed {fails} times; skipping it for {:?}",
+                upstream.authority,
+                self.fail_timeout
+            );
+        }
+    }
+
+    fn connect(&self, upstream: &Upstream) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+        for addr in upstream.authority.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    stream.set_nodelay(true)?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Send `request` over `stream` and read the head of the response,
+    /// leaving its body to be streamed.
+    fn exchange(
+        &self,
+        upstream: &Upstream,
+        stream: TcpStream,
+        request: &Request,
+    ) -> io::Result<Response> {
+        let mut writer = BufWriter::new(&stream);
+        writer.write_all(self.head(upstream, request).as_bytes())?;
+        match &request.body_reader {
+            Some(body) => {
+                let chunked = request.headers.contains("Transfer-Encoding");
+                copy_body(&mut body.clone(), &mut writer, chunked)?;
+            }
+            None => writer.write_all(&request.body)?,
+        }
+        writer.flush()?;
+        drop(writer);
+
+        let mut reader = BufReader::new(stream);
+        let (status, headers) = loop {
+            let (status, headers) = read_head(&mut reader).map_err(upstream_error)?;
+            match status.code() {
+                // `Upgrade` isn't passed on, so the upstream has no reason to
+                // switch, and there's no tunnel to switch over.
+                101 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "upstream switched protocols",
+                    ))
+                }
+                // Interim responses such as `100 Continue` are not relayed.
+                100..=199 => {}
+                _ => break (status, headers),
+            }
+        };
+        let mut response = Response::new(status);
+        response.headers = end_to_end(&headers);
+
+        if request.method == Method::Head || status.forbids_body() {
+            return Ok(response);
+        }
+        let length = body_length(&headers).map_err(upstream_error)?;
+        // The length is filled in again when the response is written.
+        response.headers.remove("Content-Length");
+        response.body = if headers.contains("Transfer-Encoding") {
+            Stream::new(Chunked::new(reader, MAX_HEAD_SIZE), None).into()
+        } else {
+            Stream::new(reader, length).into()
+        };
+        Ok(response)
+    }
+
+    /// The request line and headers to send upstream.
+    fn head(&self, upstream: &Upstream, request: &Request) -> String {
+        let path = match &self.strip_prefix {
+            Some(prefix) => strip_prefix(&request.path, prefix),
+            None => &request.path,
+        };
+        let mut head = format!("{} {path}", request.method);
+        if let Some(query) = &request.query {
+            let _ = write!(head, "?{query}");
+        }
+        head.push_str(" HTTP/1.1\r\n");
+
+        let mut headers = end_to_end(&request.headers);
+        // The client was never told to continue, and sends its body anyway.
+        headers.remove("Expect");
+        if !self.preserve_host || !headers.contains("Host") {
+            headers.set("Host", upstream.authority.as_str());
+        }
+        if let Some(peer) = request.peer {
+            let mut forwarded: Vec<String> = request
+                .headers
+                .get_all("X-Forwarded-For")
+                .map(String::from)
+                .collect();
+            forwarded.push(peer.ip().to_string());
+            headers.set("X-Forwarded-For", forwarded.join(", "));
+        }
+        let proto = if request.https { "https" } else { "http" };
+        headers.set("X-Forwarded-Proto", proto);
+        if let Some(host) = request.headers.get("Host") {
+            headers.set("X-Forwarded-Host", host);
+        }
+        headers.remove("Content-Length");
+        let length = match &request.body_reader {
+            Some(_) => body_length(&request.headers).ok().flatten().unwrap_or(0),
+            None => request.body.len() as u64,
+        };
+        if request.body_reader.is_some() && request.headers.contains("Transfer-Encoding") {
+            headers.set("Transfer-Encoding", "chunked");
+        } else if length > 0 || matches!(request.method, Method::Post | Method::Put | Method::Patch)
+        {
+            headers.set("Content-Length", length.to_string());
+        }
+        headers.set("Connection", "close");
+
+        for (name, value) in headers.iter() {
+            let _ = write!(head, "{name}: {value}\r\n");
+        }
+        head.push_str("\r\n");
+        head
+    }
+}
+
+impl Handler for Proxy {
+    fn handle(&self, request: &mut Request) -> Response {
+        let mut status = StatusCode::BadGateway;
+
+        for upstream in self.candidates() {
+            let stream = match self.connect(upstream) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    crate::warn!("Problem connecting to upstream {}: {e}", upstream.authority);
+                    self.failed(upstream);
+                    status = failure_status(&e);
+                    continue;
+                }
+            };
+
+            return match self.exchange(upstream, stream, request) {
+                Ok(response) => {
+                    upstream.fails.store(0, Ordering::Relaxed);
+                    response
+                }
+                Err(e) => match client_error(&e) {
+                    Some(error) => {
+                        crate::debug!("Problem reading request body to proxy: {e}");
+                        // A client that hung up won't hear this anyway.
+                        let status = error.status().unwrap_or(StatusCode::BadRequest);
+                        Response::new(status).header("Connection", "close")
+                    }
+                    None => {
+                        crate::warn!("Problem proxying to upstream {}: {e}", upstream.authority);
+                        self.failed(upstream);
+                        Response::new(failure_status(&e))
+                    }
+                },
+            };
+        }
+
+        Response::new(status)
+    }
+
+    fn streams_body(&self, _request: &Request) -> bool {
+        true
+    }
+}
+
+impl Upstream {
+    fn parse(spec: &str) -> io::Result<Upstream> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+        let authority = match spec.split_once("://") {
+            Some(("http", rest)) => rest,
+            Some((scheme, _)) => {
+                return Err(invalid(format!(
+                    "unsupported upstream scheme {scheme:?} in {spec:?}"
+                )))
+            }
+            None => spec,
+        };
+        let authority = authority.strip_suffix('/').unwrap_or(authority);
+        if authority.is_empty() || authority.contains(['/', '?', '#', '@', ' ']) {
+            return Err(invalid(format!("expected http://host:port, got {spec:?}")));
+        }
+
+        let has_port = authority.rsplit_once(':').is_some_and(|(host, port)| {
+            !port.is_empty()
+                && port.bytes().all(|b| b.is_ascii_digit())
+                && (!host.contains(':') || host.ends_with(']'))
+        });
+        let authority = if has_port {
+            authority.to_string()
+        } else {
+            format!("{authority}:80")
+        };
+
+        Ok(Upstream {
+            authority,
+            fails: AtomicUsize::new(0),
+            down_until: Mutex::new(None),
+        })
+    }
+
+    fn down(&self) -> std::sync::MutexGuard<'_, Option<Instant>> {
+        self.down_until.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn is_up(&self, now: Instant) -> bool {
+        self.down().is_none_or(|until| until <= now)
+    }
+}
+
+/// `path` without `prefix`, still starting with `/`.
+fn strip_prefix<'a>(path: &'a str, prefix: &str) -> &'a str {
+    match path.strip_prefix(prefix) {
+        Some("") => "/",
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => path,
+    }
+}
+
+/// `headers` without the hop-by-hop ones, including any that the
+/// `Connection` header names.
+fn end_to_end(headers: &Headers) -> Headers {
+    let named: Vec<String> = headers
+        .get_all("Connection")
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .collect();
+
+    let mut kept = Headers::new();
+    for (name, value) in headers.iter() {
+        let hop_by_hop = HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name))
+            || named.contains(&name.to_ascii_lowercase());
+        if !hop_by_hop {
+            kept.append(name, value);
+        }
+    }
+    kept
+}
+
+/// Read a response's status line and headers.
+fn read_head<R: BufRead>(reader: &mut R) -> Result<(StatusCode, Headers), ParseError> {
+    let mut budget = MAX_HEAD_SIZE;
+    let line = read_line(reader, &mut budget)?.ok_or(ParseError::Closed)?;
+
+    let mut parts = line.splitn(3, ' ');
+    let code = match (parts.next(), parts.next()) {
+        (Some("HTTP/1.1" | "HTTP/1.0"), Some(code)) if code.len() == 3 => code.parse().ok(),
+        _ => None,
+    };
+    let code = code
+        .filter(|code| (100..600).contains(code))
+        .ok_or(ParseError::RequestLine)?;
+    let status = StatusCode::from_code(code).unwrap_or(StatusCode::Other(code));
+
+    let limits = Limits {
+        max_header_size: MAX_HEAD_SIZE,
+        max_headers: 256,
+        ..Limits::default()
+    };
+    let headers = read_headers(reader, &mut budget, &limits)?;
+    Ok((status, headers))
+}
+
+/// A parse error in what an upstream sent, as an I/O error.
+fn upstream_error(error: ParseError) -> io::Error {
+    match error {
+        ParseError::Io(e) => e,
+        ParseError::TimedOut => io::ErrorKind::TimedOut.into(),
+        ParseError::Closed => io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "upstream closed the connection",
+        ),
+        _ => io::Error::new(io::ErrorKind::InvalidData, "malformed response"),
+    }
+}
+
+/// What the client did wrong, if `error` came from reading its body.
+fn client_error(error: &io::Error) -> Option<&ParseError> {
+    error.get_ref()?.downcast_ref()
+}
+
+/// Send on a streamed request body as it arrives, in chunks if `chunked`.
+fn copy_body<W: Write>(body: &mut impl Read, writer: &mut W, chunked: bool) -> io::Result<()> {
+    let mut chunk = vec![0; 16 * 1024];
+    loop {
+        let read = body.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        if chunked {
+            write!(writer, "{read:x}\r\n")?;
+            writer.write_all(&chunk[..read])?;
+            writer.write_all(b"\r\n")?;
+        } else {
+            writer.write_all(&chunk[..read])?;
+        }
+        // The upstream may answer or act on part of the body.
+        writer.flush()?;
+    }
+    if chunked {
+        writer.write_all(b"0\r\n\r\n")?;
+    }
+    Ok(())
+}
+
+fn failure_status(error: &io::Error) -> StatusCode {
+    match error.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => StatusCode::GatewayTimeout,
+        _ => StatusCode::BadGateway,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(raw: &str) -> Request {
+        let mut request = Request::read_from(&mut raw.as_bytes()).unwrap();
+        request.peer = Some("203.0.113.7:4000".parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn parses_upstreams() {
+        let authority = |spec| Upstream::parse(spec).map(|u| u.authority);
+        assert_eq!(
+            authority("http://127.0.0.1:9000").unwrap(),
+            "127.0.0.1:9000"
+        );
+        assert_eq!(authority("backend/").unwrap(), "backend:80");
+        assert_eq!(authority("[::1]").unwrap(), "[::1]:80");
+        assert_eq!(authority("http://[::1]:8080").unwrap(), "[::1]:8080");
+        assert!(authority("https://backend").is_err());
+        assert!(authority("http://backend/path").is_err());
+        assert!(Proxy::new(Vec::<String>::new()).is_err());
+    }
+
+    #[test]
+    fn rewrites_the_request_head() {
+        let proxy = Proxy::new(["backend:9000"]).unwrap().strip_prefix("/api/");
+        let request = request(
+            "POST /api/users?page=2 HTTP/1.1\r\n\
+             Host: example.com\r\n\
+             Connection: keep-alive, X-Secret, Upgrade\r\n\
+             X-Secret: hop\r\n\
+             Upgrade: websocket\r\n\
+             X-Forwarded-For: 198.51.100.1\r\n\
+             Content-Length: 2\r\n\
+             \r\n\
+             hi",
+        );
+
+        let head = proxy.head(&proxy.upstreams[0], &request);
+        assert!(head.starts_with("POST /users?page=2 HTTP/1.1\r\n"));
+        assert!(head.contains("\r\nHost: backend:9000\r\n"));
+        assert!(head.contains("\r\nX-Forwarded-For: 198.51.100.1, 203.0.113.7\r\n"));
+        assert!(head.contains("\r\nX-Forwarded-Proto: http\r\n"));
+        assert!(head.contains("\r\nX-Forwarded-Host: example.com\r\n"));
+        assert!(head.contains("\r\nContent-Length: 2\r\n"));
+        assert!(head.contains("\r\nConnection: close\r\n"));
+        assert!(!head.contains("X-Secret"));
+        assert!(!head.contains("keep-alive"));
+        assert!(!head.contains("Upgrade"));
+        assert!(!head.contains("websocket"));
+
+        assert_eq!(strip_prefix("/api", "/api"), "/");
+        assert_eq!(strip_prefix("/apis", "/api"), "/apis");
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    ops::Range,
+};
+
+use crate::{
+    date::DateTime,
+    request::{Method, Request},
+    response::{Body, Response, StatusCode},
+};
+
+/// More ranges than this in one request are answered with the whole file;
+/// hundreds of tiny ranges are a well-known way to make a server work hard.
+const MAX_RANGES: usize = 16;
+
+/// Why a `Range` header could not be honoured.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeError {
+    /// Not a byte range set we understand; the header is ignored.
+    Invalid,
+    /// Every range starts past the end of the file: `416`.
+    Unsatisfiable,
+}
+
+/// Parse a `Range: bytes=...` header against a resource of `len` bytes.
+///
+/// Ranges that overlap or touch are merged, so the result is sorted and
+/// disjoint. Ranges that start past the end are dropped, and the error is
+/// only `Unsatisfiable` if nothing is left.
+pub fn parse(header: &str, len: u64) -> Result<Vec<Range<u64>>, RangeError> {
+    let (unit, set) = header.split_once('=').ok_or(RangeError::Invalid)?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Err(RangeError::Invalid);
+    }
+
+    let mut ranges = Vec::new();
+    for spec in set.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (first, last) = spec.split_once('-').ok_or(RangeError::Invalid)?;
+        let number = |s: &str| s.trim().parse::<u64>().map_err(|_| RangeError::Invalid);
+
+        let range = match (first.trim(), last.trim()) {
+            ("", "") => return Err(RangeError::Invalid),
+            // The last n bytes.
+            ("", suffix) => len.saturating_sub(number(suffix)?)..len,
+            (first, "") => number(first)?..len,
+            (first, last) => {
+                let (first, last) = (number(first)?, number(last)?);
+                if last < first {
+                    return Err(RangeError::Invalid);
+                }
+                first..last.saturating_add(1).min(len)
+            }
+        };
+        if range.start < len && !range.is_empty() {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.is_empty() {
+        return Err(RangeError::Unsatisfiable);
+    }
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    Ok(merged)
+}
+
+/// Whether `If-Range` lets the `Range` header apply: it must name the
+/// current strong `ETag` or exact `Last-Modified` date.
+fn if_range_matches(request: &Request, response: &Response) -> bool {
+    let Some(condition) = request.headers.get("If-Range").map(str::trim) else {
+        return true;
+    };
+    if condition.starts_with('"') {
+        return response.headers.get("ETag") == Some(condition);
+    }
+    match (
+        DateTime::parse_http(condition),
+        response
+            .headers
+            .get("Last-Modified")
+            .and_then(DateTime::parse_http),
+    ) {
+        (Some(condition), Some(modified)) => condition == modified,
+        _ => false,
+    }
+}
+
+/// Turn a `200` file response into a `206 Partial Content` for the ranges
+/// `request` asks for, or a `416` if none of them exist.
+///
+/// Anything else, including requests without `Range` or with one that
+/// can't be parsed, is returned as it was, with `Accept-Ranges: bytes`
+/// added to file responses.
+pub fn apply(request: &Request, mut response: Response) -> Response {
+    let Body::File { file, offset, len } = &response.body else {
+        return response;
+    };
+    if response.status != StatusCode::Ok {
+        return response;
+    }
+    let (base, len) = (*offset, *len);
+    response.headers.set("Accept-Ranges", "bytes");
+
+    let Some(header) = request.headers.get("Range") else {
+        return response;
+    };
+    if request.method != Method::Get || !if_range_matches(request, &response) {
+        return response;
+    }
+
+    let ranges = match parse(header, len) {
+        Ok(ranges) if ranges.len() <= MAX_RANGES => ranges,
+        Ok(_) | Err(RangeError::Invalid) => return response,
+        Err(RangeError::Unsatisfiable) => {
+            return Response::new(StatusCode::RangeNotSatisfiable)
+                .header("Content-Range", format!("bytes */{len}"))
+                .header("Accept-Ranges", "bytes");
+        }
+    };
+
+    let slice = |range: &Range<u64>| -> Option<Body> {
+        Some(Body::File {
+            file: file.try_clone().ok()?,
+            offset: base + range.start,
+            len: range.end - range.start,
+        })
+    };
+    let content_range =
+        |range: &Range<u64>| format!("bytes {}-{}/{len}", range.start, range.end - 1);
+
+    let mut partial = Response::new(StatusCode::PartialContent);
+    for name in [
+        "ETag",
+        "Last-Modified",
+        "Cache-Control",
+        "Vary",
+        "Content-Encoding",
+        "Accept-Ranges",
+    ] {
+        if let Some(value) = response.headers.get(name) {
+            partial.headers.set(name, value);
+        }
+    }
+    let content_type = response
+        .headers
+        .get("Content-Type")
+        .unwrap_or("application/octet-stream");
+
+    if let [range] = &ranges[..] {
+        let Some(body) = slice(range) else {
+            return response;
+        };
+        return partial
+            .header("Content-Type", content_type)
+            .header("Content-Range", content_range(range))
+            .body(body);
+    }
+
+    let boundary = format!("hello-{:016x}", RandomState::new().hash_one(header));
+    let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+    for (i, range) in ranges.iter().enumerate() {
+        let separator = if i == 0 { "" } else { "\r\n" };
+        parts.push(Body::Text(format!(
+            "{separator}--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+            content_range(range)
+        )));
+        let Some(body) = slice(range) else {
+            return response;
+        };
+        parts.push(body);
+    }
+    parts.push(Body::Text(format!("\r\n--{boundary}--\r\n")));
+
+    partial
+        .header(
+            "Content-Type",
+            format!("multipart/byteranges; boundary={boundary}"),
+        )
+        .body(Body::Parts(parts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn parses_byte_range_sets() {
+        assert_eq!(parse("bytes=0-499", 1000), Ok(vec![0..500]));
+        assert_eq!(parse("bytes=500-", 1000), Ok(vec![500..1000]));
+        assert_eq!(parse("bytes=-200", 1000), Ok(vec![800..1000]));
+        assert_eq!(parse("bytes=-2000", 1000), Ok(vec![0..1000]));
+        assert_eq!(parse("bytes=900-1999", 1000), Ok(vec![900..1000]));
+        assert_eq!(
+            parse("bytes=500-599, 0-99, 50-149", 1000),
+            Ok(vec![0..150, 500..600])
+        );
+        assert_eq!(parse("bytes=0-0,1000-", 1000), Ok(vec![0..1]));
+
+        assert_eq!(parse("bytes=1000-", 1000), Err(RangeError::Unsatisfiable));
+        assert_eq!(parse("bytes=-0", 1000), Err(RangeError::Unsatisfiable));
+        assert_eq!(parse("bytes=5-1", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse("bytes=x-1", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse("items=0-1", 1000), Err(RangeError::Invalid));
+    }
+}
@@ -0,0 +1,422 @@
+//! Token-bucket rate limiting by client address.
+//!
+//! Every client gets a bucket holding up to `burst` requests that refills
+//! at a steady [`Rate`]. A request takes one token; with none left it is
+//! answered `429 Too Many Requests` with `Retry-After` saying when the
+//! next token arrives. Clients are told apart by their socket address, or
+//! for IPv6 by its /64, since one host usually has a whole /64 to pick
+//! from.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    middleware::Middleware,
+    request::{normalize_path, Request},
+    response::{Response, StatusCode},
+};
+
+/// How often idle buckets are looked for.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A number of requests allowed per period, such as `10/s` or `5/m`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub requests: u32,
+    pub per: Duration,
+}
+
+impl Rate {
+    pub fn per_second(requests: u32) -> Rate {
+        Rate {
+            requests,
+            per: Duration::from_secs(1),
+        }
+    }
+
+    pub fn per_minute(requests: u32) -> Rate {
+        Rate {
+            requests,
+            per: Duration::from_secs(60),
+        }
+    }
+
+    pub fn per_hour(requests: u32) -> Rate {
+        Rate {
+            requests,
+            per: Duration::from_secs(3600),
+        }
+    }
+
+    /// Tokens added per second.
+    fn refill(self) -> f64 {
+        f64::from(self.requests) / self.per.as_secs_f64()
+    }
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    /// Parse `N/s`, `N/m` or `N/h`.
+    fn from_str(s: &str) -> Result<Rate, String> {
+        let invalid = || format!("expected a rate like \"10/s\", \"5/m\" or \"100/h\", got {s:?}");
+        let (requests, unit) = s.trim().split_once('/').ok_or_else(invalid)?;
+        let requests: u32 = requests.trim().parse().map_err(|_| invalid())?;
+        if requests == 0 {
+            return Err(invalid());
+        }
+        match unit.trim() {
+            "s" | "sec" | "second" => Ok(Rate::per_second(requests)),
+            "m" | "min" | "minute" => Ok(Rate::per_minute(requests)),
+            "h" | "hour" => Ok(Rate::per_hour(requests)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = match self.per.as_secs() {
+            60 => "m",
+            3600 => "h",
+            _ => "s",
+        };
+        write!(f, "{}/{unit}", self.requests)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    rate: Rate,
+    burst: u32,
+}
+
+impl Limit {
+    /// How long an untouched bucket takes to fill up again, after which
+    /// it is no different from a new one.
+    fn refill_time(self) -> Duration {
+        Duration::from_secs_f64(f64::from(self.burst) / self.rate.refill())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: Limit, now: Instant) -> Bucket {
+        Bucket {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        }
+    }
+
+    /// Take a token, or say how long until one is available.
+    fn take(&mut self, limit: Limit, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate.refill()).min(f64::from(limit.burst));
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / limit.rate.refill(),
+            ))
+        }
+    }
+}
+
+/// Which limit a bucket counts against: the default one, or a route's.
+type Key = (IpAddr, Option<usize>);
+
+struct Buckets {
+    map: HashMap<Key, Bucket>,
+    last_sweep: Instant,
+}
+
+/// Limits how fast each client may send requests.
+///
+/// [`limit`](RateLimit::limit) applies to every path; a
+/// [`route`](RateLimit::route) gives the paths under a prefix a separate
+/// limit, counted in separate buckets, instead. Requests without a known
+/// client address, or on paths with no limit, pass untouched.
+pub struct RateLimit {
+    default: Option<Limit>,
+    routes: Vec<(String, Limit)>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimit {
+    pub fn new() -> RateLimit {
+        RateLimit {
+            default: None,
+            routes: Vec::new(),
+            buckets: Mutex::new(Buckets {
+                map: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    /// Allow `rate` on every path, in bursts of up to `rate.requests`
+    /// unless [`burst`](RateLimit::burst) says otherwise.
+    pub fn limit(mut self, rate: Rate) -> RateLimit {
+        self.default = Some(Limit {
+            rate,
+            burst: rate.requests,
+        });
+        self
+    }
+
+    /// Requests a client may make at once before being held to the rate.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no [`limit`](RateLimit::limit) has been set or `burst` is
+    /// zero.
+    pub fn burst(mut self, burst: u32) -> RateLimit {
+        assert!(burst > 0, "burst must be at least 1");
+        let limit = self
+            .default
+            .as_mut()
+            .expect("burst needs a limit to apply to");
+        limit.burst = burst;
+        self
+    }
+
+    /// Allow `rate` on `prefix` and everything below it, in bursts of up
+    /// to `rate.requests`. The longest matching prefix wins.
+    pub fn route(mut self, prefix: &str, rate: Rate) -> RateLimit {
+        let prefix = match prefix.trim_end_matches('/') {
+            "" => "/",
+            trimmed => trimmed,
+        };
+        self.routes.push((
+            prefix.to_string(),
+            Limit {
+                rate,
+                burst: rate.requests,
+            },
+        ));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.routes.is_empty()
+    }
+
+    /// The limit for `path`, and which route it came from.
+    fn limit_for(&self, path: &str) -> Option<(Option<usize>, Limit)> {
+        let route = self
+            .routes
+            .iter()
+            .enumerate()
+            .filter(|(_, (prefix, _))| {
+                prefix == "/"
+                    || path
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .max_by_key(|(_, (prefix, _))| prefix.len());
+        match route {
+            Some((i, (_, limit))) => Some((Some(i), *limit)),
+            None => self.default.map(|limit| (None, limit)),
+        }
+    }
+
+    fn limit_of(&self, route: Option<usize>) -> Option<Limit> {
+        match route {
+            Some(i) => self.routes.get(i).map(|(_, limit)| *limit),
+            None => self.default,
+        }
+    }
+
+    /// Take a token for `client` on `path` at `now`, or say how long until
+    /// it may try again.
+    fn check(&self, client: IpAddr, path: &str, now: Instant) -> Result<(), Duration> {
+        let Some((route, limit)) = self.limit_for(path) else {
+            return Ok(());
+        };
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if now.saturating_duration_since(buckets.last_sweep) >= SWEEP_INTERVAL {
+            self.sweep(&mut buckets, now);
+        }
+        buckets
+            .map
+            .entry((client_key(client), route))
+            .or_insert_with(|| Bucket::full(limit, now))
+            .take(limit, now)
+    }
+
+    /// Drop buckets that have refilled completely; a new one would be the
+    /// same. This keeps memory to the clients seen recently.
+    fn sweep(&self, buckets: &mut Buckets, now: Instant) {
+        buckets.map.retain(|(_, route), bucket| {
+            self.limit_of(*route).is_some_and(|limit| {
+                now.saturating_duration_since(bucket.updated) < limit.refill_time()
+            })
+        });
+        buckets.last_sweep = now;
+    }
+
+    /// Number of clients currently tracked.
+    pub fn clients(&self) -> usize {
+        let buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets.map.len()
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> RateLimit {
+        RateLimit::new()
+    }
+}
+
+impl Middleware for RateLimit {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let client = request.peer?.ip();
+        // A path that doesn't decode might be under any route's limit.
+        let Some(path) = normalize_path(&request.path) else {
+            return Some(Response::new(StatusCode::BadRequest));
+        };
+        let wait = self.check(client, &path, Instant::now()).err()?;
+
+        crate::debug!("{client}: rate limited on {}", request.path);
+        // Round up, so a client that waits as told gets a token.
+        let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        Some(
+            Response::new(StatusCode::TooManyRequests)
+                .header("Retry-After", seconds.max(1).to_string()),
+        )
+    }
+}
+
+/// The address a client's bucket is kept under: its IPv4 address, or the
+/// /64 network of its IPv6 one.
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let network = u128::from(v6) & !((1u128 << 64) - 1);
+                IpAddr::V6(network.into())
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!("10/s".parse(), Ok(Rate::per_second(10)));
+        assert_eq!(" 5 / min ".parse(), Ok(Rate::per_minute(5)));
+        assert_eq!("100/h".parse(), Ok(Rate::per_hour(100)));
+        assert!("0/s".parse::<Rate>().is_err());
+        assert!("10".parse::<Rate>().is_err());
+        assert!("10/d".parse::<Rate>().is_err());
+        assert_eq!(Rate::per_minute(5).to_string(), "5/m");
+    }
+
+    #[test]
+    fn allows_bursts_then_refills() {
+        let limit = RateLimit::new().limit(Rate::per_second(2)).burst(3);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limit.check(CLIENT, "/", start), Ok(()));
+        }
+        let wait = limit.check(CLIENT, "/", start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+
+        // Other clients have their own bucket.
+        let other = IpAddr::from([192, 0, 2, 2]);
+        assert_eq!(limit.check(other, "/", start), Ok(()));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limit.check(CLIENT, "/", later), Ok(()));
+        assert!(limit.check(CLIENT, "/", later).is_err());
+    }
+
+    #[test]
+    fn limits_routes_separately() {
+        let limit = RateLimit::new()
+            .limit(Rate::per_second(100))
+            .route("/login/", Rate::per_minute(1));
+        let now = Instant::now();
+
+        assert_eq!(limit.check(CLIENT, "/login", now), Ok(()));
+        let wait = limit.check(CLIENT, "/login/form", now).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(60));
+        assert_eq!(limit.check(CLIENT, "/loginx", now), Ok(()));
+        assert_eq!(limit.check(CLIENT, "/", now), Ok(()));
+
+        let routes_only = RateLimit::new().route("/api", Rate::per_second(1));
+        assert_eq!(routes_only.check(CLIENT, "/", now), Ok(()));
+        assert_eq!(routes_only.check(CLIENT, "/", now), Ok(()));
+        assert_eq!(routes_only.clients(), 0);
+    }
+
+    #[test]
+    fn refuses_paths_that_do_not_decode() {
+        let limit = RateLimit::new().route("/login", Rate::per_minute(1));
+        for path in ["/login%zz", "/%6Cogin/%"] {
+            let raw = format!("GET {path} HTTP/1.1\r\n\r\n");
+            let mut request = Request::read_from(&mut raw.as_bytes()).unwrap();
+            request.peer = Some((CLIENT, 1234).into());
+            let response = limit.before(&mut request).unwrap();
+            assert_eq!(response.status, StatusCode::BadRequest, "{path}");
+        }
+        assert_eq!(limit.clients(), 0);
+    }
+
+    #[test]
+    fn groups_ipv6_clients_by_network() {
+        let a: Ipv6Addr = "2001:db8:1:2::1".parse().unwrap();
+        let b: Ipv6Addr = "2001:db8:1:2:ffff::9".parse().unwrap();
+        let c: Ipv6Addr = "2001:db8:1:3::1".parse().unwrap();
+        assert_eq!(client_key(a.into()), client_key(b.into()));
+        assert_ne!(client_key(a.into()), client_key(c.into()));
+
+        let mapped: Ipv6Addr = "::ffff:192.0.2.1".parse().unwrap();
+        assert_eq!(client_key(mapped.into()), CLIENT);
+    }
+
+    #[test]
+    fn evicts_idle_buckets() {
+        let limit = RateLimit::new().limit(Rate::per_second(1)).burst(5);
+        let start = Instant::now();
+        limit.check(CLIENT, "/", start).unwrap();
+        assert_eq!(limit.clients(), 1);
+
+        // Refilled after 5s, but nothing sweeps until the interval passes.
+        let idle = IpAddr::from([192, 0, 2, 9]);
+        limit
+            .check(idle, "/", start + Duration::from_secs(30))
+            .unwrap();
+        assert_eq!(limit.clients(), 2);
+
+        let later = start + SWEEP_INTERVAL + Duration::from_secs(1);
+        limit
+            .check(IpAddr::from([192, 0, 2, 3]), "/", later)
+            .unwrap();
+        assert_eq!(limit.clients(), 1);
+    }
+}
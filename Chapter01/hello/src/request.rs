@@ -0,0 +1,945 @@
+use std::{
+    any::Any,
+    fmt,
+    io::{self, BufRead, Read},
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{headers::Headers, response::StatusCode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Method, ParseError> {
+        match s {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "CONNECT" => Ok(Method::Connect),
+            "OPTIONS" => Ok(Method::Options),
+            "TRACE" => Ok(Method::Trace),
+            "PATCH" => Ok(Method::Patch),
+            _ => Err(ParseError::Method),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why a request could not be read off the wire.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The peer closed the connection before sending a request line.
+    Closed,
+    Io(io::Error),
+    RequestLine,
+    Method,
+    Target,
+    Version,
+    Header,
+    ContentLength,
+    TransferEncoding,
+    Chunk,
+    /// The request line and headers exceed [`Limits::max_header_size`] or
+    /// [`Limits::max_headers`].
+    HeadersTooLarge,
+    /// The body exceeds [`Limits::max_body_size`].
+    BodyTooLarge,
+    /// The client started a request but stopped sending it.
+    TimedOut,
+}
+
+impl ParseError {
+    /// Whether the client sent something we must refuse, rather than just
+    /// going away or going quiet.
+    pub fn is_malformed(&self) -> bool {
+        !matches!(
+            self,
+            ParseError::Closed | ParseError::Io(_) | ParseError::TimedOut
+        )
+    }
+
+    /// The status to answer with, if the client is still there to hear it.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ParseError::Closed | ParseError::Io(_) => None,
+            ParseError::TimedOut => Some(StatusCode::RequestTimeout),
+            ParseError::HeadersTooLarge => Some(StatusCode::RequestHeaderFieldsTooLarge),
+            ParseError::BodyTooLarge => Some(StatusCode::PayloadTooLarge),
+            _ => Some(StatusCode::BadRequest),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Closed => write!(f, "connection closed"),
+            ParseError::Io(e) => write!(f, "{e}"),
+            ParseError::RequestLine => write!(f, "malformed request line"),
+            ParseError::Method => write!(f, "unknown method"),
+            ParseError::Target => write!(f, "invalid request target"),
+            ParseError::Version => write!(f, "unsupported HTTP version"),
+            ParseError::Header => write!(f, "malformed header field"),
+            ParseError::ContentLength => write!(f, "invalid Content-Length"),
+            ParseError::TransferEncoding => write!(f, "unsupported Transfer-Encoding"),
+            ParseError::Chunk => write!(f, "malformed chunked body"),
+            ParseError::HeadersTooLarge => write!(f, "request header fields too large"),
+            ParseError::BodyTooLarge => write!(f, "request body too large"),
+            ParseError::TimedOut => write!(f, "timed out reading request"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => ParseError::Closed,
+            // A socket read timeout mid-request.
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ParseError::TimedOut,
+            _ => ParseError::Io(e),
+        }
+    }
+}
+
+/// Bounds on what a client may send, so one request can't exhaust memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Bytes in the request line and header fields together. Defaults to
+    /// 8 KiB.
+    pub max_header_size: usize,
+    /// Header fields in one request. Defaults to 100.
+    pub max_headers: usize,
+    /// Bytes of body, after any chunked framing is removed. Defaults to
+    /// 10 MiB.
+    pub max_body_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_header_size: 8 * 1024,
+            max_headers: 100,
+            max_body_size: 10 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    /// The path component of the request target, still percent-encoded.
+    pub path: String,
+    pub query: Option<String>,
+    pub version: Version,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+    /// The body still on the connection, when the handler reads it itself
+    /// rather than from [`body`](Request::body). See
+    /// [`Handler::streams_body`](crate::router::Handler::streams_body).
+    pub body_reader: Option<BodyReader>,
+    /// Values captured from the route pattern by the [`Router`](crate::router::Router).
+    pub params: Vec<(String, String)>,
+    /// The pattern of the route that matched, such as `/users/:id`, filled
+    /// in by the [`Router`](crate::router::Router).
+    pub route: Option<String>,
+    /// The client's address, filled in by the server.
+    pub peer: Option<SocketAddr>,
+    /// Whether the request arrived over HTTPS, filled in by the server.
+    pub https: bool,
+}
+
+impl Request {
+    /// Read one request, including its body, from `reader`, within the
+    /// default [`Limits`].
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Request, ParseError> {
+        Request::read_limited(reader, &Limits::default())
+    }
+
+    /// Read one request, including its body, from `reader`.
+    pub fn read_limited<R: BufRead>(
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<Request, ParseError> {
+        let mut request = Request::read_head(reader, limits)?;
+        request.read_body(reader, limits)?;
+        Ok(request)
+    }
+
+    /// Read the request line and headers, leaving the body, if any, to
+    /// [`read_body`](Request::read_body).
+    ///
+    /// A `Content-Length` over the limit is refused here, before any of
+    /// the body is read.
+    pub fn read_head<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, ParseError> {
+        let mut budget = limits.max_header_size;
+        let request_line = loop {
+            match read_line(reader, &mut budget)? {
+                None => return Err(ParseError::Closed),
+                // Tolerate stray CRLFs between requests (RFC 9112 section 2.2).
+                Some(line) if line.is_empty() => continue,
+                Some(line) => break line,
+            }
+        };
+
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v)) if parts.next().is_none() => (m, t, v),
+            _ => return Err(ParseError::RequestLine),
+        };
+
+        let method: Method = method.parse()?;
+        let version = match version {
+            "HTTP/1.1" => Version::Http11,
+            "HTTP/1.0" => Version::Http10,
+            _ => return Err(ParseError::Version),
+        };
+        let (path, query) = split_target(target)?;
+
+        let headers = read_headers(reader, &mut budget, limits)?;
+        if body_length(&headers)?.is_some_and(|length| length > limits.max_body_size) {
+            return Err(ParseError::BodyTooLarge);
+        }
+
+        Ok(Request {
+            method,
+            path,
+            query,
+            version,
+            headers,
+            body: Vec::new(),
+            body_reader: None,
+            params: Vec::new(),
+            route: None,
+            peer: None,
+            https: false,
+        })
+    }
+
+    /// A stand-in for a request from `peer` that couldn't be read, so the
+    /// answer to it can still be logged and counted. Its path is empty.
+    pub(crate) fn unread(peer: SocketAddr) -> Request {
+        Request {
+            method: Method::Get,
+            path: String::new(),
+            query: None,
+            version: Version::Http11,
+            headers: Headers::new(),
+            body: Vec::new(),
+            body_reader: None,
+            params: Vec::new(),
+            route: None,
+            peer: Some(peer),
+            https: false,
+        }
+    }
+
+    /// Whether this is a stand-in made by [`unread`](Request::unread).
+    pub fn is_unread(&self) -> bool {
+        self.path.is_empty()
+    }
+
+    /// Read the body that follows a head read by
+    /// [`read_head`](Request::read_head).
+    pub fn read_body<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<(), ParseError> {
+        self.body = if self.headers.contains("Transfer-Encoding") {
+            read_chunked(reader, limits)?
+        } else {
+            let length = body_length(&self.headers)?.unwrap_or(0);
+            let mut body = Vec::new();
+            reader.take(length).read_to_end(&mut body)?;
+            if body.len() as u64 != length {
+                return Err(ParseError::Closed);
+            }
+            body
+        };
+        Ok(())
+    }
+
+    /// A value captured by the matched route, such as `id` in `/users/:id`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The decoded value of the first query parameter called `name`.
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query.as_deref()?.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            if decode_form(key) == name {
+                Some(decode_form(value))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+fn split_target(target: &str) -> Result<(String, Option<String>), ParseError> {
+    let target = if target == "*" || target.starts_with('/') {
+        target
+    } else if let Some(rest) = target
+        .strip_prefix("http://")
+        .or_else(|| target.strip_prefix("https://"))
+    {
+        // absolute-form: drop the authority and keep the path.
+        match rest.find('/') {
+            Some(i) => &rest[i..],
+            None => "/",
+        }
+    } else {
+        return Err(ParseError::Target);
+    };
+
+    if target.bytes().any(|b| b <= b' ' || b == 0x7f || b == b'#') {
+        return Err(ParseError::Target);
+    }
+
+    Ok(match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    })
+}
+
+/// Read one CRLF- (or bare LF-) terminated line of at most `budget` bytes,
+/// taking its length off the budget. `None` means EOF before any bytes
+/// were read.
+pub(crate) fn read_line<R: BufRead>(
+    reader: &mut R,
+    budget: &mut usize,
+) -> Result<Option<String>, ParseError> {
+    let mut buf = Vec::new();
+    // One byte over the budget tells a line that is too long from one that
+    // fits exactly.
+    let read = reader
+        .take(*budget as u64 + 1)
+        .read_until(b'\n', &mut buf)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read > *budget {
+        return Err(ParseError::HeadersTooLarge);
+    }
+    *budget -= read;
+    if buf.pop() != Some(b'\n') {
+        return Err(ParseError::Closed);
+    }
+    if buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+
+    String::from_utf8(buf)
+        .map(Some)
+        .map_err(|_| ParseError::Header)
+}
+
+pub(crate) fn read_headers<R: BufRead>(
+    reader: &mut R,
+    budget: &mut usize,
+    limits: &Limits,
+) -> Result<Headers, ParseError> {
+    let mut headers = Headers::new();
+
+    loop {
+        let line = read_line(reader, budget)?.ok_or(ParseError::Closed)?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if headers.len() == limits.max_headers {
+            return Err(ParseError::HeadersTooLarge);
+        }
+
+        let (name, value) = line.split_once(':').ok_or(ParseError::Header)?;
+        if name.is_empty() || !name.bytes().all(is_token_byte) {
+            return Err(ParseError::Header);
+        }
+        headers.append(name, value.trim_matches([' ', '\t']));
+    }
+}
+
+/// Check the body framing: the `Content-Length`, or `None` for no body or
+/// a chunked one.
+pub(crate) fn body_length(headers: &Headers) -> Result<Option<u64>, ParseError> {
+    if let Some(encoding) = headers.get("Transfer-Encoding") {
+        if headers.contains("Content-Length") {
+            // Both framings at once is a request smuggling vector.
+            return Err(ParseError::TransferEncoding);
+        }
+        if !encoding.trim().eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::TransferEncoding);
+        }
+        return Ok(None);
+    }
+
+    let mut lengths = headers.get_all("Content-Length");
+    let length = match lengths.next() {
+        None => return Ok(None),
+        Some(value) => parse_content_length(value)?,
+    };
+    if lengths.any(|other| parse_content_length(other).ok() != Some(length)) {
+        return Err(ParseError::ContentLength);
+    }
+    Ok(Some(length))
+}
+
+fn parse_content_length(value: &str) -> Result<u64, ParseError> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ParseError::ContentLength);
+    }
+    value.parse().map_err(|_| ParseError::ContentLength)
+}
+
+/// The size on a chunk size line, ignoring any extensions.
+pub(crate) fn chunk_size(line: &str) -> Result<u64, ParseError> {
+    let size = line.split(';').next().unwrap_or("").trim();
+    u64::from_str_radix(size, 16).map_err(|_| ParseError::Chunk)
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    // Chunk size lines and trailers count against the header budget.
+    let mut budget = limits.max_header_size;
+
+    loop {
+        let line = read_line(reader, &mut budget)?.ok_or(ParseError::Closed)?;
+        let size = chunk_size(&line)?;
+
+        if size == 0 {
+            // Trailer fields are read and discarded.
+            read_headers(reader, &mut budget, limits)?;
+            return Ok(body);
+        }
+
+        // Read through `take` so a bogus size can't force a huge allocation,
+        // and only refuse a chunk over the limit once its bytes arrive.
+        let allowed = limits.max_body_size - body.len() as u64;
+        let read = reader
+            .by_ref()
+            .take(size.min(allowed + 1))
+            .read_to_end(&mut body)? as u64;
+        if read > allowed {
+            return Err(ParseError::BodyTooLarge);
+        }
+        if read != size {
+            return Err(ParseError::Closed);
+        }
+
+        if read_line(reader, &mut budget)?.as_deref() != Some("") {
+            return Err(ParseError::Chunk);
+        }
+    }
+}
+
+/// A request body left on the connection for a handler that streams it,
+/// decoded as it is read.
+///
+/// Reads end with the body, after removing any chunked framing, and fail
+/// once it grows past [`Limits::max_body_size`]. When the client is at
+/// fault, by sending garbage, too much or nothing more, the error wraps
+/// the [`ParseError`], so a handler can tell it apart from its own I/O
+/// errors. Clones read from the same place.
+#[derive(Clone)]
+pub struct BodyReader {
+    source: Arc<Mutex<Option<Box<dyn Source>>>>,
+}
+
+impl BodyReader {
+    /// The body framed by `headers`, as already checked by
+    /// [`Request::read_head`], to be read from `conn`.
+    pub(crate) fn new<R: BufRead + Send + 'static>(
+        conn: R,
+        headers: &Headers,
+        limits: &Limits,
+    ) -> BodyReader {
+        let body = match body_length(headers) {
+            Ok(None) if headers.contains("Transfer-Encoding") => {
+                Framing::Chunked(Chunked::new(conn, limits.max_header_size))
+            }
+            length => Framing::Length(conn.take(length.ok().flatten().unwrap_or(0))),
+        };
+        let framed = Framed {
+            body,
+            read: 0,
+            max: limits.max_body_size,
+        };
+        BodyReader {
+            source: Arc::new(Mutex::new(Some(Box::new(framed)))),
+        }
+    }
+
+    /// The connection back from the handler, and whether the whole body
+    /// was read from it. Clones left behind can no longer read.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the connection was already taken back or is not an `R`.
+    pub(crate) fn into_inner<R: BufRead + Send + 'static>(self) -> (R, bool) {
+        let source = self.lock().take().expect("connection already taken back");
+        let finished = source.is_finished();
+        let framed = source
+            .into_any()
+            .downcast::<Framed<R>>()
+            .expect("body read from another kind of connection");
+        (framed.body.into_inner(), finished)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Box<dyn Source>>> {
+        self.source.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.lock().as_mut() {
+            Some(source) => source.read(buf),
+            None => Err(io_error(ParseError::Closed)),
+        }
+    }
+}
+
+impl fmt::Debug for BodyReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyReader").finish_non_exhaustive()
+    }
+}
+
+/// A [`Framed`] body, whatever its connection is.
+trait Source: Read + Send {
+    /// Whether the body has been read to its end.
+    fn is_finished(&self) -> bool;
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+/// One request body on the connection `R`.
+struct Framed<R> {
+    body: Framing<R>,
+    /// Bytes of body read so far.
+    read: u64,
+    max: u64,
+}
+
+enum Framing<R> {
+    Length(io::Take<R>),
+    Chunked(Chunked<R>),
+}
+
+impl<R> Framing<R> {
+    fn into_inner(self) -> R {
+        match self {
+            Framing::Length(body) => body.into_inner(),
+            Framing::Chunked(body) => body.inner,
+        }
+    }
+}
+
+impl<R: BufRead + Send + 'static> Source for Framed<R> {
+    fn is_finished(&self) -> bool {
+        match &self.body {
+            Framing::Length(body) => body.limit() == 0,
+            Framing::Chunked(body) => body.done,
+        }
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl<R: BufRead> Read for Framed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = match &mut self.body {
+            Framing::Length(body) => match body.read(buf) {
+                Ok(0) if body.limit() > 0 && !buf.is_empty() => Err(io_error(ParseError::Closed)),
+                result => result,
+            },
+            Framing::Chunked(body) => body.read(buf),
+        };
+        let read = read.map_err(|e| {
+            if e.get_ref().is_some_and(|inner| inner.is::<ParseError>()) {
+                e
+            } else {
+                io_error(e.into())
+            }
+        })?;
+
+        self.read += read as u64;
+        if self.read > self.max {
+            return Err(io_error(ParseError::BodyTooLarge));
+        }
+        Ok(read)
+    }
+}
+
+/// Decodes a chunked body, ending after the last chunk and its trailers.
+pub(crate) struct Chunked<R> {
+    inner: R,
+    /// The longest chunk size line, or trailer section, allowed.
+    max_line: usize,
+    /// Bytes left in the current chunk.
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> Chunked<R> {
+    pub(crate) fn new(inner: R, max_line: usize) -> Chunked<R> {
+        Chunked {
+            inner,
+            max_line,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    fn next_chunk(&mut self) -> Result<(), ParseError> {
+        let mut budget = self.max_line;
+        let line = read_line(&mut self.inner, &mut budget)?.ok_or(ParseError::Closed)?;
+        self.remaining = chunk_size(&line)?;
+
+        if self.remaining == 0 {
+            // Trailer fields are read and discarded.
+            read_headers(&mut self.inner, &mut budget, &Limits::default())?;
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 && !self.done {
+            self.next_chunk().map_err(io_error)?;
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let max = self.remaining.min(buf.len() as u64) as usize;
+        let read = self.inner.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(io_error(ParseError::Closed));
+        }
+        self.remaining -= read as u64;
+
+        if self.remaining == 0 {
+            let mut budget = 2;
+            if read_line(&mut self.inner, &mut budget)
+                .map_err(io_error)?
+                .as_deref()
+                != Some("")
+            {
+                return Err(io_error(ParseError::Chunk));
+            }
+        }
+        Ok(read)
+    }
+}
+
+/// `error` as an I/O error of the matching kind, wrapping it.
+fn io_error(error: ParseError) -> io::Error {
+    let kind = match &error {
+        ParseError::Io(e) => e.kind(),
+        ParseError::Closed => io::ErrorKind::UnexpectedEof,
+        ParseError::TimedOut => io::ErrorKind::TimedOut,
+        _ => io::ErrorKind::InvalidData,
+    };
+    io::Error::new(kind, error)
+}
+
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Decode `%XX` escapes. Returns `None` for truncated or non-hex escapes.
+pub fn percent_decode(input: &str) -> Option<Vec<u8>> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            let hex = std::str::from_utf8(hex).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Some(out)
+}
+
+/// The path decoded, with empty and `.` segments dropped and `..` applied.
+/// Rules keyed on path prefixes match against this, so `/%61dmin` and
+/// `/x/../admin` can't slip past a rule for `/admin`. `None` if an escape is
+/// malformed; such a path can't be matched safely and should be refused.
+///
+/// Proxies still forward the path as it was received.
+pub(crate) fn normalize_path(path: &str) -> Option<String> {
+    let decoded = percent_decode(path)?;
+    let decoded = String::from_utf8_lossy(&decoded);
+
+    let mut segments = Vec::new();
+    for segment in decoded.split(['/', '\\']) {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    Some(format!("/{}", segments.join("/")))
+}
+
+fn decode_form(input: &str) -> String {
+    let input = input.replace('+', " ");
+    match percent_decode(&input) {
+        Some(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        None => input,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request, ParseError> {
+        Request::read_from(&mut raw.as_bytes())
+    }
+
+    #[test]
+    fn parses_request_line_and_headers() {
+        let request = parse(
+            "GET /search?q=rust+book&page=2 HTTP/1.1\r\n\
+             Host: localhost\r\n\
+             X-Thing:  spaced \r\n\
+             \r\n",
+        )
+        .unwrap();
+
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.path, "/search");
+        assert_eq!(request.query.as_deref(), Some("q=rust+book&page=2"));
+        assert_eq!(request.query_param("q").as_deref(), Some("rust book"));
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.headers.get("host"), Some("localhost"));
+        assert_eq!(request.headers.get("x-thing"), Some("spaced"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn reads_content_length_body() {
+        let request = parse(
+            "POST /submit HTTP/1.1\r\n\
+             Content-Length: 5\r\n\
+             \r\n\
+             helloEXTRA",
+        )
+        .unwrap();
+
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn reads_chunked_body() {
+        let request = parse(
+            "POST /submit HTTP/1.1\r\n\
+             Transfer-Encoding: chunked\r\n\
+             \r\n\
+             5;ext=1\r\nhello\r\n\
+             7\r\n, world\r\n\
+             0\r\n\
+             Trailer: ignored\r\n\
+             \r\n",
+        )
+        .unwrap();
+
+        assert_eq!(request.body, b"hello, world");
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let cases = [
+            "GET /\r\n\r\n",
+            "GET / HTTP/1.1 extra\r\n\r\n",
+            "FETCH / HTTP/1.1\r\n\r\n",
+            "GET / HTTP/2.0\r\n\r\n",
+            "GET relative HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1\r\nNo colon here\r\n\r\n",
+            "GET / HTTP/1.1\r\nBad Name: x\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+        ];
+
+        for raw in cases {
+            let err = parse(raw).unwrap_err();
+            assert!(err.is_malformed(), "{raw:?} gave {err:?}");
+        }
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = Limits {
+            max_header_size: 64,
+            max_headers: 2,
+            max_body_size: 8,
+        };
+        let parse = |raw: &str| Request::read_limited(&mut raw.as_bytes(), &limits);
+
+        assert!(parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n").is_ok());
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "x".repeat(64));
+        assert!(matches!(parse(&long), Err(ParseError::HeadersTooLarge)));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"),
+            Err(ParseError::HeadersTooLarge)
+        ));
+
+        assert!(parse("POST / HTTP/1.1\r\nContent-Length: 8\r\n\r\n12345678").is_ok());
+        // Refused before the body arrives.
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n"),
+            Err(ParseError::BodyTooLarge)
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n12345\r\n5\r\n12345\r\n0\r\n\r\n"),
+            Err(ParseError::BodyTooLarge)
+        ));
+    }
+
+    #[test]
+    fn truncated_request_is_not_malformed() {
+        assert!(matches!(parse(""), Err(ParseError::Closed)));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nHost: x"),
+            Err(ParseError::Closed)
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc"),
+            Err(ParseError::Closed)
+        ));
+    }
+
+    #[test]
+    fn streams_bodies_off_the_connection() {
+        let limits = Limits {
+            max_body_size: 12,
+            ..Limits::default()
+        };
+        let stream = |raw: &str| {
+            let mut conn = io::Cursor::new(raw.as_bytes().to_vec());
+            let request = Request::read_head(&mut conn, &limits).unwrap();
+            BodyReader::new(conn, &request.headers, &limits)
+        };
+
+        let mut reader = stream(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nTrailer: x\r\n\r\nNEXT",
+        );
+        let mut body = String::new();
+        reader.clone().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello, world");
+        let (conn, finished) = reader.clone().into_inner::<io::Cursor<Vec<u8>>>();
+        assert!(finished);
+        assert_eq!(&conn.get_ref()[conn.position() as usize..], b"NEXT");
+        // The connection is gone once taken back.
+        assert!(reader.read(&mut [0; 4]).is_err());
+
+        let reader = stream("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloNEXT");
+        let mut start = [0; 2];
+        reader.clone().read_exact(&mut start).unwrap();
+        let (_, finished) = reader.into_inner::<io::Cursor<Vec<u8>>>();
+        assert!(!finished);
+
+        let client_error = |raw: &str| {
+            let error = stream(raw).read_to_end(&mut Vec::new()).unwrap_err();
+            let inner = error.into_inner().unwrap();
+            inner.downcast::<ParseError>().unwrap().status()
+        };
+        assert_eq!(
+            client_error("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel"),
+            None
+        );
+        assert_eq!(
+            client_error("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"),
+            Some(StatusCode::BadRequest)
+        );
+        assert_eq!(
+            client_error(
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             10\r\n0123456789abcdef\r\n0\r\n\r\n"
+            ),
+            Some(StatusCode::PayloadTooLarge)
+        );
+    }
+}
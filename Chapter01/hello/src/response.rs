@@ -0,0 +1,511 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{date::DateTime, headers::Headers, mime, websocket::Upgrade};
+
+/// Sent as the `Server` header unless a handler sets its own.
+pub const SERVER: &str = concat!("hello/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatusCode {
+    SwitchingProtocols,
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
+    RangeNotSatisfiable,
+    UpgradeRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+    /// A status `hello` has no name for, such as one relayed from an
+    /// upstream server. Sent without a reason phrase.
+    Other(u16),
+}
+
+impl StatusCode {
+    const ALL: [StatusCode; 28] = [
+        StatusCode::SwitchingProtocols,
+        StatusCode::Ok,
+        StatusCode::Created,
+        StatusCode::Accepted,
+        StatusCode::NoContent,
+        StatusCode::PartialContent,
+        StatusCode::MovedPermanently,
+        StatusCode::Found,
+        StatusCode::SeeOther,
+        StatusCode::NotModified,
+        StatusCode::TemporaryRedirect,
+        StatusCode::PermanentRedirect,
+        StatusCode::BadRequest,
+        StatusCode::Unauthorized,
+        StatusCode::Forbidden,
+        StatusCode::NotFound,
+        StatusCode::MethodNotAllowed,
+        StatusCode::RequestTimeout,
+        StatusCode::PayloadTooLarge,
+        StatusCode::RangeNotSatisfiable,
+        StatusCode::UpgradeRequired,
+        StatusCode::TooManyRequests,
+        StatusCode::RequestHeaderFieldsTooLarge,
+        StatusCode::InternalServerError,
+        StatusCode::NotImplemented,
+        StatusCode::BadGateway,
+        StatusCode::ServiceUnavailable,
+        StatusCode::GatewayTimeout,
+    ];
+
+    pub fn code(self) -> u16 {
+        match self {
+            StatusCode::SwitchingProtocols => 101,
+            StatusCode::Ok => 200,
+            StatusCode::Created => 201,
+            StatusCode::Accepted => 202,
+            StatusCode::NoContent => 204,
+            StatusCode::PartialContent => 206,
+            StatusCode::MovedPermanently => 301,
+            StatusCode::Found => 302,
+            StatusCode::SeeOther => 303,
+            StatusCode::NotModified => 304,
+            StatusCode::TemporaryRedirect => 307,
+            StatusCode::PermanentRedirect => 308,
+            StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
+            StatusCode::Forbidden => 403,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::RequestTimeout => 408,
+            StatusCode::PayloadTooLarge => 413,
+            StatusCode::RangeNotSatisfiable => 416,
+            StatusCode::UpgradeRequired => 426,
+            StatusCode::TooManyRequests => 429,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::BadGateway => 502,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::GatewayTimeout => 504,
+            StatusCode::Other(code) => code,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            StatusCode::SwitchingProtocols => "Switching Protocols",
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "Created",
+            StatusCode::Accepted => "Accepted",
+            StatusCode::NoContent => "No Content",
+            StatusCode::PartialContent => "Partial Content",
+            StatusCode::MovedPermanently => "Moved Permanently",
+            StatusCode::Found => "Found",
+            StatusCode::SeeOther => "See Other",
+            StatusCode::NotModified => "Not Modified",
+            StatusCode::TemporaryRedirect => "Temporary Redirect",
+            StatusCode::PermanentRedirect => "Permanent Redirect",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
+            StatusCode::Forbidden => "Forbidden",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::PayloadTooLarge => "Content Too Large",
+            StatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            StatusCode::UpgradeRequired => "Upgrade Required",
+            StatusCode::TooManyRequests => "Too Many Requests",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::NotImplemented => "Not Implemented",
+            StatusCode::BadGateway => "Bad Gateway",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::GatewayTimeout => "Gateway Timeout",
+            StatusCode::Other(_) => "",
+        }
+    }
+
+    pub fn from_code(code: u16) -> Option<StatusCode> {
+        StatusCode::ALL.into_iter().find(|s| s.code() == code)
+    }
+
+    pub fn is_success(self) -> bool {
+        (200..300).contains(&self.code())
+    }
+
+    /// Responses that must not carry a body or `Content-Length`.
+    pub(crate) fn forbids_body(self) -> bool {
+        self.code() < 200 || matches!(self, StatusCode::NoContent | StatusCode::NotModified)
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
+/// What follows the headers.
+#[derive(Debug, Default)]
+pub enum Body {
+    #[default]
+    Empty,
+    Bytes(Vec<u8>),
+    Text(String),
+    /// `len` bytes of `file` from `offset` on, streamed from disk in chunks
+    /// rather than read into memory.
+    File {
+        file: File,
+        offset: u64,
+        len: u64,
+    },
+    /// Several bodies sent back to back, such as the parts of a
+    /// `multipart/byteranges` response.
+    Parts(Vec<Body>),
+    /// Bytes read from elsewhere as they are sent, such as an upstream
+    /// server's response.
+    Stream(Stream),
+}
+
+impl Body {
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::Empty => 0,
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::Text(text) => text.len() as u64,
+            Body::File { len, .. } => *len,
+            Body::Parts(parts) => parts.iter().map(Body::len).sum(),
+            // Unknown lengths count as zero.
+            Body::Stream(stream) => stream.len.unwrap_or(0),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.has_length() && self.len() == 0
+    }
+
+    /// Whether the length is known before the body is sent. If it isn't,
+    /// the response has no `Content-Length` and the connection is closed
+    /// to mark the end of the body.
+    pub fn has_length(&self) -> bool {
+        match self {
+            Body::Stream(stream) => stream.len.is_some(),
+            Body::Parts(parts) => parts.iter().all(Body::has_length),
+            _ => true,
+        }
+    }
+
+    /// The body's bytes if it is held in memory.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Empty => Some(&[]),
+            Body::Bytes(bytes) => Some(bytes),
+            Body::Text(text) => Some(text.as_bytes()),
+            Body::File { .. } | Body::Parts(_) | Body::Stream(_) => None,
+        }
+    }
+
+    /// Write the body, returning how many bytes that took.
+    fn write_to<W: Write>(&self, out: &mut W) -> io::Result<u64> {
+        match self {
+            Body::File { file, offset, len } => {
+                let mut file: &File = file;
+                file.seek(SeekFrom::Start(*offset))?;
+                let copied = io::copy(&mut file.take(*len), out)?;
+                if copied != *len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file shrank while it was being sent",
+                    ));
+                }
+                Ok(copied)
+            }
+            Body::Parts(parts) => parts.iter().map(|part| part.write_to(out)).sum(),
+            Body::Stream(stream) => {
+                let mut reader = stream.lock();
+                let copied = io::copy(&mut *reader, out)?;
+                stream.check(copied)?;
+                Ok(copied)
+            }
+            body => {
+                let bytes = body.as_bytes().unwrap_or_default();
+                out.write_all(bytes)?;
+                Ok(bytes.len() as u64)
+            }
+        }
+    }
+}
+
+/// A body read from a reader as it is sent.
+pub struct Stream {
+    // `Response::write_to` takes `&self`.
+    reader: Mutex<Box<dyn Read + Send>>,
+    len: Option<u64>,
+}
+
+impl Stream {
+    /// Send what `reader` yields. With a `len`, exactly that many bytes
+    /// are sent and a reader that ends early is an error; without one,
+    /// everything up to the end of the reader is.
+    pub fn new(reader: impl Read + Send + 'static, len: Option<u64>) -> Stream {
+        let reader: Box<dyn Read + Send> = match len {
+            Some(len) => Box::new(reader.take(len)),
+            None => Box::new(reader),
+        };
+        Stream {
+            reader: Mutex::new(reader),
+            len,
+        }
+    }
+
+    pub(crate) fn lock(&self) -> std::sync::MutexGuard<'_, Box<dyn Read + Send>> {
+        self.reader.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Check that `sent` bytes were the whole body.
+    pub(crate) fn check(&self, sent: u64) -> io::Result<()> {
+        match self.len {
+            Some(len) if sent != len => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "stream ended before its length",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stream").field("len", &self.len).finish()
+    }
+}
+
+impl From<Stream> for Body {
+    fn from(stream: Stream) -> Body {
+        Body::Stream(stream)
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Body {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Body {
+        Body::Text(text)
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Body {
+        Body::Text(text.to_string())
+    }
+}
+
+/// An HTTP response, built up with chained calls:
+///
+/// ```
+/// use hello::response::{Response, StatusCode};
+///
+/// let response = Response::new(StatusCode::Created)
+///     .header("Location", "/users/7")
+///     .with_body("application/json", r#"{"id":7}"#);
+/// assert_eq!(response.body.len(), 8);
+/// ```
+///
+/// `Date`, `Server` and `Content-Length` are filled in when the response is
+/// written unless a handler set them itself.
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Body,
+    /// Where a `101` response hands the connection over to.
+    pub(crate) upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::Empty,
+            upgrade: None,
+        }
+    }
+
+    /// A `200 OK` streaming the file at `path`, typed by its extension.
+    pub fn file(path: impl AsRef<Path>) -> io::Result<Response> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+
+        Ok(Response::new(StatusCode::Ok)
+            .header("Content-Type", mime::content_type(path))
+            .body(Body::File {
+                file,
+                offset: 0,
+                len,
+            }))
+    }
+
+    /// Set a header, replacing any earlier value.
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Response {
+        self.headers.set(name, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Body>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Set the body along with its `Content-Type`.
+    pub fn with_body(self, content_type: &str, body: impl Into<Body>) -> Response {
+        self.header("Content-Type", content_type).body(body)
+    }
+
+    /// Write the status line, headers and body. Returns the size of the
+    /// body sent, which for a stream isn't known until it has been.
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<u64> {
+        self.write_head(out)?;
+        let sent = if self.status.forbids_body() {
+            0
+        } else {
+            self.body.write_to(out)?
+        };
+        out.flush()?;
+        Ok(sent)
+    }
+
+    /// Write only the status line and headers, as for a `HEAD` request.
+    pub fn write_head<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        if !self.headers.contains("Date") {
+            head.push_str(&format!(
+                "Date: {}\r\n",
+                DateTime::from_system_time(SystemTime::now()).http()
+            ));
+        }
+        if !self.headers.contains("Server") {
+            head.push_str(&format!("Server: {SERVER}\r\n"));
+        }
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !self.status.forbids_body()
+            && !self.headers.contains("Content-Length")
+            && self.body.has_length()
+        {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+
+        out.write_all(head.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wire(response: &Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn serializes_status_headers_and_body() {
+        let response = Response::new(StatusCode::NotFound)
+            .header("Date", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .with_body("text/plain", "nope");
+
+        assert_eq!(
+            wire(&response),
+            format!(
+                "HTTP/1.1 404 Not Found\r\n\
+                 Server: {SERVER}\r\n\
+                 Date: Sun, 06 Nov 1994 08:49:37 GMT\r\n\
+                 Content-Type: text/plain\r\n\
+                 Content-Length: 4\r\n\
+                 \r\n\
+                 nope"
+            )
+        );
+    }
+
+    #[test]
+    fn no_content_has_no_length() {
+        let text = wire(&Response::new(StatusCode::NoContent));
+
+        assert!(text.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(text.contains("\r\nDate: "));
+        assert!(!text.contains("Content-Length"));
+    }
+
+    #[test]
+    fn streams_files() {
+        let response = Response::file("templates/hello.html").unwrap();
+        let text = wire(&response);
+
+        assert!(text.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(text.ends_with(&std::fs::read_to_string("templates/hello.html").unwrap()));
+    }
+
+    #[test]
+    fn status_codes_round_trip() {
+        for status in StatusCode::ALL {
+            assert_eq!(StatusCode::from_code(status.code()), Some(status));
+        }
+        assert_eq!(StatusCode::from_code(299), None);
+        assert_eq!(StatusCode::Other(299).to_string(), "299 ");
+    }
+
+    #[test]
+    fn streams_readers() {
+        let sized = Response::new(StatusCode::Ok).body(Stream::new(&b"hello, world"[..], Some(5)));
+        assert!(wire(&sized).ends_with("\r\nContent-Length: 5\r\n\r\nhello"));
+
+        let unknown = Response::new(StatusCode::Ok).body(Stream::new(&b"hello"[..], None));
+        assert_eq!(unknown.body.len(), 0);
+        let mut out = Vec::new();
+        assert_eq!(unknown.write_to(&mut out).unwrap(), 5);
+        let text = String::from_utf8(out).unwrap();
+        assert!(!text.contains("Content-Length"));
+        assert!(text.ends_with("\r\n\r\nhello"));
+
+        let short = Response::new(StatusCode::Ok).body(Stream::new(&b"hi"[..], Some(5)));
+        assert!(short.write_to(&mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,352 @@
+use std::sync::Arc;
+
+use crate::{
+    request::{percent_decode, Method, Request},
+    response::{Response, StatusCode},
+};
+
+/// Anything that can turn a request into a response.
+///
+/// Closures taking `&mut Request` implement this, as do [`Router`] and any
+/// type that wraps one.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, request: &mut Request) -> Response;
+
+    /// Whether [`handle`](Handler::handle) reads the body of `request`
+    /// itself, from [`Request::body_reader`] as it arrives, rather than
+    /// from [`Request::body`] once the server has read all of it. Only the
+    /// head of `request` has been read when this is asked. Defaults to
+    /// `false`.
+    ///
+    /// The connection is closed after the response if any of the body is
+    /// left unread.
+    fn streams_body(&self, _request: &Request) -> bool {
+        false
+    }
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&mut Request) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &mut Request) -> Response {
+        self(request)
+    }
+}
+
+impl<H: Handler + ?Sized> Handler for Arc<H> {
+    fn handle(&self, request: &mut Request) -> Response {
+        (**self).handle(request)
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        (**self).streams_body(request)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+struct Route {
+    /// `None` matches every method.
+    method: Option<Method>,
+    pattern: Vec<Segment>,
+    /// The pattern as written, reported in [`Request::route`].
+    source: String,
+    handler: Box<dyn Handler>,
+}
+
+impl Route {
+    fn accepts(&self, method: Method) -> bool {
+        self.method
+            .is_none_or(|m| m == method || (m == Method::Get && method == Method::Head))
+    }
+}
+
+/// Dispatches requests to handlers by method and path pattern.
+///
+/// Patterns are matched segment by segment. `:name` matches exactly one
+/// segment and is available percent-decoded through [`Request::param`].
+/// A trailing `*name` (or bare `*`) matches the rest of the path, which is
+/// kept percent-encoded so it can be handed to something like
+/// [`StaticFiles`](crate::static_files::StaticFiles) unchanged.
+///
+/// Routes are tried in the order they were added and the first match wins.
+/// `GET` routes also answer `HEAD`.
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Box<dyn Handler>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            not_found: Box::new(|_: &mut Request| Response::new(StatusCode::NotFound)),
+        }
+    }
+
+    /// Register `handler` for `method` requests whose path matches `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pattern does not start with `/` or has a wildcard
+    /// anywhere but the last segment.
+    pub fn route(&mut self, method: Method, pattern: &str, handler: impl Handler) -> &mut Router {
+        self.routes.push(Route {
+            method: Some(method),
+            pattern: parse_pattern(pattern),
+            source: pattern.to_string(),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&mut Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&mut Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&mut Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<F>(&mut self, pattern: &str, handler: F) -> &mut Router
+    where
+        F: Fn(&mut Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Register `handler` for requests of any method whose path matches
+    /// `pattern`, such as those passed on to a [`Proxy`](crate::proxy::Proxy).
+    ///
+    /// # Panics
+    ///
+    /// Panics on the same patterns as [`route`](Router::route).
+    pub fn any(&mut self, pattern: &str, handler: impl Handler) -> &mut Router {
+        self.routes.push(Route {
+            method: None,
+            pattern: parse_pattern(pattern),
+            source: pattern.to_string(),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Handler for requests that match no route at all.
+    pub fn not_found(&mut self, handler: impl Handler) -> &mut Router {
+        self.not_found = Box::new(handler);
+        self
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, request: &mut Request) -> Response {
+        let path: Vec<&str> = request.path.split('/').skip(1).collect();
+        let mut allowed = Vec::new();
+
+        for route in &self.routes {
+            let Some(params) = match_path(&route.pattern, &path) else {
+                continue;
+            };
+
+            if route.accepts(request.method) {
+                request.params = params;
+                request.route = Some(route.source.clone());
+                return route.handler.handle(request);
+            }
+
+            let methods: &[Method] = match &route.method {
+                Some(Method::Get) => &[Method::Get, Method::Head],
+                Some(method) => std::slice::from_ref(method),
+                None => &[],
+            };
+            for method in methods {
+                if !allowed.contains(method) {
+                    allowed.push(*method);
+                }
+            }
+        }
+
+        if allowed.is_empty() {
+            return self.not_found.handle(request);
+        }
+
+        let allow: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+        Response::new(StatusCode::MethodNotAllowed).header("Allow", allow.join(", "))
+    }
+
+    /// Asks the route [`handle`](Handler::handle) would pick.
+    fn streams_body(&self, request: &Request) -> bool {
+        let path: Vec<&str> = request.path.split('/').skip(1).collect();
+        self.routes
+            .iter()
+            .find(|route| {
+                route.accepts(request.method) && match_path(&route.pattern, &path).is_some()
+            })
+            .is_some_and(|route| route.handler.streams_body(request))
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    assert!(
+        pattern.starts_with('/'),
+        "route pattern must start with '/'"
+    );
+
+    let segments: Vec<Segment> = pattern
+        .split('/')
+        .skip(1)
+        .map(|s| {
+            if let Some(name) = s.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = s.strip_prefix('*') {
+                Segment::Wildcard(name.to_string())
+            } else {
+                Segment::Literal(s.to_string())
+            }
+        })
+        .collect();
+
+    let wildcards = segments
+        .iter()
+        .filter(|s| matches!(s, Segment::Wildcard(_)));
+    assert!(
+        wildcards.count() == 0 || matches!(segments.last(), Some(Segment::Wildcard(_))),
+        "wildcard must be the last segment of a route pattern"
+    );
+
+    segments
+}
+
+fn match_path(pattern: &[Segment], path: &[&str]) -> Option<Vec<(String, String)>> {
+    let mut params = Vec::new();
+
+    for (i, segment) in pattern.iter().enumerate() {
+        match segment {
+            Segment::Wildcard(name) => {
+                params.push((name.clone(), path.get(i..).unwrap_or(&[]).join("/")));
+                return Some(params);
+            }
+            Segment::Literal(literal) => {
+                if path.get(i) != Some(&literal.as_str()) {
+                    return None;
+                }
+            }
+            Segment::Param(name) => {
+                let value = path.get(i).filter(|v| !v.is_empty())?;
+                let value = percent_decode(value)?;
+                params.push((name.clone(), String::from_utf8_lossy(&value).into_owned()));
+            }
+        }
+    }
+
+    (pattern.len() == path.len()).then_some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(raw: &str) -> Request {
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(response.body.as_bytes().unwrap()).unwrap()
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router
+            .get("/", |_| {
+                Response::new(StatusCode::Ok).with_body("text/plain", "home")
+            })
+            .get("/users/:id", |req| {
+                let id = req.param("id").unwrap_or_default().to_string();
+                Response::new(StatusCode::Ok).with_body("text/plain", id)
+            })
+            .delete("/users/:id", |_| Response::new(StatusCode::NoContent))
+            .get("/files/*rest", |req| {
+                let rest = req.param("rest").unwrap_or_default().to_string();
+                Response::new(StatusCode::Ok).with_body("text/plain", rest)
+            });
+        router
+    }
+
+    #[test]
+    fn dispatches_by_pattern() {
+        let router = router();
+
+        let response = router.handle(&mut request("GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(body(&response), "home");
+
+        let mut user = request("GET /users/j%20doe HTTP/1.1\r\n\r\n");
+        let response = router.handle(&mut user);
+        assert_eq!(body(&response), "j doe");
+        assert_eq!(user.route.as_deref(), Some("/users/:id"));
+
+        let response = router.handle(&mut request("HEAD /users/7 HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.status, StatusCode::Ok);
+
+        let response = router.handle(&mut request("GET /files/a/b%2Fc HTTP/1.1\r\n\r\n"));
+        assert_eq!(body(&response), "a/b%2Fc");
+
+        let response = router.handle(&mut request("GET /users/ HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.status, StatusCode::NotFound);
+
+        let response = router.handle(&mut request("GET /users/1/posts HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.status, StatusCode::NotFound);
+    }
+
+    #[test]
+    fn wrong_method_is_405_with_allow() {
+        let router = router();
+
+        let response = router.handle(&mut request("POST /users/7 HTTP/1.1\r\n\r\n"));
+        assert_eq!(response.status, StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers.get("Allow"), Some("GET, HEAD, DELETE"));
+    }
+
+    #[test]
+    fn any_matches_every_method() {
+        let mut router = router();
+        router.any("/proxy/*rest", |req: &mut Request| {
+            Response::new(StatusCode::Ok).with_body("text/plain", req.method.as_str())
+        });
+
+        let response = router.handle(&mut request("PATCH /proxy/a HTTP/1.1\r\n\r\n"));
+        assert_eq!(body(&response), "PATCH");
+        let response = router.handle(&mut request("DELETE /proxy/ HTTP/1.1\r\n\r\n"));
+        assert_eq!(body(&response), "DELETE");
+    }
+
+    #[test]
+    #[should_panic]
+    fn wildcard_must_be_last() {
+        Router::new().get("/*rest/more", |_| Response::new(StatusCode::Ok));
+    }
+}
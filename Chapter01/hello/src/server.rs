@@ -0,0 +1,860 @@
+use std::{
+    collections::HashMap,
+    io::{self, prelude::*, BufReader, BufWriter},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    access_log::{AccessLog, Entry},
+    compression::Compression,
+    error::Error,
+    metrics::Metrics,
+    request::{BodyReader, Limits, Method, Request, Version},
+    response::{Response, StatusCode},
+    router::Handler,
+    shutdown::Shutdown,
+    template::ErrorPages,
+    websocket::{Session, Upgrade, GOING_AWAY},
+    ThreadPool,
+};
+
+/// A blocking HTTP server that hands each connection to a [`ThreadPool`].
+pub struct Server {
+    listener: TcpListener,
+    shutdown: Shutdown,
+    workers: usize,
+    drain_period: Duration,
+    shutdown_timeout: Duration,
+    connection: ConnectionSettings,
+    max_connections_per_ip: usize,
+    max_websockets: Option<usize>,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Option<Arc<Metrics>>,
+    compression: Option<Compression>,
+    error_pages: Option<ErrorPages>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+/// Per-connection limits, copied into every worker job.
+#[derive(Debug, Clone, Copy)]
+struct ConnectionSettings {
+    keep_alive_timeout: Duration,
+    max_requests: usize,
+    header_timeout: Duration,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    limits: Limits,
+    websocket_idle_timeout: Duration,
+}
+
+#[cfg(feature = "async")]
+mod async_io;
+
+/// Everything a worker needs to serve a connection.
+struct Context {
+    handler: Box<dyn Handler>,
+    shutdown: Shutdown,
+    settings: ConnectionSettings,
+    connections: ConnectionCounts,
+    websockets: WebSocketCount,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Option<Arc<Metrics>>,
+    compression: Option<Compression>,
+    error_pages: Option<ErrorPages>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+/// How often an idle connection checks whether the server is shutting down.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a connection closed on an error keeps reading what the client
+/// still sends, and how much of it, so the answer isn't lost to a reset.
+const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_LINGER_BYTES: usize = 1024 * 1024;
+
+/// How often a blocking WebSocket connection stops reading to send what
+/// has been queued for it: the longest a pushed message waits.
+const WEBSOCKET_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+impl Server {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Server> {
+        Server::from_listener(TcpListener::bind(addr)?)
+    }
+
+    pub fn from_listener(listener: TcpListener) -> io::Result<Server> {
+        let shutdown = Shutdown::new(&listener)?;
+
+        Ok(Server {
+            listener,
+            shutdown,
+            workers: thread::available_parallelism().map_or(4, |n| n.get()),
+            drain_period: Duration::ZERO,
+            shutdown_timeout: Duration::from_secs(30),
+            connection: ConnectionSettings {
+                keep_alive_timeout: Duration::from_secs(5),
+                max_requests: 100,
+                header_timeout: Duration::from_secs(10),
+                read_timeout: Duration::from_secs(30),
+                write_timeout: Duration::from_secs(30),
+                limits: Limits::default(),
+                websocket_idle_timeout: Duration::from_secs(60),
+            },
+            max_connections_per_ip: 0,
+            max_websockets: None,
+            access_log: None,
+            metrics: None,
+            compression: None,
+            error_pages: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// A handle that stops [`serve`](Server::serve) from another thread,
+    /// for example a signal handler.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Number of worker threads. Defaults to the available parallelism.
+    pub fn workers(mut self, workers: usize) -> Server {
+        self.workers = workers;
+        self
+    }
+
+    /// How long to keep accepting connections once shutdown is triggered,
+    /// so a load balancer polling a readiness check, such as
+    /// [`Health::shutdown`](crate::health::Health::shutdown), sees the
+    /// server is going before its connections are refused. Every response
+    /// in this time closes its connection. Defaults to zero.
+    pub fn drain_period(mut self, period: Duration) -> Server {
+        self.drain_period = period;
+        self
+    }
+
+    /// How long [`serve`](Server::serve) waits for in-flight requests once
+    /// the listener is closed.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Server {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// How long a persistent connection may sit idle between requests.
+    /// A zero timeout disables keep-alive.
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Server {
+        self.connection.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// How many requests one connection may send before it is closed.
+    pub fn max_requests_per_connection(mut self, max: usize) -> Server {
+        self.connection.max_requests = max.max(1);
+        self
+    }
+
+    /// How long a client has to send the request line and headers once it
+    /// starts a request, however steadily it trickles them in, and how long
+    /// a new connection may take to start its first one, TLS handshake
+    /// included. Defaults to 10 seconds.
+    pub fn header_timeout(mut self, timeout: Duration) -> Server {
+        self.connection.header_timeout = timeout;
+        self
+    }
+
+    /// How long one read of a request may wait for data. Defaults to 30
+    /// seconds.
+    pub fn read_timeout(mut self, timeout: Duration) -> Server {
+        self.connection.read_timeout = timeout;
+        self
+    }
+
+    /// How long one write of a response may wait for the client to make
+    /// room. Defaults to 30 seconds.
+    pub fn write_timeout(mut self, timeout: Duration) -> Server {
+        self.connection.write_timeout = timeout;
+        self
+    }
+
+    /// Size limits on requests; bigger ones are answered with `431` or
+    /// `413`. See [`Limits`] for the defaults.
+    pub fn limits(mut self, limits: Limits) -> Server {
+        self.connection.limits = limits;
+        self
+    }
+
+    /// How many connections one client address may hold open at once;
+    /// more are closed as soon as they are accepted. Zero, the default,
+    /// means no limit.
+    pub fn max_connections_per_ip(mut self, max: usize) -> Server {
+        self.max_connections_per_ip = max;
+        self
+    }
+
+    /// How long a WebSocket client may stay silent before it is closed.
+    /// It is pinged halfway through, so a live client answers in time.
+    /// Defaults to 60 seconds.
+    pub fn websocket_idle_timeout(mut self, timeout: Duration) -> Server {
+        self.connection.websocket_idle_timeout = timeout;
+        self
+    }
+
+    /// How many WebSocket sessions may be open at once; upgrades past that
+    /// are answered with `503`. A session holds a worker thread for as long
+    /// as it is open under [`serve`](Server::serve), so there this defaults
+    /// to one less than [`workers`](Server::workers), leaving one for
+    /// plain requests. Sessions are tasks under `serve_async`, and there is
+    /// no limit by default.
+    pub fn max_websockets(mut self, max: usize) -> Server {
+        self.max_websockets = Some(max);
+        self
+    }
+
+    /// Record every request to `log`. Off by default.
+    ///
+    /// Pass an `Arc` to share one log between several servers.
+    pub fn access_log(mut self, log: impl Into<Arc<AccessLog>>) -> Server {
+        self.access_log = Some(log.into());
+        self
+    }
+
+    /// Count requests and connections in `metrics`. Off by default.
+    ///
+    /// Pass an `Arc` to share one set of metrics between several servers.
+    pub fn metrics(mut self, metrics: impl Into<Arc<Metrics>>) -> Server {
+        self.metrics = Some(metrics.into());
+        self
+    }
+
+    /// Compress responses for clients that accept it. Off by default.
+    pub fn compression(mut self, compression: Compression) -> Server {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Render a page for error responses that have no body, including the
+    /// `400` and `500` the server sends itself. Off by default.
+    pub fn error_pages(mut self, pages: ErrorPages) -> Server {
+        self.error_pages = Some(pages);
+        self
+    }
+
+    /// Speak HTTPS on this listener, typically with a config built by
+    /// [`Certificates`](crate::tls::Certificates).
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: Arc<rustls::ServerConfig>) -> Server {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Accept connections until shutdown is triggered and the
+    /// [drain period](Server::drain_period) is over.
+    ///
+    /// Returns `false` if in-flight requests were still running when the
+    /// shutdown timeout expired.
+    pub fn serve(self, handler: impl Handler) -> bool {
+        let pool = ThreadPool::new(self.workers);
+        let shutdown_timeout = self.shutdown_timeout;
+        let drain_period = self.drain_period;
+        let max_websockets = self.workers.saturating_sub(1);
+        let (listener, context) = self.into_parts(handler, max_websockets);
+        let mut draining_until = None;
+
+        loop {
+            let stream = listener.accept().map(|(stream, _)| stream);
+            if context.shutdown.is_triggered() {
+                // Nothing wakes `accept` at the end of the drain period,
+                // so poll until then.
+                let until = *draining_until.get_or_insert_with(|| {
+                    let _ = listener.set_nonblocking(true);
+                    Instant::now() + drain_period
+                });
+                if Instant::now() >= until {
+                    break;
+                }
+            }
+
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    // Usually a client that gave up before we accepted, or
+                    // running out of file descriptors; neither is fatal, but
+                    // back off so the latter doesn't become a busy loop.
+                    crate::warn!("Problem accepting connection: {e}");
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+            // Some platforms make sockets accepted while draining
+            // non-blocking too.
+            if draining_until.is_some() && stream.set_nonblocking(false).is_err() {
+                continue;
+            }
+            let Ok(peer) = stream.peer_addr() else {
+                continue;
+            };
+            let Some(slot) = context.connections.acquire(peer.ip()) else {
+                crate::debug!("{peer}: too many connections from this address");
+                continue;
+            };
+            let context = Arc::clone(&context);
+            if let Some(metrics) = &context.metrics {
+                metrics.connection_queued();
+            }
+
+            pool.execute(move || {
+                let _open = context.metrics.as_deref().map(|metrics| {
+                    metrics.connection_dequeued();
+                    metrics.connection_opened()
+                });
+                if let Err(e) = context.accept(stream) {
+                    log_error(Some(peer), &e);
+                }
+                drop(slot);
+            });
+        }
+
+        pool.shutdown(shutdown_timeout)
+    }
+
+    /// The listener and what workers share, with `max_websockets` unless
+    /// another limit was set.
+    fn into_parts(
+        self,
+        handler: impl Handler,
+        max_websockets: usize,
+    ) -> (TcpListener, Arc<Context>) {
+        let context = Context {
+            handler: Box::new(handler),
+            shutdown: self.shutdown,
+            settings: self.connection,
+            connections: ConnectionCounts::new(self.max_connections_per_ip),
+            websockets: WebSocketCount {
+                max: self.max_websockets.unwrap_or(max_websockets),
+                open: AtomicUsize::new(0),
+            },
+            access_log: self.access_log,
+            metrics: self.metrics,
+            compression: self.compression,
+            error_pages: self.error_pages,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+        };
+        (self.listener, Arc::new(context))
+    }
+}
+
+impl Context {
+    /// Serve `stream`, after a TLS handshake if this is an HTTPS listener.
+    fn accept(&self, stream: TcpStream) -> Result<(), Error> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            let connection = rustls::ServerConnection::new(Arc::clone(tls))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            return handle_connection(rustls::StreamOwned::new(connection, stream), self);
+        }
+        handle_connection(stream, self)
+    }
+
+    /// Run the handler, turning a panic into an error, and fill in and
+    /// compress what it returns.
+    fn call(&self, request: &mut Request) -> Result<Response, Error> {
+        let mut response = panic::catch_unwind(AssertUnwindSafe(|| self.handler.handle(request)))
+            .map_err(Error::from_panic)?;
+        if let Some(pages) = &self.error_pages {
+            pages.apply(&request.path, &mut response);
+        }
+        if let Some(compression) = &self.compression {
+            compression.apply(request, &mut response);
+        }
+        Ok(response)
+    }
+
+    /// Decide whether the connection stays open after `response` and mark
+    /// the response accordingly.
+    fn finish(&self, request: &Request, response: &mut Response, served: usize) -> bool {
+        let keep_alive = wants_keep_alive(request)
+            && !response.headers.has_token("Connection", "close")
+            && (response.body.has_length() || request.method == Method::Head)
+            && !self.settings.keep_alive_timeout.is_zero()
+            && served < self.settings.max_requests
+            && !self.shutdown.is_triggered();
+        if !keep_alive {
+            response.headers.set("Connection", "close");
+        } else if request.version == Version::Http10 {
+            response.headers.set("Connection", "keep-alive");
+        }
+        keep_alive
+    }
+
+    fn record(
+        &self,
+        peer: SocketAddr,
+        request: &Request,
+        response: &Response,
+        bytes: u64,
+        started: Instant,
+    ) {
+        if let Some(log) = &self.access_log {
+            log.record(&Entry {
+                client: peer,
+                time: SystemTime::now(),
+                method: request.method,
+                path: &request.path,
+                query: request.query.as_deref(),
+                version: request.version,
+                status: response.status.code(),
+                bytes,
+                latency: started.elapsed(),
+            });
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.record(request, response.status, bytes, started.elapsed());
+        }
+    }
+
+    /// The response to send for `error` before closing the connection, if
+    /// the client can still be answered at all. `path` is empty if the
+    /// request couldn't be read.
+    fn error_response(&self, error: &Error, path: &str) -> Option<Response> {
+        let mut response = Response::new(error.status()?).header("Connection", "close");
+        if let Some(pages) = &self.error_pages {
+            pages.apply(path, &mut response);
+        }
+        Some(response)
+    }
+
+    /// Whether requests arrive over HTTPS.
+    fn https(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        false
+    }
+
+    /// How long a connection may wait for its `served`th request to start.
+    /// A new connection gets the header timeout, however short keep-alive
+    /// is; after that, it is the keep-alive timeout.
+    fn request_timeout(&self, served: usize) -> Duration {
+        let timeout = if served == 1 {
+            self.settings.header_timeout
+        } else {
+            self.settings.keep_alive_timeout
+        };
+        timeout.max(POLL_INTERVAL)
+    }
+}
+
+/// A connection the blocking server can speak HTTP over: a plain socket or
+/// a TLS session on top of one.
+trait Transport: Read + Write + Send + 'static {
+    fn socket(&self) -> &TcpStream;
+
+    /// Say goodbye before the socket is dropped.
+    fn close(&mut self) {}
+}
+
+impl Transport for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Transport for rustls::StreamOwned<rustls::ServerConnection, TcpStream> {
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.conn.complete_io(&mut self.sock);
+    }
+}
+
+fn handle_connection<T: Transport>(stream: T, context: &Context) -> Result<(), Error> {
+    let peer = stream.socket().peer_addr()?;
+    stream
+        .socket()
+        .set_write_timeout(Some(context.settings.write_timeout))?;
+    let mut buf_reader = BufReader::new(stream);
+    let limits = context.settings.limits;
+
+    for served in 1..=context.settings.max_requests {
+        // Requests already queued on an accepted connection are still
+        // answered during shutdown; only idle keep-alive waits are cut short.
+        let interruptible = served > 1;
+        if !wait_for_request(
+            &mut buf_reader,
+            context.request_timeout(served),
+            interruptible,
+            &context.shutdown,
+        )? {
+            return Ok(());
+        }
+        let started = Instant::now();
+
+        let mut head = HeadReader {
+            inner: &mut buf_reader,
+            deadline: started + context.settings.header_timeout,
+            read_timeout: context.settings.read_timeout,
+        };
+        let mut request = match Request::read_head(&mut head, &limits) {
+            Ok(request) => request,
+            Err(e) => {
+                let unread = Request::unread(peer);
+                return Err(fail(&mut buf_reader, context, e.into(), &unread, started));
+            }
+        };
+        request.peer = Some(peer);
+        request.https = context.https();
+        buf_reader
+            .get_ref()
+            .socket()
+            .set_read_timeout(Some(context.settings.read_timeout))?;
+
+        let (result, finished) = if context.handler.streams_body(&request) {
+            // The handler reads the body off the connection, which comes
+            // back once it is done.
+            let body = BodyReader::new(buf_reader, &request.headers, &limits);
+            request.body_reader = Some(body.clone());
+            let result = context.call(&mut request);
+            request.body_reader = None;
+            let finished;
+            (buf_reader, finished) = body.into_inner();
+            (result, finished)
+        } else {
+            if let Err(e) = request.read_body(&mut buf_reader, &limits) {
+                return Err(fail(&mut buf_reader, context, e.into(), &request, started));
+            }
+            (context.call(&mut request), true)
+        };
+
+        let mut response = match result {
+            Ok(response) => response,
+            Err(e) => return Err(fail(&mut buf_reader, context, e, &request, started)),
+        };
+        // Head and body go out in one segment rather than tripping over
+        // Nagle's algorithm and delayed ACKs; `write_to` flushes.
+        let mut writer = BufWriter::new(buf_reader.get_mut());
+        if !finished {
+            // The next request starts somewhere in what is left.
+            response.headers.set("Connection", "close");
+        }
+        if let Some(upgrade) = response.upgrade.take() {
+            if let Some(slot) = context.websockets.acquire() {
+                response.write_to(&mut writer)?;
+                drop(writer);
+                context.record(peer, &request, &response, 0, started);
+                return serve_websocket(buf_reader, upgrade, slot, context);
+            }
+            crate::debug!("{peer}: too many WebSocket sessions open");
+            response = Response::new(StatusCode::ServiceUnavailable).header("Connection", "close");
+        }
+        let keep_alive = context.finish(&request, &mut response, served);
+
+        let bytes = if request.method == Method::Head {
+            response.write_head(&mut writer)?;
+            0
+        } else {
+            response.write_to(&mut writer)?
+        };
+        context.record(peer, &request, &response, bytes, started);
+
+        if !keep_alive {
+            break;
+        }
+    }
+
+    buf_reader.get_mut().close();
+    Ok(())
+}
+
+/// Run a WebSocket session on a connection whose handshake is done, until
+/// either side closes it, the client goes quiet or the server shuts down.
+fn serve_websocket<T: Transport>(
+    mut reader: BufReader<T>,
+    upgrade: Upgrade,
+    slot: WebSocketSlot<'_>,
+    context: &Context,
+) -> Result<(), Error> {
+    // Reads time out regularly to send what senders have queued, so nothing
+    // needs to wake this thread.
+    reader
+        .get_ref()
+        .socket()
+        .set_read_timeout(Some(WEBSOCKET_POLL_INTERVAL))?;
+    let mut session = Session::open(
+        upgrade,
+        context.settings.limits.max_body_size,
+        context.settings.websocket_idle_timeout,
+        Arc::new(|| {}),
+    );
+    let mut chunk = [0; 8 * 1024];
+
+    while !session.is_done() {
+        if context.shutdown.is_triggered() && !session.is_closing() {
+            session.close(GOING_AWAY, "server shutting down");
+        }
+        session.check_idle();
+        let output = session.take_output();
+        if !output.is_empty() {
+            reader.get_mut().write_all(&output)?;
+            reader.get_mut().flush()?;
+        }
+
+        match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(read) => session.receive(&chunk[..read]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    // Anything queued in the last read, such as the reply to a close frame.
+    let output = session.take_output();
+    session.finish();
+    // Free the slot before the client hears the connection close.
+    drop(slot);
+    let stream = reader.get_mut();
+    let _ = stream.write_all(&output).and_then(|()| stream.flush());
+    stream.close();
+    Ok(())
+}
+
+/// Reads the head of a request, failing with `TimedOut` once `deadline`
+/// passes even if the client keeps sending a byte at a time.
+struct HeadReader<'a, T: Transport> {
+    inner: &'a mut BufReader<T>,
+    deadline: Instant,
+    read_timeout: Duration,
+}
+
+impl<T: Transport> Read for HeadReader<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.fill_buf()?.read(buf)?;
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl<T: Transport> BufRead for HeadReader<'_, T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.inner.buffer().is_empty() {
+            let remaining = self.deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.inner
+                .get_ref()
+                .socket()
+                .set_read_timeout(Some(remaining.min(self.read_timeout)))?;
+        }
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.inner.consume(amount);
+    }
+}
+
+/// Open connections by client address, for
+/// [`max_connections_per_ip`](Server::max_connections_per_ip).
+#[derive(Clone)]
+struct ConnectionCounts {
+    max: usize,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// One open connection, counted until it is dropped.
+struct ConnectionSlot {
+    counts: ConnectionCounts,
+    ip: IpAddr,
+}
+
+impl ConnectionCounts {
+    fn new(max: usize) -> ConnectionCounts {
+        ConnectionCounts {
+            max,
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Count a new connection from `ip`, or `None` if it already has as
+    /// many as allowed.
+    fn acquire(&self, ip: IpAddr) -> Option<ConnectionSlot> {
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        let count = counts.entry(ip).or_insert(0);
+        if self.max > 0 && *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot {
+            counts: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.counts.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Open WebSocket sessions, for [`max_websockets`](Server::max_websockets).
+struct WebSocketCount {
+    max: usize,
+    open: AtomicUsize,
+}
+
+/// One open WebSocket session, counted until it is dropped.
+struct WebSocketSlot<'a>(&'a AtomicUsize);
+
+impl WebSocketCount {
+    /// Count a new session, or `None` if as many as allowed are open.
+    fn acquire(&self) -> Option<WebSocketSlot<'_>> {
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < self.max).then_some(open + 1)
+            })
+            .ok()?;
+        Some(WebSocketSlot(&self.open))
+    }
+}
+
+impl Drop for WebSocketSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Answer `error` with its status, if it has one, before the connection
+/// is closed, and log and count the answer like any other.
+fn fail<T: Transport>(
+    reader: &mut BufReader<T>,
+    context: &Context,
+    error: Error,
+    request: &Request,
+    started: Instant,
+) -> Error {
+    if let Some(response) = context.error_response(&error, &request.path) {
+        // The client may already be gone; the original error is what matters.
+        let bytes = response
+            .write_to(&mut BufWriter::new(reader.get_mut()))
+            .unwrap_or(0);
+        if let Some(peer) = request.peer {
+            context.record(peer, request, &response, bytes, started);
+        }
+    }
+    linger(reader.get_mut());
+    error
+}
+
+/// Stop sending, then read and throw away what the client still sends
+/// until it closes its end or [`LINGER_TIMEOUT`] passes. Closing with
+/// unread bytes queued makes the kernel reset the connection, and the
+/// client may lose the response it has not read yet.
+fn linger<T: Transport>(stream: &mut T) {
+    stream.close();
+    let mut socket = stream.socket();
+    if socket.shutdown(std::net::Shutdown::Write).is_err() {
+        return;
+    }
+    let deadline = Instant::now() + LINGER_TIMEOUT;
+    let mut chunk = [0; 8 * 1024];
+    let mut left = MAX_LINGER_BYTES;
+    while left > 0 {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() || socket.set_read_timeout(Some(remaining)).is_err() {
+            return;
+        }
+        match socket.read(&mut chunk) {
+            Ok(0) | Err(_) => return,
+            Ok(read) => left = left.saturating_sub(read),
+        }
+    }
+}
+
+fn log_error(peer: Option<SocketAddr>, error: &Error) {
+    match peer {
+        Some(peer) if error.is_disconnect() => crate::debug!("{peer}: {error}"),
+        Some(peer) => crate::warn!("{peer}: {error}"),
+        None => crate::debug!("{error}"),
+    }
+}
+
+/// HTTP/1.1 connections persist unless either side says `close`; HTTP/1.0
+/// ones only when the client asks for `keep-alive`.
+fn wants_keep_alive(request: &Request) -> bool {
+    match request.version {
+        Version::Http11 => !request.headers.has_token("Connection", "close"),
+        Version::Http10 => request.headers.has_token("Connection", "keep-alive"),
+    }
+}
+
+/// Block until the next request starts arriving. Returns `false` if the
+/// client closed the connection or stayed idle for `timeout`, or if
+/// `interruptible` and the server began shutting down.
+fn wait_for_request<T: Transport>(
+    reader: &mut BufReader<T>,
+    timeout: Duration,
+    interruptible: bool,
+    shutdown: &Shutdown,
+) -> io::Result<bool> {
+    if !reader.buffer().is_empty() {
+        return Ok(true);
+    }
+
+    let deadline = Instant::now() + timeout;
+    reader
+        .get_ref()
+        .socket()
+        .set_read_timeout(Some(POLL_INTERVAL))?;
+
+    loop {
+        match reader.fill_buf() {
+            Ok(buf) => return Ok(!buf.is_empty()),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if (interruptible && shutdown.is_triggered()) || Instant::now() >= deadline {
+                    return Ok(false);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
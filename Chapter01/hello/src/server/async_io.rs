@@ -0,0 +1,745 @@
+use std::{
+    future::Future,
+    io::{self, BufRead, Read, SeekFrom},
+    mem,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime,
+    sync::{watch, Notify},
+    task::{self, JoinSet},
+    time::{self, Sleep},
+};
+
+use super::{
+    log_error, ConnectionSettings, Context, Server, WebSocketSlot, LINGER_TIMEOUT,
+    MAX_LINGER_BYTES, POLL_INTERVAL,
+};
+use crate::{
+    error::Error,
+    metrics::Metrics,
+    request::{body_length, chunk_size, read_line, BodyReader, Method, ParseError, Request},
+    response::{Body, Response, StatusCode},
+    router::Handler,
+    websocket::{Session, Upgrade, GOING_AWAY},
+};
+
+impl Server {
+    /// Like [`serve`](Server::serve), but on a tokio runtime with `workers`
+    /// threads instead of a [`ThreadPool`](crate::ThreadPool).
+    ///
+    /// Each connection is a task rather than a thread, so idle keep-alive
+    /// connections cost almost nothing. Handlers are the same synchronous
+    /// closures and run on tokio's blocking pool.
+    pub fn serve_async(self, handler: impl Handler) -> bool {
+        let runtime = match runtime::Builder::new_multi_thread()
+            .worker_threads(self.workers)
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                crate::error!("Problem starting async runtime: {e}");
+                return false;
+            }
+        };
+
+        let shutdown_timeout = self.shutdown_timeout;
+        let drain_period = self.drain_period;
+        // Sessions are tasks here, not threads the pool runs out of.
+        let (listener, context) = self.into_parts(handler, usize::MAX);
+        let clean = runtime.block_on(accept_loop(
+            listener,
+            context,
+            drain_period,
+            shutdown_timeout,
+        ));
+
+        // Don't wait for handlers still stuck after the timeout.
+        runtime.shutdown_background();
+        clean
+    }
+}
+
+async fn accept_loop(
+    listener: std::net::TcpListener,
+    context: Arc<Context>,
+    drain_period: Duration,
+    shutdown_timeout: Duration,
+) -> bool {
+    let listener = match listener
+        .set_nonblocking(true)
+        .and_then(|()| TcpListener::from_std(listener))
+    {
+        Ok(listener) => listener,
+        Err(e) => {
+            crate::error!("Problem registering listener: {e}");
+            return false;
+        }
+    };
+
+    let (stop, stopped) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let mut draining_until = None;
+
+    loop {
+        if context.shutdown.is_triggered() {
+            // Idle connections close now; new ones are still served until
+            // the drain period is over.
+            let until = *draining_until.get_or_insert_with(|| {
+                let _ = stop.send(true);
+                Instant::now() + drain_period
+            });
+            if Instant::now() >= until {
+                break;
+            }
+        }
+
+        tokio::select! {
+            accepted = listener.accept() => {
+                match accepted {
+                    Ok((stream, peer)) => {
+                        let Some(slot) = context.connections.acquire(peer.ip()) else {
+                            crate::debug!("{peer}: too many connections from this address");
+                            continue;
+                        };
+                        let context = Arc::clone(&context);
+                        let stopped = stopped.clone();
+                        connections.spawn(async move {
+                            let _open = context.metrics.as_deref().map(Metrics::connection_opened);
+                            if let Err(e) = accept(stream, peer, &context, stopped).await {
+                                log_error(Some(peer), &e);
+                            }
+                            drop(slot);
+                        });
+                    }
+                    Err(e) => {
+                        crate::warn!("Problem accepting connection: {e}");
+                        time::sleep(POLL_INTERVAL).await;
+                    }
+                }
+            }
+            _ = time::sleep(POLL_INTERVAL) => {}
+        }
+
+        while connections.try_join_next().is_some() {}
+    }
+
+    let drain = async { while connections.join_next().await.is_some() {} };
+    time::timeout(shutdown_timeout, drain).await.is_ok()
+}
+
+/// Serve `stream`, after a TLS handshake if this is an HTTPS listener.
+async fn accept(
+    stream: TcpStream,
+    peer: SocketAddr,
+    context: &Arc<Context>,
+    stopped: watch::Receiver<bool>,
+) -> Result<(), Error> {
+    #[cfg(feature = "tls")]
+    if let Some(tls) = &context.tls {
+        let handshake = tokio_rustls::TlsAcceptor::from(Arc::clone(tls)).accept(stream);
+        let stream = time::timeout(context.request_timeout(1), handshake)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        return serve_connection(stream, peer, context, stopped).await;
+    }
+    serve_connection(stream, peer, context, stopped).await
+}
+
+async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    mut stream: S,
+    peer: SocketAddr,
+    context: &Arc<Context>,
+    mut stopped: watch::Receiver<bool>,
+) -> Result<(), Error> {
+    let settings = context.settings;
+    let mut buf = Vec::with_capacity(4096);
+
+    for served in 1..=context.settings.max_requests {
+        if buf.is_empty() {
+            // Same rule as the blocking server: only idle keep-alive waits
+            // are cut short by shutdown.
+            let interruptible = served > 1;
+            tokio::select! {
+                read = stream.read_buf(&mut buf) => {
+                    if read? == 0 {
+                        return Ok(());
+                    }
+                }
+                _ = time::sleep(context.request_timeout(served)) => return Ok(()),
+                _ = stopped.changed(), if interruptible => return Ok(()),
+            }
+        }
+        let started = Instant::now();
+        let header_deadline = started + settings.header_timeout;
+
+        // The head has to arrive by the deadline. Only new bytes are
+        // scanned for its end, and the blocking parser runs once it is all
+        // here.
+        let head = read_head(&mut stream, &mut buf, settings, header_deadline).await;
+        let mut request = match head {
+            Ok(request) => request,
+            Err(e) => {
+                let unread = Request::unread(peer);
+                return Err(fail(&mut stream, context, e.into(), &unread, started).await);
+            }
+        };
+        request.peer = Some(peer);
+        request.https = context.https();
+
+        let (request, result, finished) = if context.handler.streams_body(&request) {
+            // The handler reads the body off the connection from its
+            // blocking thread, and the connection comes back once it is done.
+            let lent = Lent {
+                stream,
+                buf: mem::take(&mut buf),
+                pos: 0,
+                timeout: settings.read_timeout,
+                runtime: runtime::Handle::current(),
+            };
+            let body = BodyReader::new(lent, &request.headers, &settings.limits);
+            request.body_reader = Some(body.clone());
+            let (mut request, result) = call(context, request).await?;
+            request.body_reader = None;
+            let (lent, finished) = body.into_inner::<Lent<S>>();
+            stream = lent.stream;
+            buf = lent.buf;
+            buf.drain(..lent.pos);
+            (request, result, finished)
+        } else {
+            let body = read_body(&mut stream, &mut buf, &request, settings).await;
+            request.body = match body {
+                Ok(body) => body,
+                Err(e) => return Err(fail(&mut stream, context, e.into(), &request, started).await),
+            };
+            let (request, result) = call(context, request).await?;
+            (request, result, true)
+        };
+
+        let mut response = match result {
+            Ok(response) => response,
+            Err(e) => return Err(fail(&mut stream, context, e, &request, started).await),
+        };
+        if !finished {
+            // The next request starts somewhere in what is left.
+            response.headers.set("Connection", "close");
+        }
+        if let Some(upgrade) = response.upgrade.take() {
+            if let Some(slot) = context.websockets.acquire() {
+                write_response(
+                    &mut WriteTimeout::new(&mut stream, settings.write_timeout),
+                    &response,
+                    false,
+                )
+                .await?;
+                context.record(peer, &request, &response, 0, started);
+                return serve_websocket(stream, buf, upgrade, slot, context, stopped).await;
+            }
+            crate::debug!("{peer}: too many WebSocket sessions open");
+            response = Response::new(StatusCode::ServiceUnavailable).header("Connection", "close");
+        }
+        let keep_alive = context.finish(&request, &mut response, served);
+
+        let bytes = write_response(
+            &mut WriteTimeout::new(&mut stream, settings.write_timeout),
+            &response,
+            request.method == Method::Head,
+        )
+        .await?;
+        context.record(peer, &request, &response, bytes, started);
+
+        if !keep_alive {
+            break;
+        }
+    }
+
+    // Sends TLS close_notify; for plain TCP, the FIN goes out a little early.
+    let _ = stream.shutdown().await;
+    Ok(())
+}
+
+/// Run the handler on tokio's blocking pool, since it may block.
+async fn call(
+    context: &Arc<Context>,
+    mut request: Request,
+) -> Result<(Request, Result<Response, Error>), Error> {
+    let context = Arc::clone(context);
+    task::spawn_blocking(move || {
+        let result = context.call(&mut request);
+        (request, result)
+    })
+    .await
+    .map_err(|e| Error::Handler(e.to_string()))
+}
+
+/// Run a WebSocket session on a connection whose handshake is done, until
+/// either side closes it, the client goes quiet or the server shuts down.
+/// `buf` holds anything the client sent after the handshake.
+async fn serve_websocket<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    mut buf: Vec<u8>,
+    upgrade: Upgrade,
+    slot: WebSocketSlot<'_>,
+    context: &Context,
+    mut stopped: watch::Receiver<bool>,
+) -> Result<(), Error> {
+    let queued = Arc::new(Notify::new());
+    let wake = {
+        let queued = Arc::clone(&queued);
+        Arc::new(move || queued.notify_one())
+    };
+    let max_message_size = context.settings.limits.max_body_size;
+    let idle_timeout = context.settings.websocket_idle_timeout;
+    // The handler is synchronous code that may block.
+    let mut session =
+        task::block_in_place(|| Session::open(upgrade, max_message_size, idle_timeout, wake));
+    task::block_in_place(|| session.receive(&buf));
+    buf.clear();
+
+    let result = loop {
+        let output = session.take_output();
+        if !output.is_empty() {
+            let mut writer = WriteTimeout::new(&mut stream, context.settings.write_timeout);
+            if let Err(e) = writer.write_all(&output).await {
+                break Err(e.into());
+            }
+        }
+        if session.is_done() {
+            break Ok(());
+        }
+
+        tokio::select! {
+            read = stream.read_buf(&mut buf) => match read {
+                Ok(0) => break Ok(()),
+                Ok(_) => {
+                    task::block_in_place(|| session.receive(&buf));
+                    buf.clear();
+                }
+                Err(e) => break Err(e.into()),
+            },
+            _ = queued.notified() => {}
+            _ = stopped.changed(), if !session.is_closing() => {
+                session.close(GOING_AWAY, "server shutting down");
+            }
+            _ = time::sleep_until(session.idle_deadline().into()), if !session.is_closing() => {
+                session.check_idle();
+            }
+            // Check on the client's answer to our close frame.
+            _ = time::sleep(POLL_INTERVAL), if session.is_closing() => {}
+        }
+    };
+
+    task::block_in_place(|| session.finish());
+    // Free the slot before the client hears the connection close.
+    drop(slot);
+    let _ = stream.shutdown().await;
+    result
+}
+
+/// Read the head of a request into `buf` and parse it, leaving anything
+/// after it in `buf`.
+async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    settings: ConnectionSettings,
+    deadline: Instant,
+) -> Result<Request, ParseError> {
+    let mut scanned = 0;
+    loop {
+        // Stray CRLFs between requests are tolerated, as by the parser, but
+        // mustn't look like the blank line ending the head.
+        let blank = buf
+            .iter()
+            .take_while(|&&b| b == b'\r' || b == b'\n')
+            .count();
+        let blank = buf[..blank]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        if blank > 0 {
+            buf.drain(..blank);
+            scanned = 0;
+        }
+        if head_end(buf, scanned).is_some() || buf.len() > settings.limits.max_header_size {
+            break;
+        }
+        scanned = buf.len();
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        read_more(stream, buf, remaining.min(settings.read_timeout)).await?;
+    }
+
+    let mut unread = &buf[..];
+    let request = Request::read_head(&mut unread, &settings.limits)?;
+    let consumed = buf.len() - unread.len();
+    buf.drain(..consumed);
+    Ok(request)
+}
+
+/// Where the head in `buf` ends, just past its blank line, if it has
+/// arrived. Bytes before `from` are known not to end it.
+fn head_end(buf: &[u8], from: usize) -> Option<usize> {
+    let start = from.saturating_sub(2);
+    (start..buf.len())
+        .filter(|&i| buf[i] == b'\n')
+        .find_map(|i| match &buf[i + 1..] {
+            [b'\n', ..] => Some(i + 2),
+            [b'\r', b'\n', ..] => Some(i + 3),
+            _ => None,
+        })
+}
+
+/// Read the body of `request` off `stream`, after whatever of it is
+/// already in `buf`. Each byte is copied into the body once.
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    request: &Request,
+    settings: ConnectionSettings,
+) -> Result<Vec<u8>, ParseError> {
+    if request.headers.contains("Transfer-Encoding") {
+        return read_chunked(stream, buf, settings).await;
+    }
+
+    // `read_head` already held the length to the limit.
+    let length = body_length(&request.headers)?.unwrap_or(0);
+    let length = usize::try_from(length).map_err(|_| ParseError::BodyTooLarge)?;
+    buf.reserve(length.saturating_sub(buf.len()));
+    while buf.len() < length {
+        read_more(stream, buf, settings.read_timeout).await?;
+    }
+    Ok(buf.drain(..length).collect())
+}
+
+/// Decode a chunked body as it arrives.
+async fn read_chunked<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    settings: ConnectionSettings,
+) -> Result<Vec<u8>, ParseError> {
+    let limits = settings.limits;
+    let mut chunks = Chunks {
+        stream,
+        buf,
+        pos: 0,
+        timeout: settings.read_timeout,
+    };
+    let mut body = Vec::new();
+    // Chunk size lines and trailers count against the header budget.
+    let mut budget = limits.max_header_size;
+
+    let result = loop {
+        let size = match chunks.line(&mut budget).await {
+            Ok(line) => chunk_size(&line),
+            Err(e) => Err(e),
+        };
+        let size = match size {
+            Ok(0) => break chunks.trailers(&mut budget).await,
+            Ok(size) if size > limits.max_body_size - body.len() as u64 => {
+                break Err(ParseError::BodyTooLarge)
+            }
+            Ok(size) => size as usize,
+            Err(e) => break Err(e),
+        };
+        match chunks.take(size, &mut body).await {
+            Ok(()) => {}
+            Err(e) => break Err(e),
+        }
+        match chunks.line(&mut budget).await {
+            Ok(line) if line.is_empty() => {}
+            Ok(_) => break Err(ParseError::Chunk),
+            Err(e) => break Err(e),
+        }
+    };
+
+    let pos = chunks.pos;
+    buf.drain(..pos);
+    result.map(|()| body)
+}
+
+/// A chunked body being decoded out of `buf`, `pos` bytes in. Consumed
+/// bytes are only dropped from `buf` now and then, so many small chunks
+/// don't shift the rest of the buffer each time.
+struct Chunks<'a, S> {
+    stream: &'a mut S,
+    buf: &'a mut Vec<u8>,
+    pos: usize,
+    timeout: Duration,
+}
+
+impl<S: AsyncRead + Unpin> Chunks<'_, S> {
+    /// Read until `buf` holds `wanted` bytes past `pos`.
+    async fn fill(&mut self, wanted: usize) -> Result<(), ParseError> {
+        while self.buf.len() - self.pos < wanted {
+            if self.pos >= 64 * 1024 && self.pos * 2 >= self.buf.len() {
+                self.buf.drain(..self.pos);
+                self.pos = 0;
+            }
+            read_more(self.stream, self.buf, self.timeout).await?;
+        }
+        Ok(())
+    }
+
+    /// The next line, without its line ending.
+    async fn line(&mut self, budget: &mut usize) -> Result<String, ParseError> {
+        let mut scanned = 0;
+        loop {
+            let rest = &self.buf[self.pos..];
+            if rest[scanned..].contains(&b'\n') || rest.len() > *budget {
+                let mut unread = rest;
+                let line = read_line(&mut unread, budget)?.ok_or(ParseError::Closed)?;
+                self.pos += rest.len() - unread.len();
+                return Ok(line);
+            }
+            scanned = rest.len();
+            let wanted = rest.len() + 1;
+            self.fill(wanted).await?;
+        }
+    }
+
+    /// Move the next `size` bytes onto the end of `body`.
+    async fn take(&mut self, size: usize, body: &mut Vec<u8>) -> Result<(), ParseError> {
+        self.fill(size).await?;
+        body.extend_from_slice(&self.buf[self.pos..self.pos + size]);
+        self.pos += size;
+        Ok(())
+    }
+
+    /// Read and discard trailer fields, up to the blank line.
+    async fn trailers(&mut self, budget: &mut usize) -> Result<(), ParseError> {
+        while !self.line(budget).await?.is_empty() {}
+        Ok(())
+    }
+}
+
+/// A connection lent to a handler that streams the request body, read
+/// from its blocking thread. `buf` holds what had arrived before, `pos`
+/// bytes of which have been read.
+struct Lent<S> {
+    stream: S,
+    buf: Vec<u8>,
+    pos: usize,
+    timeout: Duration,
+    runtime: runtime::Handle,
+}
+
+impl<S: AsyncRead + Unpin> Read for Lent<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl<S: AsyncRead + Unpin> BufRead for Lent<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+            let read = time::timeout(self.timeout, self.stream.read_buf(&mut self.buf));
+            self.runtime
+                .block_on(read)
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.pos += amount;
+    }
+}
+
+/// Read more of a request into `buf`, giving up after `timeout`.
+async fn read_more<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+    timeout: Duration,
+) -> Result<(), ParseError> {
+    match time::timeout(timeout, stream.read_buf(buf)).await {
+        Err(_) => Err(ParseError::TimedOut),
+        Ok(Ok(0)) => Err(ParseError::Closed),
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.into()),
+    }
+}
+
+/// Fails a write that makes no progress for `timeout`, like a socket
+/// write timeout in the blocking server.
+struct WriteTimeout<'a, S> {
+    inner: &'a mut S,
+    timeout: Duration,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<'a, S: AsyncWrite + Unpin> WriteTimeout<'a, S> {
+    fn new(inner: &'a mut S, timeout: Duration) -> WriteTimeout<'a, S> {
+        WriteTimeout {
+            inner,
+            timeout,
+            sleep: None,
+        }
+    }
+
+    /// Start the clock when `poll` can't make progress, and stop it when
+    /// it can.
+    fn check<T>(
+        &mut self,
+        cx: &mut TaskContext<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.sleep = None;
+            return poll;
+        }
+        let timeout = self.timeout;
+        let sleep = self
+            .sleep
+            .get_or_insert_with(|| Box::pin(time::sleep(timeout)));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::ErrorKind::TimedOut.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WriteTimeout<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        self.check(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut *self.inner).poll_flush(cx);
+        self.check(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+async fn write_response<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    response: &Response,
+    head_only: bool,
+) -> io::Result<u64> {
+    let mut out = Vec::new();
+    response.write_head(&mut out)?;
+
+    if head_only || response.status.forbids_body() {
+        stream.write_all(&out).await?;
+        return Ok(0);
+    }
+
+    match response.body.as_bytes() {
+        // One write for head and body, so Nagle's algorithm doesn't hold
+        // back the second half.
+        Some(bytes) => {
+            out.extend_from_slice(bytes);
+            stream.write_all(&out).await?;
+            Ok(bytes.len() as u64)
+        }
+        None => {
+            stream.write_all(&out).await?;
+            write_body(stream, &response.body).await
+        }
+    }
+}
+
+/// Write `body`, returning how many bytes that took.
+async fn write_body<S: AsyncWrite + Unpin>(stream: &mut S, body: &Body) -> io::Result<u64> {
+    match body {
+        Body::File { file, offset, len } => {
+            let mut file = tokio::fs::File::from_std(file.try_clone()?);
+            file.seek(SeekFrom::Start(*offset)).await?;
+            let copied = tokio::io::copy(&mut file.take(*len), stream).await?;
+            if copied != *len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file shrank while it was being sent",
+                ));
+            }
+            Ok(copied)
+        }
+        Body::Parts(parts) => {
+            let mut sent = 0;
+            for part in parts {
+                sent += Box::pin(write_body(stream, part)).await?;
+            }
+            Ok(sent)
+        }
+        Body::Stream(body) => {
+            // The reader is blocking, such as a socket to an upstream
+            // server, so each read hands the worker thread over to others.
+            let mut chunk = vec![0; 16 * 1024];
+            let mut sent = 0;
+            loop {
+                let read = task::block_in_place(|| body.lock().read(&mut chunk))?;
+                if read == 0 {
+                    break;
+                }
+                stream.write_all(&chunk[..read]).await?;
+                sent += read as u64;
+            }
+            body.check(sent)?;
+            Ok(sent)
+        }
+        body => {
+            let bytes = body.as_bytes().unwrap_or_default();
+            stream.write_all(bytes).await?;
+            Ok(bytes.len() as u64)
+        }
+    }
+}
+
+async fn fail<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    context: &Context,
+    error: Error,
+    request: &Request,
+    started: Instant,
+) -> Error {
+    if let Some(response) = context.error_response(&error, &request.path) {
+        let mut stream = WriteTimeout::new(stream, context.settings.write_timeout);
+        let bytes = write_response(&mut stream, &response, false)
+            .await
+            .unwrap_or(0);
+        if let Some(peer) = request.peer {
+            context.record(peer, request, &response, bytes, started);
+        }
+    }
+    linger(stream).await;
+    error
+}
+
+/// Like the blocking server's `linger`: stop sending, then read and throw
+/// away what the client still sends for a while, so closing doesn't reset
+/// the connection under a response the client has not read yet.
+async fn linger<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) {
+    let drain = async {
+        stream.shutdown().await?;
+        let mut chunk = [0; 8 * 1024];
+        let mut left = MAX_LINGER_BYTES;
+        while left > 0 {
+            match stream.read(&mut chunk).await? {
+                0 => break,
+                read => left = left.saturating_sub(read),
+            }
+        }
+        io::Result::Ok(())
+    };
+    let _ = time::timeout(LINGER_TIMEOUT, drain).await;
+}
@@ -0,0 +1,74 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// A cloneable handle that tells the accept loop to stop.
+///
+/// `TcpListener::incoming` blocks in `accept`, so triggering also opens a
+/// throwaway connection to the listener to wake it up.
+#[derive(Clone)]
+pub struct Shutdown {
+    flag: Arc<AtomicBool>,
+    wake_addr: SocketAddr,
+}
+
+impl Shutdown {
+    pub fn new(listener: &TcpListener) -> io::Result<Shutdown> {
+        let mut wake_addr = listener.local_addr()?;
+        if wake_addr.ip().is_unspecified() {
+            match wake_addr {
+                SocketAddr::V4(_) => wake_addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+                SocketAddr::V6(_) => wake_addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+            }
+        }
+
+        Ok(Shutdown {
+            flag: Arc::new(AtomicBool::new(false)),
+            wake_addr,
+        })
+    }
+
+    pub fn trigger(&self) {
+        if !self.flag.swap(true, Ordering::SeqCst) {
+            let _ = TcpStream::connect_timeout(&self.wake_addr, Duration::from_secs(1));
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn trigger_wakes_the_accept_loop() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let shutdown = Shutdown::new(&listener).unwrap();
+
+        let handle = shutdown.clone();
+        let accept = thread::spawn(move || {
+            let mut accepted = 0;
+            for _ in listener.incoming() {
+                accepted += 1;
+                if handle.is_triggered() {
+                    break;
+                }
+            }
+            accepted
+        });
+
+        shutdown.trigger();
+        assert_eq!(accept.join().unwrap(), 1);
+        assert!(shutdown.is_triggered());
+    }
+}
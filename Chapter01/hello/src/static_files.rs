@@ -0,0 +1,452 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    autoindex,
+    cache::{self, CacheControl},
+    compression::{self, Encoding},
+    date::DateTime,
+    mime, range,
+    request::{percent_decode, Request},
+    response::{Body, Response, StatusCode},
+};
+
+/// Why a URL path could not be mapped to a file.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reject {
+    /// The path could not be decoded.
+    BadRequest,
+    /// The path tries to leave the document root.
+    Forbidden,
+    NotFound,
+}
+
+impl fmt::Display for Reject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reject::BadRequest => write!(f, "bad request path"),
+            Reject::Forbidden => write!(f, "path escapes the document root"),
+            Reject::NotFound => write!(f, "no such file"),
+        }
+    }
+}
+
+/// Serves files from beneath a document root.
+pub struct StaticFiles {
+    root: PathBuf,
+    precompressed: bool,
+    cache_control: CacheControl,
+    autoindex: bool,
+}
+
+/// Served in place of a directory that contains one.
+const INDEX_FILE: &str = "index.html";
+
+impl StaticFiles {
+    /// Fails if `root` does not exist or is not a directory.
+    pub fn new(root: impl AsRef<Path>) -> io::Result<StaticFiles> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+
+        Ok(StaticFiles {
+            root,
+            precompressed: false,
+            cache_control: CacheControl::new(),
+            autoindex: false,
+        })
+    }
+
+    /// Send `file.br` or `file.gz` in place of `file` when one exists and
+    /// the client accepts that coding. Off by default.
+    pub fn precompressed(mut self, enabled: bool) -> StaticFiles {
+        self.precompressed = enabled;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// `Cache-Control` to send, by request path prefix. None by default.
+    pub fn cache_control(mut self, rules: CacheControl) -> StaticFiles {
+        self.cache_control = rules;
+        self
+    }
+
+    /// List the contents of directories without an `index.html`. Off by
+    /// default, which answers them with [`Reject::NotFound`].
+    pub fn autoindex(mut self, enabled: bool) -> StaticFiles {
+        self.autoindex = enabled;
+        self
+    }
+
+    /// Map a still-encoded URL path to a file under the root.
+    ///
+    /// Rejects `..` segments, encoded separators and anything that resolves,
+    /// through symlinks or otherwise, to a location outside the root.
+    pub fn resolve(&self, url_path: &str) -> Result<PathBuf, Reject> {
+        let lower = url_path.to_ascii_lowercase();
+        if lower.contains("%2f") || lower.contains("%5c") {
+            return Err(Reject::Forbidden);
+        }
+
+        let decoded = percent_decode(url_path).ok_or(Reject::BadRequest)?;
+        let decoded = String::from_utf8(decoded).map_err(|_| Reject::BadRequest)?;
+        if decoded.contains(['\0', '\\']) {
+            return Err(Reject::Forbidden);
+        }
+
+        let mut path = self.root.clone();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return Err(Reject::Forbidden),
+                segment => path.push(segment),
+            }
+        }
+
+        let path = path.canonicalize().map_err(|_| Reject::NotFound)?;
+        if !path.starts_with(&self.root) {
+            return Err(Reject::Forbidden);
+        }
+
+        Ok(path)
+    }
+
+    /// Build a `200` response to `request` streaming the file at
+    /// `url_path`, or a precompressed copy of it. Conditional and `Range`
+    /// requests get a `304`, `206` or `416` instead.
+    ///
+    /// A directory is served by its `index.html`, or listed if
+    /// [`autoindex`](StaticFiles::autoindex) is on. Either way a request
+    /// for it without the trailing slash is redirected to one, so relative
+    /// links resolve.
+    pub fn serve(&self, request: &Request, url_path: &str) -> Result<Response, Reject> {
+        let mut path = self.resolve(url_path)?;
+        if path.is_dir() {
+            let index = path
+                .join(INDEX_FILE)
+                .canonicalize()
+                .ok()
+                .filter(|index| index.starts_with(&self.root) && index.is_file());
+            if index.is_none() && !self.autoindex {
+                return Err(Reject::NotFound);
+            }
+            if !request.path.ends_with('/') {
+                // Rebuilt from its segments: echoing `//host` back would
+                // send the client to another site.
+                let mut location = String::from("/");
+                for segment in request.path.split('/') {
+                    if !matches!(segment, "" | ".") {
+                        location.push_str(segment);
+                        location.push('/');
+                    }
+                }
+                if let Some(query) = &request.query {
+                    location.push('?');
+                    location.push_str(query);
+                }
+                return Ok(Response::new(StatusCode::MovedPermanently).header("Location", location));
+            }
+            let Some(index) = index else {
+                let entries = autoindex::read(&path, &self.root).map_err(|_| Reject::NotFound)?;
+                let decoded = percent_decode(&request.path).ok_or(Reject::BadRequest)?;
+                let url_path = String::from_utf8_lossy(&decoded);
+                return Ok(autoindex::listing(request, &url_path, entries));
+            };
+            path = index;
+        }
+        if !path.is_file() {
+            return Err(Reject::NotFound);
+        }
+
+        let precompressed = match self.precompressed {
+            true => self.serve_precompressed(request, &path),
+            false => None,
+        };
+        let response = match precompressed {
+            Some(response) => response,
+            None => Response::file(&path).map_err(|_| Reject::NotFound)?,
+        };
+        Ok(range::apply(request, self.revalidate(request, response)))
+    }
+
+    /// Add validators and `Cache-Control` to a file response, and swap it
+    /// for a `304 Not Modified` if the client already has this version.
+    fn revalidate(&self, request: &Request, mut response: Response) -> Response {
+        let metadata = match &response.body {
+            Body::File { file, .. } => file.metadata().ok(),
+            _ => None,
+        };
+        let modified = metadata.as_ref().and_then(|m| m.modified().ok());
+        let mut etag = metadata.as_ref().map(cache::etag);
+        if let (Some(etag), Some(encoding)) = (&mut etag, response.headers.get("Content-Encoding"))
+        {
+            // Each precompressed variant needs a tag of its own.
+            etag.insert_str(etag.len() - 1, &format!("-{encoding}"));
+        }
+
+        if let Some(etag) = &etag {
+            response.headers.set("ETag", etag.as_str());
+        }
+        if let Some(modified) = modified {
+            response
+                .headers
+                .set("Last-Modified", DateTime::from_system_time(modified).http());
+        }
+        if let Some(value) = self.cache_control.for_path(&request.path) {
+            response.headers.set("Cache-Control", value);
+        }
+
+        if !cache::not_modified(request, etag.as_deref(), modified) {
+            return response;
+        }
+        let mut not_modified = Response::new(StatusCode::NotModified);
+        for name in ["ETag", "Last-Modified", "Cache-Control", "Vary"] {
+            if let Some(value) = response.headers.get(name) {
+                not_modified.headers.set(name, value);
+            }
+        }
+        not_modified
+    }
+
+    /// Negotiate between `path` and its compressed siblings, if it has any.
+    fn serve_precompressed(&self, request: &Request, path: &Path) -> Option<Response> {
+        let siblings: Vec<(Encoding, PathBuf)> = Encoding::ALL
+            .into_iter()
+            .filter_map(|encoding| {
+                let mut sibling = path.as_os_str().to_owned();
+                sibling.push(format!(".{}", encoding.extension()));
+                let sibling = Path::new(&sibling).canonicalize().ok()?;
+                (sibling.starts_with(&self.root) && sibling.is_file())
+                    .then_some((encoding, sibling))
+            })
+            .collect();
+        if siblings.is_empty() {
+            return None;
+        }
+
+        let offered: Vec<Encoding> = siblings.iter().map(|(encoding, _)| *encoding).collect();
+        let mut response =
+            match compression::negotiate(request.headers.get("Accept-Encoding"), &offered) {
+                Some(encoding) => {
+                    let (_, sibling) = siblings.iter().find(|(e, _)| *e == encoding)?;
+                    Response::file(sibling)
+                        .ok()?
+                        .header("Content-Type", mime::content_type(path))
+                        .header("Content-Encoding", encoding.as_str())
+                }
+                None => Response::file(path).ok()?,
+            };
+        compression::vary_on_accept_encoding(&mut response.headers);
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    fn get(path: &str, headers: &[(&str, &str)]) -> Request {
+        let mut raw = format!("GET {path} HTTP/1.1\r\n");
+        for (name, value) in headers {
+            raw.push_str(&format!("{name}: {value}\r\n"));
+        }
+        raw.push_str("\r\n");
+        Request::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn document_root(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("hello-static-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("public/css")).unwrap();
+        fs::write(dir.join("public/index.html"), "<h1>hi</h1>").unwrap();
+        fs::write(dir.join("public/css/site.css"), "body {}").unwrap();
+        fs::write(
+            dir.join("public/logo.png"),
+            [0x89, b'P', b'N', b'G', 0, 0xff],
+        )
+        .unwrap();
+        fs::write(dir.join("secret.txt"), "top secret").unwrap();
+        dir
+    }
+
+    #[test]
+    fn serves_files_with_content_type() {
+        let dir = document_root("serve");
+        let files = StaticFiles::new(dir.join("public")).unwrap();
+
+        let request = get("/css/site.css", &[]);
+        let response = files.serve(&request, "/css/site.css").unwrap();
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/css; charset=utf-8")
+        );
+        assert_eq!(response.body.len(), 7);
+
+        let response = files.serve(&request, "/logo.png").unwrap();
+        let mut wire = Vec::new();
+        response.write_to(&mut wire).unwrap();
+        assert!(wire.ends_with(&[0x89, b'P', b'N', b'G', 0, 0xff]));
+
+        assert_eq!(
+            files.serve(&request, "/missing.txt").unwrap_err(),
+            Reject::NotFound
+        );
+        assert_eq!(files.serve(&request, "/css").unwrap_err(), Reject::NotFound);
+    }
+
+    #[test]
+    fn prefers_precompressed_siblings() {
+        let dir = document_root("precompressed");
+        fs::write(dir.join("public/css/site.css.gz"), "gzipped").unwrap();
+        fs::write(dir.join("public/css/site.css.br"), "brotli!!!").unwrap();
+        let files = StaticFiles::new(dir.join("public"))
+            .unwrap()
+            .precompressed(true);
+
+        let response = files
+            .serve(
+                &get("/css/site.css", &[("Accept-Encoding", "gzip")]),
+                "/css/site.css",
+            )
+            .unwrap();
+        assert_eq!(response.headers.get("Content-Encoding"), Some("gzip"));
+        assert_eq!(
+            response.headers.get("Content-Type"),
+            Some("text/css; charset=utf-8")
+        );
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body.len(), 7);
+
+        let response = files
+            .serve(
+                &get("/css/site.css", &[("Accept-Encoding", "gzip, br")]),
+                "/css/site.css",
+            )
+            .unwrap();
+        assert_eq!(response.headers.get("Content-Encoding"), Some("br"));
+
+        let response = files
+            .serve(&get("/css/site.css", &[]), "/css/site.css")
+            .unwrap();
+        assert!(!response.headers.contains("Content-Encoding"));
+        assert_eq!(response.headers.get("Vary"), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn answers_conditional_requests() {
+        let dir = document_root("conditional");
+        let mut rules = CacheControl::new();
+        rules.rule("/", "no-cache").rule("/css/", "max-age=3600");
+        let files = StaticFiles::new(dir.join("public"))
+            .unwrap()
+            .cache_control(rules);
+
+        let response = files
+            .serve(&get("/css/site.css", &[]), "/css/site.css")
+            .unwrap();
+        assert_eq!(response.status, StatusCode::Ok);
+        assert_eq!(response.headers.get("Cache-Control"), Some("max-age=3600"));
+        let etag = response.headers.get("ETag").unwrap();
+        let modified = response.headers.get("Last-Modified").unwrap();
+
+        let revalidated = files
+            .serve(
+                &get("/css/site.css", &[("If-None-Match", etag)]),
+                "/css/site.css",
+            )
+            .unwrap();
+        assert_eq!(revalidated.status, StatusCode::NotModified);
+        assert_eq!(revalidated.headers.get("ETag"), Some(etag));
+        assert!(revalidated.body.is_empty());
+
+        let revalidated = files
+            .serve(
+                &get("/css/site.css", &[("If-Modified-Since", modified)]),
+                "/css/site.css",
+            )
+            .unwrap();
+        assert_eq!(revalidated.status, StatusCode::NotModified);
+
+        let changed = files
+            .serve(
+                &get("/index.html", &[("If-None-Match", etag)]),
+                "/index.html",
+            )
+            .unwrap();
+        assert_eq!(changed.status, StatusCode::Ok);
+        assert_eq!(changed.headers.get("Cache-Control"), Some("no-cache"));
+    }
+
+    #[test]
+    fn serves_directories_by_index_or_listing() {
+        let dir = document_root("directories");
+        fs::write(dir.join("public/css/.hidden"), "").unwrap();
+        let files = StaticFiles::new(dir.join("public")).unwrap();
+
+        let response = files.serve(&get("/", &[]), "/").unwrap();
+        assert_eq!(response.body.len(), 11);
+        assert_eq!(
+            files.serve(&get("/css/", &[]), "/css/").unwrap_err(),
+            Reject::NotFound
+        );
+
+        let files = files.autoindex(true);
+        let response = files.serve(&get("/css?x=1", &[]), "/css").unwrap();
+        assert_eq!(response.status, StatusCode::MovedPermanently);
+        assert_eq!(response.headers.get("Location"), Some("/css/?x=1"));
+        let response = files.serve(&get("//css", &[]), "//css").unwrap();
+        assert_eq!(response.headers.get("Location"), Some("/css/"));
+
+        let response = files
+            .serve(&get("/css/", &[("Accept", "application/json")]), "/css/")
+            .unwrap();
+        let json = String::from_utf8(response.body.as_bytes().unwrap().to_vec()).unwrap();
+        assert!(json.starts_with("{\"path\":\"/css/\",\"entries\":[{\"name\":\"site.css\""));
+        assert!(!json.contains(".hidden"));
+
+        let response = files.serve(&get("/", &[]), "/").unwrap();
+        assert_eq!(response.body.len(), 11);
+    }
+
+    #[test]
+    fn refuses_to_leave_the_root() {
+        let dir = document_root("traversal");
+        let files = StaticFiles::new(dir.join("public")).unwrap();
+
+        for path in [
+            "/../secret.txt",
+            "/css/../../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/..%2fsecret.txt",
+            "/css%2F..%2F..%2Fsecret.txt",
+            "/..%5csecret.txt",
+        ] {
+            assert_eq!(
+                files.resolve(path).unwrap_err(),
+                Reject::Forbidden,
+                "{path}"
+            );
+        }
+        assert_eq!(files.resolve("/%zz").unwrap_err(), Reject::BadRequest);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks_out_of_the_root() {
+        let dir = document_root("symlink");
+        std::os::unix::fs::symlink(dir.join("secret.txt"), dir.join("public/link.txt")).unwrap();
+        let files = StaticFiles::new(dir.join("public")).unwrap();
+
+        assert_eq!(files.resolve("/link.txt").unwrap_err(), Reject::Forbidden);
+    }
+}
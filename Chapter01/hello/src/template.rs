@@ -0,0 +1,723 @@
+//! A small template language for HTML pages.
+//!
+//! ```text
+//! {{ user.name }}               escaped for HTML
+//! {{ snippet | raw }}           inserted as it is
+//! {% if user %}...{% else %}...{% endif %}
+//! {% if not items %}...{% endif %}
+//! {% for item in items %}{{ loop.index }}. {{ item }}{% endfor %}
+//! {% include "header.html" %}
+//! {# a comment #}
+//! ```
+//!
+//! Names that aren't set render as nothing and are false in conditions, so
+//! one template can serve pages with and without optional data.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, io,
+    path::Path,
+    sync::Arc,
+};
+
+use crate::response::{Response, StatusCode};
+
+/// How deep includes may nest before a template is assumed to include
+/// itself.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Data a template can refer to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    String(String),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    /// Empty strings, lists and maps, zero, `false` and null are false.
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Int(n) => *n != 0,
+            Value::String(s) => !s.is_empty(),
+            Value::List(items) => !items.is_empty(),
+            Value::Map(map) => !map.is_empty(),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Value {
+        Value::Int(n)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(n: u16) -> Value {
+        Value::Int(n.into())
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Value {
+        Value::Int(n as i64)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Value {
+        Value::List(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Value {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl From<Vars> for Value {
+    fn from(vars: Vars) -> Value {
+        Value::Map(vars.0)
+    }
+}
+
+/// The variables a template is rendered with.
+///
+/// ```
+/// use hello::template::Vars;
+///
+/// let vars = Vars::new()
+///     .var("title", "Inbox")
+///     .var("unread", 3_i64)
+///     .var("user", Vars::new().var("name", "Ferris"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Vars(BTreeMap<String, Value>);
+
+impl Vars {
+    pub fn new() -> Vars {
+        Vars::default()
+    }
+
+    pub fn var(mut self, name: impl Into<String>, value: impl Into<Value>) -> Vars {
+        self.0.insert(name.into(), value.into());
+        self
+    }
+}
+
+/// A template that could not be parsed or rendered, and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError {
+    pub template: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.template, self.line, self.message)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl From<TemplateError> for io::Error {
+    fn from(e: TemplateError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Print {
+        path: Vec<String>,
+        raw: bool,
+        line: usize,
+    },
+    If {
+        path: Vec<String>,
+        negate: bool,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    For {
+        name: String,
+        path: Vec<String>,
+        body: Vec<Node>,
+        line: usize,
+    },
+    Include {
+        name: String,
+        line: usize,
+    },
+}
+
+/// A set of named templates that can include one another.
+#[derive(Debug, Clone, Default)]
+pub struct Templates {
+    templates: HashMap<String, Vec<Node>>,
+}
+
+impl Templates {
+    pub fn new() -> Templates {
+        Templates::default()
+    }
+
+    /// Parse every file directly inside `dir`, named by its file name.
+    pub fn load(dir: impl AsRef<Path>) -> io::Result<Templates> {
+        let mut templates = Templates::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            templates.add(name, &fs::read_to_string(&path)?)?;
+        }
+        Ok(templates)
+    }
+
+    /// Parse `source` and make it available as `name`, replacing any
+    /// template already called that.
+    pub fn add(&mut self, name: &str, source: &str) -> Result<&mut Templates, TemplateError> {
+        let nodes = Parser::new(name, source).parse()?;
+        self.templates.insert(name.to_string(), nodes);
+        Ok(self)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.templates.contains_key(name)
+    }
+
+    pub fn render(&self, name: &str, vars: &Vars) -> Result<String, TemplateError> {
+        let mut out = String::new();
+        let mut scope = Scope {
+            vars,
+            locals: Vec::new(),
+        };
+        self.render_template(name, &mut scope, &mut out, 0, (name, 0))?;
+        Ok(out)
+    }
+
+    /// Render `name` as a `text/html` response with `status`.
+    pub fn page(
+        &self,
+        status: StatusCode,
+        name: &str,
+        vars: &Vars,
+    ) -> Result<Response, TemplateError> {
+        let html = self.render(name, vars)?;
+        Ok(Response::new(status).with_body("text/html; charset=utf-8", html))
+    }
+
+    fn render_template(
+        &self,
+        name: &str,
+        scope: &mut Scope<'_>,
+        out: &mut String,
+        depth: usize,
+        (from, line): (&str, usize),
+    ) -> Result<(), TemplateError> {
+        let error = |message: String| TemplateError {
+            template: from.to_string(),
+            line,
+            message,
+        };
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(error(format!(
+                "includes nest deeper than {MAX_INCLUDE_DEPTH}"
+            )));
+        }
+        let nodes = self
+            .templates
+            .get(name)
+            .ok_or_else(|| error(format!("no template called {name:?}")))?;
+        self.render_nodes(name, nodes, scope, out, depth)
+    }
+
+    fn render_nodes(
+        &self,
+        template: &str,
+        nodes: &[Node],
+        scope: &mut Scope<'_>,
+        out: &mut String,
+        depth: usize,
+    ) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Print { path, raw, line } => {
+                    let text = match scope.lookup(path) {
+                        None | Some(Value::Null) => String::new(),
+                        Some(Value::Bool(b)) => b.to_string(),
+                        Some(Value::Int(n)) => n.to_string(),
+                        Some(Value::String(s)) => s.clone(),
+                        Some(Value::List(_) | Value::Map(_)) => {
+                            return Err(TemplateError {
+                                template: template.to_string(),
+                                line: *line,
+                                message: format!("{} is not text", path.join(".")),
+                            });
+                        }
+                    };
+                    if *raw {
+                        out.push_str(&text);
+                    } else {
+                        out.push_str(&escape(&text));
+                    }
+                }
+                Node::If {
+                    path,
+                    negate,
+                    then,
+                    otherwise,
+                } => {
+                    let truthy = scope.lookup(path).is_some_and(Value::is_truthy);
+                    let branch = if truthy != *negate { then } else { otherwise };
+                    self.render_nodes(template, branch, scope, out, depth)?;
+                }
+                Node::For {
+                    name,
+                    path,
+                    body,
+                    line,
+                } => {
+                    let items = match scope.lookup(path) {
+                        None | Some(Value::Null) => Vec::new(),
+                        Some(Value::List(items)) => items.clone(),
+                        Some(_) => {
+                            return Err(TemplateError {
+                                template: template.to_string(),
+                                line: *line,
+                                message: format!("{} is not a list", path.join(".")),
+                            });
+                        }
+                    };
+                    let count = items.len();
+                    for (i, item) in items.into_iter().enumerate() {
+                        let info = Vars::new()
+                            .var("index", i + 1)
+                            .var("first", i == 0)
+                            .var("last", i + 1 == count);
+                        scope.locals.push(("loop".to_string(), info.into()));
+                        scope.locals.push((name.clone(), item));
+                        let result = self.render_nodes(template, body, scope, out, depth);
+                        scope.locals.truncate(scope.locals.len() - 2);
+                        result?;
+                    }
+                }
+                Node::Include { name, line } => {
+                    self.render_template(name, scope, out, depth + 1, (template, *line))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Variables visible while rendering: loop variables shadow the ones the
+/// template was rendered with.
+struct Scope<'a> {
+    vars: &'a Vars,
+    locals: Vec<(String, Value)>,
+}
+
+impl Scope<'_> {
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        let (first, rest) = path.split_first()?;
+        let mut value = self
+            .locals
+            .iter()
+            .rev()
+            .find(|(name, _)| name == first)
+            .map(|(_, value)| value)
+            .or_else(|| self.vars.0.get(first))?;
+        for key in rest {
+            value = match value {
+                Value::Map(map) => map.get(key)?,
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+}
+
+/// Nodes parsed up to an end tag, and that tag with its line.
+type Block = (Vec<Node>, Option<(String, usize)>);
+
+struct Parser<'a> {
+    name: &'a str,
+    rest: &'a str,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(name: &'a str, source: &'a str) -> Parser<'a> {
+        Parser {
+            name,
+            rest: source,
+            line: 1,
+        }
+    }
+
+    fn error(&self, line: usize, message: impl Into<String>) -> TemplateError {
+        TemplateError {
+            template: self.name.to_string(),
+            line,
+            message: message.into(),
+        }
+    }
+
+    fn parse(mut self) -> Result<Vec<Node>, TemplateError> {
+        let (nodes, end) = self.block()?;
+        match end {
+            None => Ok(nodes),
+            Some((tag, line)) => Err(self.error(line, format!("unexpected {{% {tag} %}}"))),
+        }
+    }
+
+    /// Parse nodes up to the end of input or a tag this level doesn't own
+    /// (`else`, `endif`, `endfor`), which is returned with its line.
+    fn block(&mut self) -> Result<Block, TemplateError> {
+        let mut nodes = Vec::new();
+        loop {
+            let Some(start) = ["{{", "{%", "{#"]
+                .iter()
+                .filter_map(|open| self.rest.find(open))
+                .min()
+            else {
+                if !self.rest.is_empty() {
+                    nodes.push(Node::Text(self.rest.to_string()));
+                }
+                return Ok((nodes, None));
+            };
+
+            let (text, after) = self.rest.split_at(start);
+            if !text.is_empty() {
+                nodes.push(Node::Text(text.to_string()));
+            }
+            self.line += text.matches('\n').count();
+            let line = self.line;
+
+            let close = match &after[..2] {
+                "{{" => "}}",
+                "{%" => "%}",
+                _ => "#}",
+            };
+            let end = after
+                .find(close)
+                .ok_or_else(|| self.error(line, format!("missing {close}")))?;
+            let inner = &after[2..end];
+            self.line += inner.matches('\n').count();
+            self.rest = &after[end + 2..];
+            let inner = inner.trim();
+
+            match close {
+                "}}" => nodes.push(self.print(inner, line)?),
+                "#}" => {}
+                _ => {
+                    let mut words = inner.split_whitespace();
+                    match words.next().unwrap_or_default() {
+                        "if" => nodes.push(self.if_tag(words.collect(), line)?),
+                        "for" => nodes.push(self.for_tag(words.collect(), line)?),
+                        "include" => {
+                            let name = inner["include".len()..].trim();
+                            let name = name
+                                .strip_prefix('"')
+                                .and_then(|n| n.strip_suffix('"'))
+                                .filter(|n| !n.is_empty())
+                                .ok_or_else(|| {
+                                    self.error(line, "expected {% include \"name\" %}")
+                                })?;
+                            nodes.push(Node::Include {
+                                name: name.to_string(),
+                                line,
+                            });
+                        }
+                        tag @ ("else" | "endif" | "endfor") => {
+                            return Ok((nodes, Some((tag.to_string(), line))));
+                        }
+                        tag => return Err(self.error(line, format!("unknown tag {tag:?}"))),
+                    }
+                }
+            }
+        }
+    }
+
+    fn print(&self, inner: &str, line: usize) -> Result<Node, TemplateError> {
+        let (expr, raw) = match inner.split_once('|') {
+            Some((expr, filter)) if filter.trim() == "raw" => (expr, true),
+            Some((_, filter)) => {
+                return Err(self.error(line, format!("unknown filter {:?}", filter.trim())));
+            }
+            None => (inner, false),
+        };
+        Ok(Node::Print {
+            path: self.path(expr.trim(), line)?,
+            raw,
+            line,
+        })
+    }
+
+    fn if_tag(&mut self, words: Vec<&str>, line: usize) -> Result<Node, TemplateError> {
+        let (negate, expr) = match words[..] {
+            [expr] => (false, expr),
+            ["not", expr] => (true, expr),
+            _ => return Err(self.error(line, "expected {% if name %} or {% if not name %}")),
+        };
+        let path = self.path(expr, line)?;
+
+        let (then, end) = self.block()?;
+        let (otherwise, end) = match end {
+            Some((tag, _)) if tag == "else" => self.block()?,
+            end => (Vec::new(), end),
+        };
+        match end {
+            Some((tag, _)) if tag == "endif" => Ok(Node::If {
+                path,
+                negate,
+                then,
+                otherwise,
+            }),
+            _ => Err(self.error(line, "{% if %} without {% endif %}")),
+        }
+    }
+
+    fn for_tag(&mut self, words: Vec<&str>, line: usize) -> Result<Node, TemplateError> {
+        let [name, "in", expr] = words[..] else {
+            return Err(self.error(line, "expected {% for name in list %}"));
+        };
+        let path = self.path(expr, line)?;
+        let name = self.path(name, line)?.remove(0);
+
+        match self.block()? {
+            (body, Some((tag, _))) if tag == "endfor" => Ok(Node::For {
+                name,
+                path,
+                body,
+                line,
+            }),
+            _ => Err(self.error(line, "{% for %} without {% endfor %}")),
+        }
+    }
+
+    /// A dotted name such as `user.name`.
+    fn path(&self, expr: &str, line: usize) -> Result<Vec<String>, TemplateError> {
+        let valid = |part: &str| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        if !expr.split('.').all(valid) {
+            return Err(self.error(line, format!("bad name {expr:?}")));
+        }
+        Ok(expr.split('.').map(String::from).collect())
+    }
+}
+
+/// Escape text for use in HTML content and quoted attribute values.
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Pages to send in place of the empty bodies of error responses, by status.
+///
+/// Each page is rendered with `status` (`404`), `reason` (`Not Found`) and
+/// `path`, the request path, which is empty when the request could not be
+/// parsed.
+#[derive(Debug, Clone)]
+pub struct ErrorPages {
+    templates: Arc<Templates>,
+    pages: HashMap<StatusCode, String>,
+}
+
+impl ErrorPages {
+    pub fn new(templates: impl Into<Arc<Templates>>) -> ErrorPages {
+        ErrorPages {
+            templates: templates.into(),
+            pages: HashMap::new(),
+        }
+    }
+
+    /// Render the template `name` for responses with `status`.
+    pub fn page(mut self, status: StatusCode, name: impl Into<String>) -> ErrorPages {
+        self.pages.insert(status, name.into());
+        self
+    }
+
+    /// Fill in the body of `response` if it is an error with none and a
+    /// page is configured for its status. A page that fails to render is
+    /// logged and the response left as it was.
+    pub fn apply(&self, path: &str, response: &mut Response) {
+        if response.status.code() < 400 || !response.body.is_empty() {
+            return;
+        }
+        let Some(name) = self.pages.get(&response.status) else {
+            return;
+        };
+        let vars = Vars::new()
+            .var("status", response.status.code())
+            .var("reason", response.status.reason())
+            .var("path", path);
+        match self.templates.render(name, &vars) {
+            Ok(html) => {
+                response
+                    .headers
+                    .set("Content-Type", "text/html; charset=utf-8");
+                response.body = html.into();
+            }
+            Err(e) => crate::warn!("Problem rendering error page: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(source: &str, vars: &Vars) -> Result<String, TemplateError> {
+        let mut templates = Templates::new();
+        templates.add("page.html", source)?;
+        templates.render("page.html", vars)
+    }
+
+    #[test]
+    fn renders_variables_conditionals_and_loops() {
+        let vars = Vars::new()
+            .var("title", "<Tom & Jerry>")
+            .var("user", Vars::new().var("name", "Ferris"))
+            .var("items", vec!["a", "b", "c"])
+            .var("empty", Vec::<String>::new());
+
+        assert_eq!(
+            render("{{ title }} {{title|raw}} {{ user.name }}", &vars).unwrap(),
+            "&lt;Tom &amp; Jerry&gt; <Tom & Jerry> Ferris"
+        );
+        assert_eq!(render("[{{ missing.name }}]", &vars).unwrap(), "[]");
+        assert_eq!(
+            render(
+                "{% if user %}hi {{ user.name }}{% else %}who?{% endif %}\
+                 {% if not empty %}, nothing{% endif %}{# ignored #}",
+                &vars
+            )
+            .unwrap(),
+            "hi Ferris, nothing"
+        );
+        assert_eq!(
+            render(
+                "{% for item in items %}{{ loop.index }}={{ item }}\
+                 {% if not loop.last %},{% endif %}{% endfor %}",
+                &vars
+            )
+            .unwrap(),
+            "1=a,2=b,3=c"
+        );
+    }
+
+    #[test]
+    fn includes_other_templates() {
+        let mut templates = Templates::new();
+        templates
+            .add("head.html", "<title>{{ title }}</title>")
+            .unwrap()
+            .add("page.html", "{% include \"head.html\" %}<p>body</p>")
+            .unwrap()
+            .add("loop.html", "{% include \"loop.html\" %}")
+            .unwrap();
+
+        let vars = Vars::new().var("title", "Home");
+        assert_eq!(
+            templates.render("page.html", &vars).unwrap(),
+            "<title>Home</title><p>body</p>"
+        );
+        assert!(templates.render("loop.html", &vars).is_err());
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        let vars = Vars::new().var("items", vec!["a"]);
+        let error = |source| render(source, &vars).unwrap_err().to_string();
+
+        assert_eq!(
+            error("a\n{% if x %}\nb"),
+            "page.html:2: {% if %} without {% endif %}"
+        );
+        assert_eq!(error("a\n\n{{ x"), "page.html:3: missing }}");
+        assert_eq!(
+            error("{% endfor %}"),
+            "page.html:1: unexpected {% endfor %}"
+        );
+        assert_eq!(
+            error("{{ x | upper }}"),
+            "page.html:1: unknown filter \"upper\""
+        );
+        assert_eq!(error("{{ items }}"), "page.html:1: items is not text");
+        assert_eq!(
+            error("\n{% include \"nope.html\" %}"),
+            "page.html:2: no template called \"nope.html\""
+        );
+    }
+
+    #[test]
+    fn fills_in_empty_error_responses() {
+        let mut templates = Templates::new();
+        templates
+            .add("error.html", "{{ status }} {{ reason }}: {{ path }}")
+            .unwrap();
+        let pages = ErrorPages::new(templates).page(StatusCode::NotFound, "error.html");
+
+        let mut response = Response::new(StatusCode::NotFound);
+        pages.apply("/a<b>", &mut response);
+        assert_eq!(
+            response.body.as_bytes(),
+            Some(&b"404 Not Found: /a&lt;b&gt;"[..])
+        );
+
+        let mut handled = Response::new(StatusCode::NotFound).with_body("text/plain", "gone");
+        pages.apply("/", &mut handled);
+        assert_eq!(handled.body.as_bytes(), Some(&b"gone"[..]));
+
+        let mut forbidden = Response::new(StatusCode::Forbidden);
+        pages.apply("/", &mut forbidden);
+        assert!(forbidden.body.is_empty());
+    }
+}
@@ -0,0 +1,239 @@
+//! HTTPS, behind the `tls` feature.
+//!
+//! ```no_run
+//! use hello::{router::Router, server::Server, tls::Certificates};
+//!
+//! let mut certificates = Certificates::new();
+//! certificates
+//!     .add(&["example.com"], "certs/example.pem", "certs/example.key")
+//!     .unwrap();
+//!
+//! Server::bind("0.0.0.0:8443")
+//!     .unwrap()
+//!     .tls(certificates.server_config().unwrap())
+//!     .serve(Router::new());
+//! ```
+
+use std::{collections::HashMap, fs, io, path::Path, sync::Arc};
+
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+pub use rustls::ServerConfig;
+
+use crate::{
+    request::Request,
+    response::{Response, StatusCode},
+    router::Handler,
+};
+
+/// The certificates an HTTPS listener presents, chosen by the host name the
+/// client asks for (SNI).
+#[derive(Debug, Default)]
+pub struct Certificates {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    pub fn new() -> Certificates {
+        Certificates::default()
+    }
+
+    /// Load a certificate chain and its private key from PEM files and
+    /// present them to clients asking for any of `names`.
+    ///
+    /// `*.example.com` matches any single label in front of `example.com`.
+    /// The first certificate added is also the fallback for clients that
+    /// send no name or one that matches nothing.
+    pub fn add(
+        &mut self,
+        names: &[&str],
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> io::Result<&mut Certificates> {
+        let read = |path: &Path| {
+            fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+        };
+        let cert_pem = read(cert.as_ref())?;
+        let key_pem = read(key.as_ref())?;
+
+        self.add_pem(names, &cert_pem, &key_pem)
+    }
+
+    /// Like [`add`](Certificates::add), with the PEM already in memory.
+    pub fn add_pem(
+        &mut self,
+        names: &[&str],
+        cert_pem: &[u8],
+        key_pem: &[u8],
+    ) -> io::Result<&mut Certificates> {
+        let chain = CertificateDer::pem_slice_iter(cert_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(format!("certificate: {e}")))?;
+        if chain.is_empty() {
+            return Err(invalid("certificate: no CERTIFICATE section found"));
+        }
+        let key = PrivateKeyDer::from_pem_slice(key_pem)
+            .map_err(|e| invalid(format!("private key: {e}")))?;
+
+        let certified = CertifiedKey::from_der(chain, key, &provider())
+            .map_err(|e| invalid(format!("private key: {e}")))?;
+        let certified = Arc::new(certified);
+
+        for name in names {
+            self.by_name
+                .insert(name.to_ascii_lowercase(), Arc::clone(&certified));
+        }
+        self.default.get_or_insert(certified);
+
+        Ok(self)
+    }
+
+    /// Build the rustls configuration to hand to
+    /// [`Server::tls`](crate::server::Server::tls).
+    pub fn server_config(self) -> io::Result<Arc<ServerConfig>> {
+        let Some(default) = self.default else {
+            return Err(invalid("no certificates were added"));
+        };
+        let resolver = Resolver {
+            by_name: self.by_name,
+            default,
+        };
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| invalid(e.to_string()))?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Arc::new(config))
+    }
+}
+
+fn provider() -> CryptoProvider {
+    ring::default_provider()
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[derive(Debug)]
+struct Resolver {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl Resolver {
+    fn lookup(&self, name: &str) -> Option<&Arc<CertifiedKey>> {
+        let name = name.to_ascii_lowercase();
+        self.by_name.get(&name).or_else(|| {
+            let (_, parent) = name.split_once('.')?;
+            self.by_name.get(&format!("*.{parent}"))
+        })
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certified = client_hello
+            .server_name()
+            .and_then(|name| self.lookup(name))
+            .unwrap_or(&self.default);
+        Some(Arc::clone(certified))
+    }
+}
+
+/// Answers every request with a `308 Permanent Redirect` to the same URL on
+/// the HTTPS port, for serving on the plain HTTP port.
+#[derive(Debug, Clone, Copy)]
+pub struct HttpsRedirect {
+    port: u16,
+}
+
+impl HttpsRedirect {
+    pub fn new(https_port: u16) -> HttpsRedirect {
+        HttpsRedirect { port: https_port }
+    }
+
+    /// Where `request` should go instead, if it named a host.
+    pub fn location(&self, request: &Request) -> Option<String> {
+        let host = host_name(request.headers.get("Host")?)?;
+        let mut location = match self.port {
+            443 => format!("https://{host}{}", request.path),
+            port => format!("https://{host}:{port}{}", request.path),
+        };
+        if let Some(query) = &request.query {
+            location.push('?');
+            location.push_str(query);
+        }
+        Some(location)
+    }
+}
+
+impl Handler for HttpsRedirect {
+    fn handle(&self, request: &mut Request) -> Response {
+        match self.location(request) {
+            Some(location) => Response::new(StatusCode::PermanentRedirect)
+                .header("Location", location)
+                .with_body("text/plain; charset=utf-8", "Use HTTPS.\n"),
+            None => Response::new(StatusCode::BadRequest),
+        }
+    }
+}
+
+/// The `Host` header without its port: `example.com:80` becomes
+/// `example.com` and `[::1]:80` becomes `[::1]`.
+fn host_name(host: &str) -> Option<&str> {
+    let host = host.trim();
+    let name = if host.starts_with('[') {
+        &host[..=host.find(']')?]
+    } else {
+        host.split(':').next()?
+    };
+    let valid = !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b".-[]:".contains(&b));
+    valid.then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(redirect: HttpsRedirect, raw: &str) -> Option<String> {
+        let request = Request::read_from(&mut raw.as_bytes()).unwrap();
+        redirect.location(&request)
+    }
+
+    #[test]
+    fn redirects_to_the_https_port() {
+        let redirect = HttpsRedirect::new(8443);
+        assert_eq!(
+            location(
+                redirect,
+                "GET /a%20b?x=1 HTTP/1.1\r\nHost: example.com:8080\r\n\r\n"
+            ),
+            Some("https://example.com:8443/a%20b?x=1".to_string())
+        );
+        assert_eq!(
+            location(
+                HttpsRedirect::new(443),
+                "GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n"
+            ),
+            Some("https://[::1]/".to_string())
+        );
+        assert_eq!(location(redirect, "GET / HTTP/1.0\r\n\r\n"), None);
+        assert_eq!(
+            location(redirect, "GET / HTTP/1.1\r\nHost: evil.com/x\r\n\r\n"),
+            None
+        );
+    }
+}
@@ -0,0 +1,965 @@
+//! WebSocket connections (RFC 6455) for message-based endpoints.
+//!
+//! A [`WebSocket`] route answers the opening handshake and hands the
+//! connection to a [`WebSocketHandler`], which hears about each message and
+//! replies through a [`Sender`]. Senders can be cloned and kept elsewhere
+//! to push messages from other threads:
+//!
+//! ```
+//! use hello::{
+//!     request::Method,
+//!     router::Router,
+//!     websocket::{Message, Sender, WebSocket},
+//! };
+//!
+//! let mut router = Router::new();
+//! router.route(
+//!     Method::Get,
+//!     "/echo",
+//!     WebSocket::new(|socket: &Sender, message: Message| {
+//!         let _ = socket.send(message);
+//!     }),
+//! );
+//! ```
+//!
+//! Pings are answered and the closing handshake is completed without the
+//! handler's involvement. A client that goes quiet is pinged, and closed
+//! with [`GOING_AWAY`] if it stays silent for the server's
+//! [`websocket_idle_timeout`](crate::server::Server::websocket_idle_timeout).
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, TrySendError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+use crate::{
+    request::{Method, Request, Version},
+    response::{Response, StatusCode},
+    router::Handler,
+};
+
+/// Appended to the client's key to compute `Sec-WebSocket-Accept`.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// How long to wait for the client to answer our close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many messages [`Sender`]s may queue on one connection before it has
+/// written them out.
+const MAX_QUEUED: usize = 256;
+
+/// Close codes (RFC 6455 section 7.4.1).
+pub const NORMAL_CLOSURE: u16 = 1000;
+pub const GOING_AWAY: u16 = 1001;
+pub const PROTOCOL_ERROR: u16 = 1002;
+/// Reported to [`WebSocketHandler::on_close`] when the client's close frame
+/// had no code; never sent.
+pub const NO_STATUS: u16 = 1005;
+/// Reported to [`WebSocketHandler::on_close`] when the connection ended
+/// without a closing handshake; never sent.
+pub const ABNORMAL_CLOSURE: u16 = 1006;
+pub const INVALID_DATA: u16 = 1007;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_bits(bits: u8) -> Option<Opcode> {
+        match bits {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    pub fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+/// Why frames from the client were refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// Reserved bits or opcodes, an unmasked frame, a fragmented or
+    /// oversized control frame, or fragments out of order.
+    Protocol,
+    /// A text message that isn't UTF-8.
+    InvalidData,
+    /// A message bigger than the server accepts.
+    TooBig,
+}
+
+impl FrameError {
+    /// The code to close the connection with.
+    pub fn close_code(self) -> u16 {
+        match self {
+            FrameError::Protocol => PROTOCOL_ERROR,
+            FrameError::InvalidData => INVALID_DATA,
+            FrameError::TooBig => MESSAGE_TOO_BIG,
+        }
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Protocol => write!(f, "WebSocket protocol error"),
+            FrameError::InvalidData => write!(f, "text message is not UTF-8"),
+            FrameError::TooBig => write!(f, "WebSocket message too big"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// One frame on the wire.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Whether this is the last frame of its message.
+    pub fin: bool,
+    pub opcode: Opcode,
+    /// The masking key, which clients must use and servers must not.
+    pub mask: Option<[u8; 4]>,
+    /// The payload, unmasked.
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// A whole, unmasked message or control frame.
+    pub fn new(opcode: Opcode, payload: impl Into<Vec<u8>>) -> Frame {
+        Frame {
+            fin: true,
+            opcode,
+            mask: None,
+            payload: payload.into(),
+        }
+    }
+
+    /// A close frame with `code` and `reason`.
+    pub fn close(code: u16, reason: &str) -> Frame {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        Frame::new(Opcode::Close, payload)
+    }
+
+    /// Parse the frame at the start of `buf`, returning it and the number
+    /// of bytes it took up, or `None` if more bytes are needed. Payloads
+    /// over `max_payload` bytes are refused before they arrive.
+    pub fn parse(buf: &[u8], max_payload: u64) -> Result<Option<(Frame, usize)>, FrameError> {
+        let [first, second, ..] = *buf else {
+            return Ok(None);
+        };
+        if first & 0x70 != 0 {
+            return Err(FrameError::Protocol);
+        }
+        let fin = first & 0x80 != 0;
+        let opcode = Opcode::from_bits(first & 0x0F).ok_or(FrameError::Protocol)?;
+        let masked = second & 0x80 != 0;
+
+        let (len, mut at) = match second & 0x7F {
+            126 => match buf.get(2..4) {
+                Some(bytes) => (u64::from(u16::from_be_bytes([bytes[0], bytes[1]])), 4),
+                None => return Ok(None),
+            },
+            127 => match buf.get(2..10) {
+                Some(bytes) => (u64::from_be_bytes(bytes.try_into().unwrap()), 10),
+                None => return Ok(None),
+            },
+            len => (u64::from(len), 2),
+        };
+        if opcode.is_control() && (!fin || len > 125) {
+            return Err(FrameError::Protocol);
+        }
+        if len > max_payload {
+            return Err(FrameError::TooBig);
+        }
+
+        let mask = if masked {
+            let Some(key) = buf.get(at..at + 4) else {
+                return Ok(None);
+            };
+            at += 4;
+            Some([key[0], key[1], key[2], key[3]])
+        } else {
+            None
+        };
+
+        let end = usize::try_from(len)
+            .ok()
+            .and_then(|len| at.checked_add(len))
+            .ok_or(FrameError::TooBig)?;
+        let Some(payload) = buf.get(at..end) else {
+            return Ok(None);
+        };
+        let mut payload = payload.to_vec();
+        if let Some(key) = mask {
+            apply_mask(&mut payload, key);
+        }
+
+        Ok(Some((
+            Frame {
+                fin,
+                opcode,
+                mask,
+                payload,
+            },
+            end,
+        )))
+    }
+
+    /// The frame as it goes on the wire, masked if it has a key.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.payload.len() + 14);
+        out.push(if self.fin { 0x80 } else { 0 } | self.opcode.bits());
+
+        let masked = if self.mask.is_some() { 0x80 } else { 0 };
+        let len = self.payload.len();
+        if len < 126 {
+            out.push(masked | len as u8);
+        } else if let Ok(len) = u16::try_from(len) {
+            out.push(masked | 126);
+            out.extend_from_slice(&len.to_be_bytes());
+        } else {
+            out.push(masked | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+
+        match self.mask {
+            Some(key) => {
+                out.extend_from_slice(&key);
+                let start = out.len();
+                out.extend_from_slice(&self.payload);
+                apply_mask(&mut out[start..], key);
+            }
+            None => out.extend_from_slice(&self.payload),
+        }
+        out
+    }
+}
+
+fn apply_mask(data: &mut [u8], key: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= key[i % 4];
+    }
+}
+
+/// A whole message, however many frames it arrived in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Message {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Message {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(bytes: Vec<u8>) -> Message {
+        Message::Binary(bytes)
+    }
+}
+
+/// What a WebSocket endpoint does with its connections.
+///
+/// Closures taking `(&Sender, Message)` implement this, for endpoints that
+/// only care about messages.
+pub trait WebSocketHandler: Send + Sync + 'static {
+    /// The handshake is done. `socket` may be kept to send messages later.
+    fn on_open(&self, socket: &Sender) {
+        let _ = socket;
+    }
+
+    fn on_message(&self, socket: &Sender, message: Message);
+
+    /// The connection is gone, with the close code the client sent, or
+    /// [`NO_STATUS`] or [`ABNORMAL_CLOSURE`].
+    fn on_close(&self, code: u16) {
+        let _ = code;
+    }
+}
+
+impl<F> WebSocketHandler for F
+where
+    F: Fn(&Sender, Message) + Send + Sync + 'static,
+{
+    fn on_message(&self, socket: &Sender, message: Message) {
+        self(socket, message)
+    }
+}
+
+enum Outgoing {
+    Message(Message),
+    Close(u16, String),
+}
+
+/// Sends messages on one connection, from any thread.
+#[derive(Clone)]
+pub struct Sender {
+    queue: mpsc::SyncSender<Outgoing>,
+    open: Arc<AtomicBool>,
+    /// Tells the connection there is something to send.
+    wake: Arc<dyn Fn() + Send + Sync>,
+}
+
+impl Sender {
+    /// Queue `message`. Fails with `NotConnected` once the connection is
+    /// closing or closed, and with `WouldBlock` while too many messages
+    /// are already waiting for a client that isn't reading them.
+    pub fn send(&self, message: impl Into<Message>) -> std::io::Result<()> {
+        self.push(Outgoing::Message(message.into()))
+    }
+
+    /// Start the closing handshake.
+    pub fn close(&self, code: u16, reason: &str) -> std::io::Result<()> {
+        self.push(Outgoing::Close(code, reason.to_string()))
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    fn push(&self, outgoing: Outgoing) -> std::io::Result<()> {
+        if !self.is_open() {
+            return Err(std::io::ErrorKind::NotConnected.into());
+        }
+        match self.queue.try_send(outgoing) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::WouldBlock,
+                    "too many WebSocket messages queued",
+                ))
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(std::io::ErrorKind::NotConnected.into())
+            }
+        }
+        (self.wake)();
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Sender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("open", &self.is_open())
+            .finish()
+    }
+}
+
+/// A route handler that accepts WebSocket connections for a
+/// [`WebSocketHandler`].
+///
+/// The connection is served on the thread or task that accepted it for as
+/// long as it stays open, so the server caps how many are open at once
+/// with [`max_websockets`](crate::server::Server::max_websockets).
+pub struct WebSocket {
+    handler: Arc<dyn WebSocketHandler>,
+}
+
+impl WebSocket {
+    pub fn new(handler: impl WebSocketHandler) -> WebSocket {
+        WebSocket {
+            handler: Arc::new(handler),
+        }
+    }
+}
+
+impl Handler for WebSocket {
+    fn handle(&self, request: &mut Request) -> Response {
+        upgrade(request, Arc::clone(&self.handler))
+    }
+}
+
+/// Answer a WebSocket opening handshake with `101 Switching Protocols`,
+/// after which the server hands the connection to `handler`.
+///
+/// Requests that aren't a valid handshake get `426 Upgrade Required` if
+/// they don't ask for a WebSocket or a version other than 13, and `400`
+/// otherwise.
+pub fn upgrade(request: &Request, handler: Arc<dyn WebSocketHandler>) -> Response {
+    let headers = &request.headers;
+    let upgrade_required = || {
+        Response::new(StatusCode::UpgradeRequired)
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+    };
+    if !headers.has_token("Upgrade", "websocket") {
+        return upgrade_required();
+    }
+    if headers.get("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return upgrade_required();
+    }
+
+    let key = headers.get("Sec-WebSocket-Key").map(str::trim);
+    let valid_key = key.is_some_and(|key| BASE64.decode(key).is_ok_and(|k| k.len() == 16));
+    if request.method != Method::Get
+        || request.version != Version::Http11
+        || !headers.has_token("Connection", "upgrade")
+        || !valid_key
+    {
+        return Response::new(StatusCode::BadRequest);
+    }
+
+    let mut response = Response::new(StatusCode::SwitchingProtocols)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", accept_key(key.unwrap_or_default()));
+    response.upgrade = Some(Upgrade(handler));
+    response
+}
+
+/// The `Sec-WebSocket-Accept` value for a `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    BASE64.encode(sha1.digest().bytes())
+}
+
+/// The handler a `101` response hands its connection to.
+pub(crate) struct Upgrade(Arc<dyn WebSocketHandler>);
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+/// The protocol side of one connection, independent of how bytes get to
+/// and from the client: the server feeds it what it reads and writes out
+/// what it produces.
+pub(crate) struct Session {
+    handler: Arc<dyn WebSocketHandler>,
+    sender: Sender,
+    outbox: mpsc::Receiver<Outgoing>,
+    max_message_size: u64,
+    idle_timeout: Duration,
+    /// When the client last sent anything.
+    last_heard: Instant,
+    /// Whether the client has been pinged since.
+    pinged: bool,
+    /// Received bytes not yet parsed into frames.
+    input: Vec<u8>,
+    /// Encoded frames waiting to be written.
+    output: Vec<u8>,
+    /// The opcode and payload so far of a fragmented message.
+    fragments: Option<(Opcode, Vec<u8>)>,
+    /// When we sent our close frame.
+    closing_since: Option<Instant>,
+    closed: bool,
+    close_code: u16,
+    finished: bool,
+}
+
+impl Session {
+    /// Start a session for `upgrade`, calling its `on_open`. `wake` is
+    /// called whenever a [`Sender`] queues something.
+    pub(crate) fn open(
+        upgrade: Upgrade,
+        max_message_size: u64,
+        idle_timeout: Duration,
+        wake: Arc<dyn Fn() + Send + Sync>,
+    ) -> Session {
+        let (queue, outbox) = mpsc::sync_channel(MAX_QUEUED);
+        let sender = Sender {
+            queue,
+            open: Arc::new(AtomicBool::new(true)),
+            wake,
+        };
+        let session = Session {
+            handler: upgrade.0,
+            sender,
+            outbox,
+            max_message_size,
+            idle_timeout,
+            last_heard: Instant::now(),
+            pinged: false,
+            input: Vec::new(),
+            output: Vec::new(),
+            fragments: None,
+            closing_since: None,
+            closed: false,
+            close_code: ABNORMAL_CLOSURE,
+            finished: false,
+        };
+        session.handler.on_open(&session.sender);
+        session
+    }
+
+    /// Handle bytes read from the client.
+    pub(crate) fn receive(&mut self, data: &[u8]) {
+        if !data.is_empty() {
+            self.last_heard = Instant::now();
+            self.pinged = false;
+        }
+        self.input.extend_from_slice(data);
+        let mut consumed = 0;
+        while !self.closed {
+            let frame = match Frame::parse(&self.input[consumed..], self.max_message_size) {
+                Ok(Some((frame, len))) => {
+                    consumed += len;
+                    frame
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    self.fail(e);
+                    break;
+                }
+            };
+            if let Err(e) = self.handle(frame) {
+                self.fail(e);
+            }
+        }
+        self.input.drain(..consumed);
+    }
+
+    fn handle(&mut self, frame: Frame) -> Result<(), FrameError> {
+        if frame.mask.is_none() {
+            return Err(FrameError::Protocol);
+        }
+
+        match frame.opcode {
+            Opcode::Ping => self.queue(Frame::new(Opcode::Pong, frame.payload)),
+            Opcode::Pong => {}
+            Opcode::Close => {
+                let code = close_code(&frame.payload)?;
+                self.close_code = code.unwrap_or(NO_STATUS);
+                if self.closing_since.is_none() {
+                    // Echo the code, as the closing handshake asks.
+                    let reply = match code {
+                        Some(code) => Frame::close(code, ""),
+                        None => Frame::new(Opcode::Close, Vec::new()),
+                    };
+                    self.queue(reply);
+                }
+                self.closed = true;
+            }
+            // Once we've sent a close frame, messages are dropped.
+            _ if self.closing_since.is_some() => {}
+            Opcode::Text | Opcode::Binary => {
+                if self.fragments.is_some() {
+                    return Err(FrameError::Protocol);
+                }
+                if frame.fin {
+                    self.deliver(frame.opcode, frame.payload)?;
+                } else {
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+            }
+            Opcode::Continuation => {
+                let (opcode, mut payload) = self.fragments.take().ok_or(FrameError::Protocol)?;
+                if (payload.len() + frame.payload.len()) as u64 > self.max_message_size {
+                    return Err(FrameError::TooBig);
+                }
+                payload.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    self.deliver(opcode, payload)?;
+                } else {
+                    self.fragments = Some((opcode, payload));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn deliver(&mut self, opcode: Opcode, payload: Vec<u8>) -> Result<(), FrameError> {
+        let message = if opcode == Opcode::Text {
+            Message::Text(String::from_utf8(payload).map_err(|_| FrameError::InvalidData)?)
+        } else {
+            Message::Binary(payload)
+        };
+        self.handler.on_message(&self.sender, message);
+        // Replies don't count against the queue while the rest of what
+        // was read is handled.
+        self.drain_outbox();
+        Ok(())
+    }
+
+    /// Refuse what the client sent: say why and drop the connection
+    /// without waiting for an answer.
+    fn fail(&mut self, error: FrameError) {
+        crate::debug!("Closing WebSocket: {error}");
+        self.queue(Frame::close(error.close_code(), ""));
+        self.close_code = error.close_code();
+        self.closed = true;
+    }
+
+    /// Start the closing handshake, unless it has already started.
+    pub(crate) fn close(&mut self, code: u16, reason: &str) {
+        if self.closing_since.is_some() || self.closed {
+            return;
+        }
+        self.queue(Frame::close(code, reason));
+        self.closing_since = Some(Instant::now());
+        self.sender.open.store(false, Ordering::Relaxed);
+    }
+
+    /// Ping a client that has been quiet for half the idle timeout, and
+    /// start closing once it has been for all of it.
+    pub(crate) fn check_idle(&mut self) {
+        if self.is_closing() || Instant::now() < self.idle_deadline() {
+            return;
+        }
+        if self.pinged {
+            self.close(GOING_AWAY, "idle timeout");
+        } else {
+            self.queue(Frame::new(Opcode::Ping, Vec::new()));
+            self.pinged = true;
+        }
+    }
+
+    /// When [`check_idle`](Session::check_idle) next has something to do,
+    /// unless the client speaks first.
+    pub(crate) fn idle_deadline(&self) -> Instant {
+        if self.pinged {
+            self.last_heard + self.idle_timeout
+        } else {
+            self.last_heard + self.idle_timeout / 2
+        }
+    }
+
+    fn queue(&mut self, frame: Frame) {
+        self.output.extend_from_slice(&frame.encode());
+    }
+
+    /// The bytes to write to the client, including anything senders have
+    /// queued since the last call.
+    pub(crate) fn take_output(&mut self) -> Vec<u8> {
+        self.drain_outbox();
+        std::mem::take(&mut self.output)
+    }
+
+    /// Encode what senders have queued.
+    fn drain_outbox(&mut self) {
+        while let Ok(outgoing) = self.outbox.try_recv() {
+            if self.closing_since.is_some() || self.closed {
+                break;
+            }
+            match outgoing {
+                Outgoing::Message(Message::Text(text)) => {
+                    self.queue(Frame::new(Opcode::Text, text));
+                }
+                Outgoing::Message(Message::Binary(bytes)) => {
+                    self.queue(Frame::new(Opcode::Binary, bytes));
+                }
+                Outgoing::Close(code, reason) => self.close(code, &reason),
+            }
+        }
+    }
+
+    /// Whether we have sent a close frame and are waiting for the reply.
+    pub(crate) fn is_closing(&self) -> bool {
+        self.closing_since.is_some()
+    }
+
+    /// Whether the connection should be closed: the closing handshake is
+    /// done, or the client took too long to finish it.
+    pub(crate) fn is_done(&self) -> bool {
+        self.closed
+            || self
+                .closing_since
+                .is_some_and(|since| since.elapsed() >= CLOSE_TIMEOUT)
+    }
+
+    /// Tell the handler the connection is gone. Also done on drop.
+    pub(crate) fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        self.sender.open.store(false, Ordering::Relaxed);
+        self.handler.on_close(self.close_code);
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// The code in a close frame's payload, if it has one.
+fn close_code(payload: &[u8]) -> Result<Option<u16>, FrameError> {
+    let (code, reason) = match payload {
+        [] => return Ok(None),
+        [_] => return Err(FrameError::Protocol),
+        [high, low, reason @ ..] => (u16::from_be_bytes([*high, *low]), reason),
+    };
+    // Codes a peer may send (RFC 6455 section 7.4).
+    if !matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999) {
+        return Err(FrameError::Protocol);
+    }
+    if std::str::from_utf8(reason).is_err() {
+        return Err(FrameError::InvalidData);
+    }
+    Ok(Some(code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    const KEY: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    fn masked(fin: bool, opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+        Frame {
+            fin,
+            opcode,
+            mask: Some(KEY),
+            payload: payload.to_vec(),
+        }
+        .encode()
+    }
+
+    /// Every frame in `bytes`, which the server sends unmasked.
+    fn frames(mut bytes: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        while let Some((frame, len)) = Frame::parse(bytes, u64::MAX).unwrap() {
+            frames.push(frame);
+            bytes = &bytes[len..];
+        }
+        assert!(bytes.is_empty());
+        frames
+    }
+
+    /// Records what happens and echoes messages back.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl WebSocketHandler for Recorder {
+        fn on_message(&self, socket: &Sender, message: Message) {
+            self.0.lock().unwrap().push(format!("{message:?}"));
+            socket.send(message).unwrap();
+        }
+
+        fn on_close(&self, code: u16) {
+            self.0.lock().unwrap().push(format!("closed {code}"));
+        }
+    }
+
+    fn session(max_message_size: u64) -> (Session, Arc<Recorder>) {
+        let recorder = Arc::new(Recorder::default());
+        let upgrade = Upgrade(Arc::clone(&recorder) as Arc<dyn WebSocketHandler>);
+        let session = Session::open(
+            upgrade,
+            max_message_size,
+            Duration::from_secs(60),
+            Arc::new(|| {}),
+        );
+        (session, recorder)
+    }
+
+    #[test]
+    fn computes_the_accept_key() {
+        // The example from RFC 6455 section 1.3.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn encodes_and_parses_frames() {
+        // "Hello", unmasked and masked, from RFC 6455 section 5.7.
+        let hello = Frame::new(Opcode::Text, "Hello");
+        assert_eq!(hello.encode(), b"\x81\x05Hello");
+        let masked = b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58";
+        let (frame, len) = Frame::parse(masked, 125).unwrap().unwrap();
+        assert_eq!((frame.payload.as_slice(), len), (&b"Hello"[..], 11));
+        assert_eq!(frame.encode(), masked);
+
+        for size in [125, 126, 65535, 65536] {
+            let frame = Frame::new(Opcode::Binary, vec![7; size]);
+            let bytes = frame.encode();
+            assert_eq!(
+                Frame::parse(&bytes, u64::MAX).unwrap(),
+                Some((frame, bytes.len()))
+            );
+            assert_eq!(Frame::parse(&bytes[..bytes.len() - 1], u64::MAX), Ok(None));
+        }
+
+        assert_eq!(Frame::parse(b"\xc1\x00", 125), Err(FrameError::Protocol));
+        assert_eq!(Frame::parse(b"\x83\x00", 125), Err(FrameError::Protocol));
+        assert_eq!(Frame::parse(b"\x09\x00", 125), Err(FrameError::Protocol));
+        assert_eq!(
+            Frame::parse(b"\x89\x7e\x00\x7e", 1000),
+            Err(FrameError::Protocol)
+        );
+        assert_eq!(
+            Frame::parse(b"\x82\x7e\x01\x00", 255),
+            Err(FrameError::TooBig)
+        );
+    }
+
+    #[test]
+    fn assembles_fragments_and_answers_control_frames() {
+        let (mut session, recorder) = session(16);
+
+        let mut input = masked(false, Opcode::Text, b"Hel");
+        input.extend(masked(true, Opcode::Ping, b"?"));
+        input.extend(masked(true, Opcode::Continuation, b"lo"));
+        // Split mid-frame, as reads may be.
+        session.receive(&input[..5]);
+        session.receive(&input[5..]);
+
+        let output = frames(&session.take_output());
+        assert_eq!(
+            output,
+            [
+                Frame::new(Opcode::Pong, "?"),
+                Frame::new(Opcode::Text, "Hello")
+            ]
+        );
+
+        session.receive(&masked(true, Opcode::Close, &1000u16.to_be_bytes()));
+        assert!(session.is_done());
+        assert_eq!(frames(&session.take_output()), [Frame::close(1000, "")]);
+        drop(session);
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            ["Text(\"Hello\")", "closed 1000"]
+        );
+    }
+
+    #[test]
+    fn fails_on_bad_frames() {
+        let cases = [
+            (
+                Frame::new(Opcode::Text, "unmasked").encode(),
+                PROTOCOL_ERROR,
+            ),
+            (masked(true, Opcode::Continuation, b"x"), PROTOCOL_ERROR),
+            (masked(true, Opcode::Text, b"\xff"), INVALID_DATA),
+            (masked(true, Opcode::Binary, &[0; 17]), MESSAGE_TOO_BIG),
+            (masked(true, Opcode::Close, &[3]), PROTOCOL_ERROR),
+        ];
+        for (input, code) in cases {
+            let (mut session, recorder) = session(16);
+            session.receive(&input);
+            assert!(session.is_done());
+            assert_eq!(frames(&session.take_output()), [Frame::close(code, "")]);
+            drop(session);
+            assert_eq!(*recorder.0.lock().unwrap(), [format!("closed {code}")]);
+        }
+    }
+
+    #[test]
+    fn closes_on_request() {
+        let (mut session, recorder) = session(16);
+        session.sender.send("bye").unwrap();
+        session.sender.close(GOING_AWAY, "restarting").unwrap();
+        assert_eq!(
+            frames(&session.take_output()),
+            [
+                Frame::new(Opcode::Text, "bye"),
+                Frame::close(GOING_AWAY, "restarting")
+            ]
+        );
+        assert!(session.is_closing() && !session.is_done());
+        assert!(session.sender.send("late").is_err());
+
+        // Messages crossing our close frame are dropped.
+        session.receive(&masked(true, Opcode::Text, b"hi"));
+        session.receive(&masked(true, Opcode::Close, &GOING_AWAY.to_be_bytes()));
+        assert!(session.is_done());
+        assert!(session.take_output().is_empty());
+        drop(session);
+        assert_eq!(*recorder.0.lock().unwrap(), ["closed 1001"]);
+    }
+
+    #[test]
+    fn limits_queued_messages() {
+        let (mut stalled, _) = session(16);
+        let sender = stalled.sender.clone();
+        for _ in 0..MAX_QUEUED {
+            sender.send("hi").unwrap();
+        }
+        let err = sender.send("one too many").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+
+        assert_eq!(frames(&stalled.take_output()).len(), MAX_QUEUED);
+        sender.send("hi").unwrap();
+
+        // Replies to what the client sent don't pile up in the queue.
+        let (mut echoing, _) = session(16);
+        let burst = masked(true, Opcode::Text, b"x").repeat(MAX_QUEUED * 2);
+        echoing.receive(&burst);
+        assert_eq!(frames(&echoing.take_output()).len(), MAX_QUEUED * 2);
+    }
+
+    #[test]
+    fn pings_then_closes_quiet_clients() {
+        let (mut session, _) = session(16);
+        let quiet_for = |session: &mut Session, secs| {
+            session.last_heard = Instant::now() - Duration::from_secs(secs);
+            session.check_idle();
+        };
+
+        quiet_for(&mut session, 10);
+        assert!(session.take_output().is_empty());
+        quiet_for(&mut session, 30);
+        assert_eq!(
+            frames(&session.take_output()),
+            [Frame::new(Opcode::Ping, Vec::new())]
+        );
+        quiet_for(&mut session, 40);
+        assert!(session.take_output().is_empty());
+
+        // A pong, like anything else, counts as hearing from the client.
+        session.receive(&masked(true, Opcode::Pong, b""));
+        assert!(session.idle_deadline() > Instant::now() + Duration::from_secs(29));
+        quiet_for(&mut session, 30);
+        assert_eq!(
+            frames(&session.take_output()),
+            [Frame::new(Opcode::Ping, Vec::new())]
+        );
+        quiet_for(&mut session, 60);
+        assert_eq!(
+            frames(&session.take_output()),
+            [Frame::close(GOING_AWAY, "idle timeout")]
+        );
+        assert!(session.is_closing());
+    }
+}
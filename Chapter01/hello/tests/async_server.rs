@@ -0,0 +1,108 @@
+#![cfg(feature = "async")]
+
+mod common;
+
+use std::{io::prelude::*, net::TcpStream, thread, time::Duration};
+
+use common::{connect, exchange, router, start_with};
+use hello::{
+    response::{Response, StatusCode},
+    server::Server,
+};
+
+#[test]
+fn serves_pipelined_requests() {
+    let server = start_with(Server::serve_async, router(), |s| s);
+
+    let response = exchange(
+        server.addr,
+        b"GET / HTTP/1.1\r\n\r\n\
+          POST /echo HTTP/1.1\r\nContent-Length: 4\r\n\r\nping\
+          GET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+
+    assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 3);
+    assert!(response.contains("\r\n\r\nping"));
+}
+
+#[test]
+fn reads_bodies_that_arrive_in_pieces() {
+    let server = start_with(Server::serve_async, router(), |s| s);
+    let mut stream = connect(server.addr);
+    let mut writer = stream.try_clone().unwrap();
+
+    let sending = thread::spawn(move || {
+        let body = vec![b'x'; 4 * 1024 * 1024];
+        let head = format!(
+            "\r\nPOST /echo HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+        writer.write_all(head.as_bytes()).unwrap();
+        for piece in body.chunks(64 * 1024) {
+            writer.write_all(piece).unwrap();
+        }
+
+        let mut chunked = b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        // Size lines count against the header limit, so there can't be
+        // too many of them.
+        for _ in 0..500 {
+            chunked.extend_from_slice(b"64;x=1\r\n");
+            chunked.extend_from_slice(&[b'y'; 100]);
+            chunked.extend_from_slice(b"\r\n");
+        }
+        chunked.extend_from_slice(b"0\r\nTrailer: x\r\n\r\n");
+        for piece in chunked.chunks(700) {
+            writer.write_all(piece).unwrap();
+        }
+        writer
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+    });
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    sending.join().unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 3);
+    assert!(response.contains("\r\nContent-Length: 4194304\r\n"));
+    assert!(response.contains("\r\nContent-Length: 50000\r\n"));
+    assert!(response.ends_with("\r\n\r\nhello"));
+}
+
+#[test]
+fn idle_connections_do_not_starve_busy_ones() {
+    let server = start_with(Server::serve_async, router(), |s| {
+        s.workers(1).keep_alive_timeout(Duration::from_secs(30))
+    });
+
+    let parked: Vec<TcpStream> = (0..200)
+        .map(|_| TcpStream::connect(server.addr).unwrap())
+        .collect();
+
+    let response = exchange(server.addr, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    drop(parked);
+}
+
+#[test]
+fn answers_errors_like_the_blocking_server() {
+    let mut router = router();
+    router.get("/boom", |_| -> Response { panic!("handler bug") });
+    router.get("/nothing", |_| Response::new(StatusCode::NoContent));
+    let server = start_with(Server::serve_async, router, |s| s);
+
+    let response = exchange(server.addr, b"BOGUS\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+
+    let response = exchange(server.addr, b"GET /boom HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 500 Internal Server Error"));
+
+    let mut stream = TcpStream::connect(server.addr).unwrap();
+    stream
+        .write_all(b"GET /nothing HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut text = String::new();
+    stream.read_to_string(&mut text).unwrap();
+    assert!(text.starts_with("HTTP/1.1 204 No Content"));
+    assert!(!text.contains("Content-Length"));
+}
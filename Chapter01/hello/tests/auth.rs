@@ -0,0 +1,70 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{exchange, router, start_with};
+use hello::{
+    auth::{Auth, AuthRule},
+    middleware::Chain,
+    request::Request,
+    response::{Response, StatusCode},
+    router::{Handler, Router},
+    server::Server,
+};
+
+fn site() -> Router {
+    let mut inner = router();
+    inner.get("/admin/stats", |_| {
+        Response::new(StatusCode::Ok).with_body("text/plain", "secret")
+    });
+    let chain =
+        Chain::new(inner).with(Auth::new().rule(AuthRule::new("/admin").bearer(["token-1"])));
+
+    let mut site = Router::new();
+    site.any("/*path", move |req: &mut Request| chain.handle(req));
+    site
+}
+
+fn get(addr: SocketAddr, path: &str, extra: &str) -> String {
+    let raw = format!("GET {path} HTTP/1.1\r\nHost: x\r\n{extra}Connection: close\r\n\r\n");
+    exchange(addr, raw.as_bytes())
+}
+
+fn protects_prefixes(serve: fn(Server, Router) -> bool) {
+    let server = start_with(serve, site(), |s| s);
+
+    let response = get(server.addr, "/", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    let response = get(server.addr, "/admin/stats", "");
+    assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    assert!(response.contains("\r\nWWW-Authenticate: Bearer realm=\"hello\"\r\n"));
+
+    // Paths that don't decode are refused rather than let past the rule.
+    for path in ["/%61dmin/stats%zz", "/admin%"] {
+        let response = get(server.addr, path, "");
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{path}"
+        );
+    }
+
+    let response = get(
+        server.addr,
+        "/admin/stats",
+        "Authorization: Bearer token-1\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with("\r\n\r\nsecret"));
+}
+
+#[test]
+fn blocking_server_protects_prefixes() {
+    protects_prefixes(Server::serve);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_protects_prefixes() {
+    protects_prefixes(Server::serve_async);
+}
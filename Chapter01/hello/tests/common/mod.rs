@@ -0,0 +1,76 @@
+#![allow(dead_code)]
+
+use std::{
+    io::prelude::*,
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use hello::{
+    response::{Response, StatusCode},
+    router::Router,
+    server::Server,
+    shutdown::Shutdown,
+};
+
+/// A server on an ephemeral port, shut down when dropped.
+pub struct TestServer {
+    pub addr: SocketAddr,
+    shutdown: Shutdown,
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.trigger();
+    }
+}
+
+pub fn router() -> Router {
+    let mut router = Router::new();
+    router
+        .get("/", |_| {
+            Response::new(StatusCode::Ok).with_body("text/plain", "hello")
+        })
+        .post("/echo", |req| {
+            let body = req.body.clone();
+            Response::new(StatusCode::Ok).with_body("application/octet-stream", body)
+        });
+    router
+}
+
+pub fn start(router: Router, configure: impl FnOnce(Server) -> Server) -> TestServer {
+    start_with(Server::serve, router, configure)
+}
+
+/// Like [`start`], choosing the backend with `serve`.
+pub fn start_with(
+    serve: fn(Server, Router) -> bool,
+    router: Router,
+    configure: impl FnOnce(Server) -> Server,
+) -> TestServer {
+    let server = configure(Server::bind("127.0.0.1:0").unwrap().workers(2));
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    thread::spawn(move || serve(server, router));
+
+    TestServer { addr, shutdown }
+}
+
+pub fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+}
+
+/// Send `raw` and read until the server closes the connection.
+pub fn exchange(addr: SocketAddr, raw: &[u8]) -> String {
+    let mut stream = connect(addr);
+    stream.write_all(raw).unwrap();
+
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).into_owned()
+}
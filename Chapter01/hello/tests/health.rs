@@ -0,0 +1,101 @@
+mod common;
+
+use std::{
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{exchange, router, start_with};
+use hello::{health::Health, router::Router, server::Server};
+
+fn site(document_root: &str) -> Router {
+    let health = Arc::new(Health::new().readable_dir("document_root", document_root));
+    let mut site = router();
+    let live = Arc::clone(&health);
+    site.get("/healthz", move |_| live.liveness())
+        .get("/readyz", move |_| health.readiness());
+    site
+}
+
+fn get(addr: SocketAddr, path: &str) -> String {
+    let raw = format!("GET {path} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n");
+    exchange(addr, raw.as_bytes())
+}
+
+fn answers_probes(serve: fn(Server, Router) -> bool) {
+    let server = start_with(serve, site("public"), |s| s);
+
+    let response = get(server.addr, "/healthz");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("\r\nContent-Type: application/json\r\n"));
+    assert!(response.contains("\r\nCache-Control: no-store\r\n"));
+    assert!(response.ends_with("\r\n\r\n{\"status\":\"ok\",\"checks\":{}}"));
+
+    let response = get(server.addr, "/readyz");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with(
+        "\r\n\r\n{\"status\":\"ok\",\"checks\":{\"document_root\":{\"status\":\"ok\"}}}"
+    ));
+
+    let server = start_with(serve, site("no/such/dir"), |s| s);
+    let response = get(server.addr, "/healthz");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    let response = get(server.addr, "/readyz");
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    assert!(response.contains("\"document_root\":{\"status\":\"fail\",\"error\":\"no/such/dir: "));
+}
+
+fn drains_before_closing(serve: fn(Server, Router) -> bool) {
+    let server = Server::bind("127.0.0.1:0")
+        .unwrap()
+        .workers(2)
+        .drain_period(Duration::from_millis(500));
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let health = Arc::new(Health::new().shutdown(shutdown.clone()));
+    let mut site = router();
+    site.get("/readyz", move |_| health.readiness());
+    let serving = thread::spawn(move || serve(server, site));
+
+    assert!(get(addr, "/readyz").starts_with("HTTP/1.1 200 OK\r\n"));
+
+    let triggered = Instant::now();
+    shutdown.trigger();
+    let response = get(addr, "/readyz");
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("\"shutdown\":{\"status\":\"fail\",\"error\":\"shutting down\"}"));
+    // Other requests are still answered, each on its own connection.
+    let response = get(addr, "/");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    assert!(serving.join().unwrap());
+    assert!(triggered.elapsed() >= Duration::from_millis(500));
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn blocking_server_answers_probes() {
+    answers_probes(Server::serve);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_answers_probes() {
+    answers_probes(Server::serve_async);
+}
+
+#[test]
+fn blocking_server_drains_before_closing() {
+    drains_before_closing(Server::serve);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_drains_before_closing() {
+    drains_before_closing(Server::serve_async);
+}
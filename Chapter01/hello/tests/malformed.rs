@@ -0,0 +1,213 @@
+mod common;
+
+use std::{
+    env, fs,
+    io::prelude::*,
+    net::Shutdown,
+    process,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{connect, exchange, router, start, start_with};
+use hello::{
+    access_log::{AccessLog, Format, RotatingFile, Sink},
+    request::Limits,
+    response::{Response, StatusCode},
+    router::Router,
+    server::Server,
+};
+
+fn assert_still_serving(addr: std::net::SocketAddr) {
+    let response = exchange(addr, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response:?}");
+}
+
+#[test]
+fn malformed_requests_get_400() {
+    let server = start(router(), |s| s);
+
+    let cases: &[&[u8]] = &[
+        b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03\r\n\r\n",
+        b"GARBAGE\r\n\r\n",
+        b"GET / HTTP/1.1 trailing\r\n\r\n",
+        b"GET / HTTP/9.9\r\n\r\n",
+        b"GET / HTTP/1.1\r\nX-Bytes: \xff\xfe\r\n\r\n",
+        b"GET \xff HTTP/1.1\r\n\r\n",
+        b"GET / HTTP/1.1\r\n: empty name\r\n\r\n",
+        b"POST /echo HTTP/1.1\r\nContent-Length: twelve\r\n\r\n",
+        b"POST /echo HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+        b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nnope\r\n",
+        b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabcXX\r\n",
+    ];
+
+    for raw in cases {
+        let response = exchange(server.addr, raw);
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request"),
+            "{:?} got {response:?}",
+            String::from_utf8_lossy(raw)
+        );
+        assert!(response.contains("Connection: close"));
+    }
+
+    assert_still_serving(server.addr);
+}
+
+#[test]
+fn truncated_requests_are_dropped_quietly() {
+    let server = start(router(), |s| s);
+
+    let cases: &[&[u8]] = &[
+        b"",
+        b"GET / HT",
+        b"GET / HTTP/1.1\r\nHost: loc",
+        b"POST /echo HTTP/1.1\r\nContent-Length: 100\r\n\r\nonly a little",
+        b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nffffffff\r\nabc",
+    ];
+
+    for raw in cases {
+        let mut stream = connect(server.addr);
+        stream.write_all(raw).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert_eq!(response, "", "{:?}", String::from_utf8_lossy(raw));
+    }
+
+    assert_still_serving(server.addr);
+}
+
+#[test]
+fn client_hanging_up_mid_response_is_harmless() {
+    let mut router = router();
+    router.get("/big", |_| {
+        Response::new(StatusCode::Ok).with_body("text/plain", vec![b'x'; 8 * 1024 * 1024])
+    });
+    let server = start(router, |s| s);
+
+    for _ in 0..4 {
+        let mut stream = connect(server.addr);
+        stream.write_all(b"GET /big HTTP/1.1\r\n\r\n").unwrap();
+        let mut first = [0; 64];
+        let _ = stream.read(&mut first);
+        drop(stream);
+    }
+
+    assert_still_serving(server.addr);
+}
+
+#[test]
+fn panicking_handler_gets_500() {
+    let mut router = router();
+    router.get("/boom", |_| panic!("handler bug"));
+    let server = start(router, |s| s.workers(1));
+
+    let response = exchange(server.addr, b"GET /boom HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 500 Internal Server Error"));
+
+    // The single worker must have survived.
+    std::thread::sleep(Duration::from_millis(50));
+    assert_still_serving(server.addr);
+}
+
+fn logs_refused_requests(serve: fn(Server, Router) -> bool, name: &str) {
+    let path = env::temp_dir().join(format!("hello-malformed-{}-{name}.log", process::id()));
+    let _ = fs::remove_file(&path);
+    let log = AccessLog::new(
+        Format::Common,
+        Sink::File(RotatingFile::open(&path, 0, 0).unwrap()),
+    );
+    let mut router = router();
+    router.get("/boom", |_| panic!("handler bug"));
+    let server = start_with(serve, router, |s| s.access_log(Arc::new(log)));
+
+    let response = exchange(server.addr, b"GARBAGE\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    let response = exchange(server.addr, b"GET /boom HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+
+    let log = fs::read_to_string(&path).unwrap();
+    let lines: Vec<_> = log.lines().collect();
+    assert_eq!(lines.len(), 2, "{log:?}");
+    assert!(lines[0].ends_with("\"-\" 400 -"), "{log:?}");
+    assert!(
+        lines[1].ends_with("\"GET /boom HTTP/1.1\" 500 -"),
+        "{log:?}"
+    );
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn blocking_server_logs_refused_requests() {
+    logs_refused_requests(Server::serve, "blocking");
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_logs_refused_requests() {
+    logs_refused_requests(Server::serve_async, "async");
+}
+
+fn refuses_oversized_and_slow_requests(serve: fn(Server, Router) -> bool) {
+    let limits = Limits {
+        max_header_size: 256,
+        max_headers: 10,
+        max_body_size: 16,
+    };
+    let server = start_with(serve, router(), |s| {
+        s.limits(limits).header_timeout(Duration::from_millis(300))
+    });
+
+    let long = format!("GET /{} HTTP/1.1\r\n\r\n", "x".repeat(300));
+    let response = exchange(server.addr, long.as_bytes());
+    assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+    let response = exchange(
+        server.addr,
+        b"POST /echo HTTP/1.1\r\nContent-Length: 17\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+
+    // A client trickling its headers in is cut off at the deadline. It
+    // goes on sending well past it, and still gets its answer rather than
+    // a reset.
+    let started = Instant::now();
+    let mut stream = connect(server.addr);
+    for byte in b"GET / HTTP/1.1\r\nX-Slow: aaaaaaaaaaaaaaaaaaaaaaaaaa" {
+        stream.write_all(&[*byte]).unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+        "{response:?}"
+    );
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    // A third connection from the same address is closed straight away.
+    // A server of its own, since the refused ones above may not have
+    // finished closing yet.
+    let server = start_with(serve, router(), |s| s.max_connections_per_ip(2));
+    let first = connect(server.addr);
+    let second = connect(server.addr);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(exchange(server.addr, b"GET / HTTP/1.1\r\n\r\n"), "");
+    drop((first, second));
+    thread::sleep(Duration::from_millis(50));
+    assert_still_serving(server.addr);
+}
+
+#[test]
+fn blocking_server_refuses_oversized_and_slow_requests() {
+    refuses_oversized_and_slow_requests(Server::serve);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_refuses_oversized_and_slow_requests() {
+    refuses_oversized_and_slow_requests(Server::serve_async);
+}
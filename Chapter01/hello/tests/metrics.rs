@@ -0,0 +1,74 @@
+mod common;
+
+use std::{net::SocketAddr, sync::Arc};
+
+use common::{exchange, router, start_with};
+use hello::{
+    metrics::Metrics,
+    response::{Response, StatusCode, Stream},
+    router::Router,
+    server::Server,
+};
+
+fn get(addr: SocketAddr, path: &str) -> String {
+    let raw = format!("GET {path} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n");
+    exchange(addr, raw.as_bytes())
+}
+
+fn counts_requests(serve: fn(Server, Router) -> bool) {
+    let metrics = Arc::new(Metrics::new());
+    let mut site = router();
+    let published = Arc::clone(&metrics);
+    site.get("/metrics", move |_| published.response());
+    site.get("/stream", |_| {
+        Response::new(StatusCode::Ok).body(Stream::new(&b"streamed"[..], None))
+    });
+    let server = start_with(serve, site, |s| s.metrics(Arc::clone(&metrics)));
+
+    assert!(get(server.addr, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(get(server.addr, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(get(server.addr, "/nope").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    // Streams of unknown length are counted as they are sent.
+    assert!(get(server.addr, "/stream").ends_with("\r\n\r\nstreamed"));
+    let response = exchange(server.addr, b"GARBAGE\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    let response = get(server.addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\n"));
+    let has = |line: &str| response.lines().any(|l| l == line);
+    assert!(has(
+        "hello_http_requests_total{route=\"/\",method=\"GET\",status=\"200\"} 2"
+    ));
+    assert!(has(
+        "hello_http_requests_total{route=\"unmatched\",method=\"GET\",status=\"404\"} 1"
+    ));
+    assert!(has("hello_http_response_size_bytes_sum{route=\"/\"} 10"));
+    assert!(has(
+        "hello_http_response_size_bytes_sum{route=\"/stream\"} 8"
+    ));
+    assert!(has(
+        "hello_http_requests_total{route=\"unmatched\",method=\"-\",status=\"400\"} 1"
+    ));
+    assert!(has("hello_connections_total 6"));
+    // The scrape's own connection is still open while it renders; earlier
+    // ones may not have been let go of yet.
+    let active = response
+        .lines()
+        .find_map(|l| l.strip_prefix("hello_connections_active "))
+        .and_then(|n| n.parse::<i64>().ok())
+        .unwrap();
+    assert!((1..=6).contains(&active));
+    assert!(has("hello_worker_queue_depth 0"));
+}
+
+#[test]
+fn blocking_server_counts_requests() {
+    counts_requests(Server::serve);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_counts_requests() {
+    counts_requests(Server::serve_async);
+}
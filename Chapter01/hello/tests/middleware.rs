@@ -0,0 +1,58 @@
+mod common;
+
+use common::{exchange, router, start_with};
+use hello::{
+    middleware::{CatchPanic, Chain, Cors, RequestId, SecurityHeaders},
+    request::Request,
+    router::{Handler, Router},
+    server::Server,
+};
+
+fn site() -> Router {
+    let mut inner = router();
+    inner.get("/panic", |_| panic!("handler bug"));
+    let chain = Chain::new(inner)
+        .with(RequestId::new())
+        .with(Cors::new(["https://app.example"]))
+        .with(SecurityHeaders::new())
+        .with(CatchPanic);
+
+    let mut site = Router::new();
+    site.any("/*path", move |req: &mut Request| chain.handle(req));
+    site
+}
+
+fn wraps_the_router(serve: fn(Server, Router) -> bool) {
+    let server = start_with(serve, site(), |s| s);
+
+    // The panic is answered inside the chain, so the connection survives
+    // for the next request and the 500 still gets the outer headers.
+    let raw = "GET /panic HTTP/1.1\r\nHost: x\r\nX-Request-Id: trace-1\r\n\r\n\
+               GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n";
+    let response = exchange(server.addr, raw.as_bytes());
+    let (first, second) = response.split_once("HTTP/1.1 200 OK\r\n").unwrap();
+    assert!(first.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    assert!(first.contains("\r\nX-Request-Id: trace-1\r\n"));
+    assert!(first.contains("\r\nX-Content-Type-Options: nosniff\r\n"));
+    assert!(!first.contains("Connection: close"));
+    assert!(second.contains("\r\nX-Request-Id: "));
+    assert!(second.ends_with("\r\n\r\nhello"));
+
+    let raw = "OPTIONS /echo HTTP/1.1\r\nHost: x\r\nOrigin: https://app.example\r\n\
+               Access-Control-Request-Method: POST\r\nConnection: close\r\n\r\n";
+    let response = exchange(server.addr, raw.as_bytes());
+    assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+    assert!(response.contains("\r\nAccess-Control-Allow-Origin: https://app.example\r\n"));
+    assert!(response.contains("\r\nAccess-Control-Allow-Methods: GET, HEAD, POST\r\n"));
+}
+
+#[test]
+fn blocking_server_wraps_the_router() {
+    wraps_the_router(Server::serve);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_wraps_the_router() {
+    wraps_the_router(Server::serve_async);
+}
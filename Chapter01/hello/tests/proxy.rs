@@ -0,0 +1,338 @@
+mod common;
+
+use std::{
+    io::prelude::*,
+    net::{SocketAddr, TcpListener},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use common::{connect, exchange, start, start_with};
+use hello::{
+    proxy::Proxy,
+    request::Request,
+    response::{Response, StatusCode},
+    router::Router,
+    server::Server,
+};
+
+/// An upstream that answers every request with its name and what it was
+/// sent.
+fn upstream(name: &'static str) -> common::TestServer {
+    let mut router = Router::new();
+    router.any("/*path", move |req: &mut Request| {
+        let header = |name| req.headers.get(name).unwrap_or("-").to_string();
+        let body = format!(
+            "{name} {} {}{} host={} for={} proto={} body={}",
+            req.method,
+            req.path,
+            req.query
+                .as_deref()
+                .map_or(String::new(), |q| format!("?{q}")),
+            header("Host"),
+            header("X-Forwarded-For"),
+            header("X-Forwarded-Proto"),
+            String::from_utf8_lossy(&req.body),
+        );
+        Response::new(StatusCode::Ok)
+            .header("X-Upstream", name)
+            .with_body("text/plain", body)
+    });
+    start(router, |s| s)
+}
+
+/// An upstream that accepts connections and sends whatever `respond`
+/// writes.
+fn raw_upstream(respond: fn(&mut std::net::TcpStream)) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            thread::spawn(move || respond(&mut stream));
+        }
+    });
+    addr
+}
+
+/// A port nothing listens on.
+fn dead_upstream() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn proxy_to(serve: fn(Server, Router) -> bool, proxy: Proxy) -> common::TestServer {
+    let mut router = Router::new();
+    router.any("/api/*rest", proxy).get("/", |_| {
+        Response::new(StatusCode::Ok).with_body("text/plain", "local")
+    });
+    start_with(serve, router, |s| s)
+}
+
+fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").unwrap().1
+}
+
+fn get(addr: SocketAddr, target: &str) -> String {
+    let raw = format!("GET {target} HTTP/1.1\r\nHost: site.example\r\nConnection: close\r\n\r\n");
+    exchange(addr, raw.as_bytes())
+}
+
+fn forwards_requests(serve: fn(Server, Router) -> bool) {
+    let a = upstream("a");
+    let b = upstream("b");
+    let proxy = Proxy::new([a.addr.to_string(), format!("http://{}", b.addr)])
+        .unwrap()
+        .strip_prefix("/api");
+    let server = proxy_to(serve, proxy);
+
+    let response = get(server.addr, "/api/users?page=2");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("\r\nX-Upstream: a\r\n"));
+    assert_eq!(
+        body(&response),
+        format!(
+            "a GET /users?page=2 host={} for=127.0.0.1 proto=http body=",
+            a.addr
+        )
+    );
+
+    let raw = "POST /api/echo HTTP/1.1\r\nHost: site.example\r\nX-Forwarded-For: 10.0.0.1\r\n\
+               Content-Length: 5\r\nConnection: close\r\n\r\nhello";
+    let response = exchange(server.addr, raw.as_bytes());
+    assert_eq!(
+        body(&response),
+        format!(
+            "b POST /echo host={} for=10.0.0.1, 127.0.0.1 proto=http body=hello",
+            b.addr
+        )
+    );
+
+    // Round-robin.
+    let names: Vec<String> = (0..4)
+        .map(|_| {
+            body(&get(server.addr, "/api/"))
+                .split(' ')
+                .next()
+                .unwrap()
+                .to_string()
+        })
+        .collect();
+    assert_eq!(names, ["a", "b", "a", "b"]);
+
+    assert_eq!(body(&get(server.addr, "/")), "local");
+}
+
+fn streams_responses(serve: fn(Server, Router) -> bool) {
+    let chunked = raw_upstream(|stream| {
+        let _ = stream.read(&mut [0; 4096]);
+        let _ = stream.write_all(
+            b"HTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\nX-Kept: yes\r\n\r\n\
+              5\r\nhello\r\n",
+        );
+        thread::sleep(Duration::from_millis(50));
+        let _ = stream.write_all(b"7\r\n, world\r\n0\r\n\r\n");
+    });
+    let server = proxy_to(serve, Proxy::new([chunked.to_string()]).unwrap());
+
+    let response = get(server.addr, "/api/stream");
+    assert!(response.starts_with("HTTP/1.1 201 Created\r\n"));
+    assert!(response.contains("\r\nX-Kept: yes\r\n"));
+    assert!(response.contains("\r\nConnection: close\r\n"));
+    assert!(!response.contains("Transfer-Encoding"));
+    assert!(!response.contains("Content-Length"));
+    assert_eq!(body(&response), "hello, world");
+
+    let teapot = raw_upstream(|stream| {
+        let _ = stream.read(&mut [0; 4096]);
+        let _ = stream.write_all(b"HTTP/1.1 418 I'm a teapot\r\nContent-Length: 5\r\n\r\nshort");
+    });
+    let server = proxy_to(serve, Proxy::new([teapot.to_string()]).unwrap());
+    let response = get(server.addr, "/api/");
+    assert!(response.starts_with("HTTP/1.1 418 \r\n"));
+    assert!(response.contains("\r\nContent-Length: 5\r\n"));
+    assert_eq!(body(&response), "short");
+}
+
+fn streams_requests(serve: fn(Server, Router) -> bool) {
+    // An upstream that says when the first half of a body has arrived, and
+    // answers with everything it was sent once the second half has.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (half, got_half) = mpsc::channel();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut received = Vec::new();
+            let mut chunk = [0; 4096];
+            while !received.ends_with(b" world") && !received.ends_with(b"0\r\n\r\n") {
+                match stream.read(&mut chunk) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => received.extend_from_slice(&chunk[..read]),
+                }
+                if received.ends_with(b"hello") || received.ends_with(b"hello\r\n") {
+                    let _ = half.send(());
+                }
+            }
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                received.len()
+            );
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(&received);
+        }
+    });
+    let server = proxy_to(serve, Proxy::new([addr.to_string()]).unwrap());
+
+    let upload = |head: &str, first: &str, second: &str| {
+        let mut client = connect(server.addr);
+        let head = format!("POST /api/upload HTTP/1.1\r\nConnection: close\r\n{head}\r\n\r\n");
+        client.write_all(head.as_bytes()).unwrap();
+        client.write_all(first.as_bytes()).unwrap();
+        // The upstream has the start of the body before the rest is sent.
+        got_half.recv_timeout(Duration::from_secs(5)).unwrap();
+        client.write_all(second.as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response = upload("Content-Length: 11", "hello", " world");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(body(&response).contains("\r\nContent-Length: 11\r\n"));
+    assert!(body(&response).ends_with("\r\n\r\nhello world"));
+
+    let response = upload(
+        "Transfer-Encoding: chunked",
+        "5\r\nhello\r\n",
+        "6;ext=1\r\n world\r\n0\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(body(&response).contains("\r\nTransfer-Encoding: chunked\r\n"));
+    assert!(!body(&response).contains("Content-Length"));
+    assert!(body(&response).ends_with("\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"));
+
+    // A malformed body is the client's fault, not the upstream's.
+    let raw = "POST /api/upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nnot hex\r\n";
+    let response = exchange(server.addr, raw.as_bytes());
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{response}"
+    );
+}
+
+fn reports_upstream_failures(serve: fn(Server, Router) -> bool) {
+    let server = proxy_to(serve, Proxy::new([dead_upstream().to_string()]).unwrap());
+    let response = get(server.addr, "/api/");
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+
+    let garbage = raw_upstream(|stream| {
+        let _ = stream.read(&mut [0; 4096]);
+        let _ = stream.write_all(b"SSH-2.0-OpenSSH\r\n\r\n");
+    });
+    let server = proxy_to(serve, Proxy::new([garbage.to_string()]).unwrap());
+    let response = get(server.addr, "/api/");
+    assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+
+    // Nothing asked it to upgrade, and the proxy can't tunnel if it does.
+    let switching = raw_upstream(|stream| {
+        let _ = stream.read(&mut [0; 4096]);
+        let _ = stream.write_all(
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\n\r\n",
+        );
+        thread::sleep(Duration::from_secs(2));
+    });
+    let proxy = Proxy::new([switching.to_string()])
+        .unwrap()
+        .timeout(Duration::from_secs(1));
+    let server = proxy_to(serve, proxy);
+    let response = get(server.addr, "/api/");
+    assert!(
+        response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+        "{response}"
+    );
+
+    let silent = raw_upstream(|stream| {
+        let _ = stream.read(&mut [0; 4096]);
+        thread::sleep(Duration::from_secs(2));
+    });
+    let proxy = Proxy::new([silent.to_string()])
+        .unwrap()
+        .timeout(Duration::from_millis(200));
+    let server = proxy_to(serve, proxy);
+    let response = get(server.addr, "/api/");
+    assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+}
+
+fn skips_failed_upstreams(serve: fn(Server, Router) -> bool) {
+    let live = upstream("live");
+    let proxy = Proxy::new([dead_upstream().to_string(), live.addr.to_string()])
+        .map(|p| p.max_fails(1).fail_timeout(Duration::from_secs(60)))
+        .unwrap();
+    let server = proxy_to(serve, proxy);
+
+    // The first request is retried on the live upstream; after that the
+    // dead one is out of the rotation.
+    for _ in 0..4 {
+        let response = get(server.addr, "/api/");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(body(&response).starts_with("live "));
+    }
+}
+
+#[test]
+fn blocking_server_forwards_requests() {
+    forwards_requests(Server::serve);
+}
+
+#[test]
+fn blocking_server_streams_responses() {
+    streams_responses(Server::serve);
+}
+
+#[test]
+fn blocking_server_streams_requests() {
+    streams_requests(Server::serve);
+}
+
+#[test]
+fn blocking_server_reports_upstream_failures() {
+    reports_upstream_failures(Server::serve);
+}
+
+#[test]
+fn blocking_server_skips_failed_upstreams() {
+    skips_failed_upstreams(Server::serve);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_forwards_requests() {
+    forwards_requests(Server::serve_async);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_streams_responses() {
+    streams_responses(Server::serve_async);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_streams_requests() {
+    streams_requests(Server::serve_async);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_reports_upstream_failures() {
+    reports_upstream_failures(Server::serve_async);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_skips_failed_upstreams() {
+    skips_failed_upstreams(Server::serve_async);
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use std::{env, fs, process, sync::Arc};
+
+use common::{exchange, start_with};
+use hello::{router::Router, server::Server, static_files::StaticFiles};
+
+/// A router serving a 1000-byte file of digits at `/digits.txt`.
+fn router(name: &str) -> Router {
+    let dir = env::temp_dir().join(format!("hello-ranges-{}-{name}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let digits: String = (0..1000)
+        .map(|i| char::from(b'0' + (i % 10) as u8))
+        .collect();
+    fs::write(dir.join("digits.txt"), digits).unwrap();
+    let files = Arc::new(StaticFiles::new(&dir).unwrap());
+
+    let mut router = Router::new();
+    router.get("/*path", move |req| {
+        files
+            .serve(req, req.param("path").unwrap_or_default())
+            .unwrap()
+    });
+    router
+}
+
+fn get(server: &common::TestServer, range: &str) -> String {
+    let raw = format!("GET /digits.txt HTTP/1.1\r\nRange: {range}\r\nConnection: close\r\n\r\n");
+    exchange(server.addr, raw.as_bytes())
+}
+
+fn serves_ranges(name: &str, serve: fn(Server, Router) -> bool) {
+    let server = start_with(serve, router(name), |s| s);
+
+    let response = exchange(
+        server.addr,
+        b"GET /digits.txt HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("\r\nAccept-Ranges: bytes\r\n"));
+
+    let response = get(&server, "bytes=10-14");
+    assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+    assert!(response.contains("\r\nContent-Range: bytes 10-14/1000\r\n"));
+    assert!(response.contains("\r\nContent-Length: 5\r\n"));
+    assert!(response.ends_with("\r\n\r\n01234"));
+
+    let response = get(&server, "bytes=-3, 0-1");
+    assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+    let boundary = response
+        .split("multipart/byteranges; boundary=")
+        .nth(1)
+        .and_then(|rest| rest.split("\r\n").next())
+        .unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.ends_with(&format!("\r\nContent-Length: {}", body.len())));
+    assert_eq!(
+        body,
+        format!(
+            "--{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Range: bytes 0-1/1000\r\n\r\n01\r\n\
+             --{boundary}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Range: bytes 997-999/1000\r\n\r\n789\r\n\
+             --{boundary}--\r\n"
+        )
+    );
+
+    let response = get(&server, "bytes=1000-");
+    assert!(response.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+    assert!(response.contains("\r\nContent-Range: bytes */1000\r\n"));
+
+    // A stale If-Range gets the whole file.
+    let raw = "GET /digits.txt HTTP/1.1\r\nRange: bytes=0-1\r\nIf-Range: \"stale\"\r\n\
+               Connection: close\r\n\r\n";
+    let response = exchange(server.addr, raw.as_bytes());
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+}
+
+#[test]
+fn blocking_server_serves_ranges() {
+    serves_ranges("blocking", Server::serve);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_serves_ranges() {
+    serves_ranges("async", Server::serve_async);
+}
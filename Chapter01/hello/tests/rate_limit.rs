@@ -0,0 +1,67 @@
+mod common;
+
+use std::net::SocketAddr;
+
+use common::{exchange, router, start_with};
+use hello::{
+    middleware::Chain,
+    rate_limit::{Rate, RateLimit},
+    request::Request,
+    router::{Handler, Router},
+    server::Server,
+};
+
+fn site() -> Router {
+    let chain = Chain::new(router()).with(
+        RateLimit::new()
+            .limit(Rate::per_minute(1))
+            .burst(2)
+            .route("/echo", Rate::per_hour(1)),
+    );
+
+    let mut site = Router::new();
+    site.any("/*path", move |req: &mut Request| chain.handle(req));
+    site
+}
+
+fn get(addr: SocketAddr, path: &str) -> String {
+    let raw = format!("GET {path} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n");
+    exchange(addr, raw.as_bytes())
+}
+
+fn limits_clients(serve: fn(Server, Router) -> bool) {
+    let server = start_with(serve, site(), |s| s);
+
+    for _ in 0..2 {
+        assert!(get(server.addr, "/").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+    let response = get(server.addr, "/");
+    assert!(response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+    assert!(response.contains("\r\nRetry-After: 60\r\n"));
+
+    // The route has a bucket of its own.
+    let response = get(server.addr, "/echo");
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    let response = get(server.addr, "/echo");
+    assert!(response.contains("\r\nRetry-After: 3600\r\n"));
+
+    // Nor can a malformed escape move a request onto the default limit.
+    for path in ["/echo%zz", "/%65cho/%"] {
+        let response = get(server.addr, path);
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{path}"
+        );
+    }
+}
+
+#[test]
+fn blocking_server_limits_clients() {
+    limits_clients(Server::serve);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_limits_clients() {
+    limits_clients(Server::serve_async);
+}
@@ -0,0 +1,156 @@
+mod common;
+
+use std::{io::prelude::*, thread, time::Duration};
+
+use common::{connect, exchange, router, start, start_with};
+use flate2::read::GzDecoder;
+use hello::{
+    compression::Compression,
+    response::{Response, StatusCode},
+    router::Router,
+    server::Server,
+    template::{ErrorPages, Templates},
+};
+
+#[test]
+fn pipelined_requests_share_a_connection() {
+    let server = start(router(), |s| s);
+
+    let response = exchange(
+        server.addr,
+        b"GET / HTTP/1.1\r\nHost: x\r\n\r\n\
+          GET / HTTP/1.1\r\nHost: x\r\n\r\n\
+          GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+    );
+
+    assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 3);
+    assert_eq!(response.matches("Connection: close").count(), 1);
+}
+
+#[test]
+fn http10_closes_unless_asked() {
+    let server = start(router(), |s| s);
+
+    let response = exchange(server.addr, b"GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n");
+    assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1);
+
+    let response = exchange(
+        server.addr,
+        b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET / HTTP/1.0\r\n\r\n",
+    );
+    assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+    assert!(response.contains("Connection: keep-alive"));
+}
+
+#[test]
+fn connection_closes_after_max_requests() {
+    let server = start(router(), |s| s.max_requests_per_connection(2));
+
+    let response = exchange(server.addr, "GET / HTTP/1.1\r\n\r\n".repeat(3).as_bytes());
+    assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+}
+
+#[test]
+fn idle_connection_times_out() {
+    let server = start(router(), |s| {
+        s.keep_alive_timeout(Duration::from_millis(200))
+    });
+
+    let response = exchange(server.addr, b"GET / HTTP/1.1\r\n\r\n");
+    assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1);
+}
+
+/// Keep-alive only limits the wait between requests; a new connection has
+/// the header timeout to start its first one.
+fn waits_for_the_first_request(serve: fn(Server, Router) -> bool) {
+    let server = start_with(serve, router(), |s| s.keep_alive_timeout(Duration::ZERO));
+
+    let mut stream = connect(server.addr);
+    thread::sleep(Duration::from_millis(500));
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response:?}");
+    assert!(response.contains("\r\nConnection: close\r\n"));
+}
+
+#[test]
+fn blocking_server_waits_for_the_first_request() {
+    waits_for_the_first_request(Server::serve);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_waits_for_the_first_request() {
+    waits_for_the_first_request(Server::serve_async);
+}
+
+#[test]
+fn compresses_for_clients_that_accept_it() {
+    let text = "all work and no play makes jack a dull boy\n".repeat(50);
+    let mut router = router();
+    let body = text.clone();
+    router.get("/text", move |_| {
+        Response::new(StatusCode::Ok).with_body("text/plain; charset=utf-8", body.clone())
+    });
+    let server = start(router, |s| s.compression(Compression::new()));
+
+    let mut stream = connect(server.addr);
+    stream
+        .write_all(b"GET /text HTTP/1.1\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw).unwrap();
+
+    let split = raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let head = String::from_utf8_lossy(&raw[..split]);
+    assert!(head.contains("\r\nContent-Encoding: gzip\r\n"));
+    assert!(head.contains("\r\nVary: Accept-Encoding\r\n"));
+    assert!(head.contains(&format!("\r\nContent-Length: {}\r\n", raw.len() - split)));
+
+    let mut decoded = String::new();
+    GzDecoder::new(&raw[split..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, text);
+
+    let response = exchange(
+        server.addr,
+        b"GET /text HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(!response.contains("Content-Encoding"));
+    assert!(response.ends_with(&text));
+}
+
+#[test]
+fn renders_error_pages() {
+    let mut templates = Templates::new();
+    templates
+        .add("error.html", "<h1>{{ status }} {{ reason }}</h1>{{ path }}")
+        .unwrap();
+    let pages = ErrorPages::new(templates)
+        .page(StatusCode::NotFound, "error.html")
+        .page(StatusCode::BadRequest, "error.html")
+        .page(StatusCode::InternalServerError, "error.html");
+    let mut router = router();
+    router.get("/panic", |_| panic!("boom"));
+    let server = start(router, |s| s.error_pages(pages));
+
+    let response = exchange(
+        server.addr,
+        b"GET /missing HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(response.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"));
+    assert!(response.ends_with("\r\n\r\n<h1>404 Not Found</h1>/missing"));
+
+    let response = exchange(server.addr, b"GET /panic HTTP/1.1\r\n\r\n");
+    assert!(response.ends_with("<h1>500 Internal Server Error</h1>/panic"));
+
+    let response = exchange(server.addr, b"GET / HTTP/9\r\n\r\n");
+    assert!(response.ends_with("<h1>400 Bad Request</h1>"));
+
+    // Handlers that send a body of their own keep it.
+    let response = exchange(server.addr, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.ends_with("\r\n\r\nhello"));
+}
@@ -0,0 +1,153 @@
+#![cfg(feature = "tls")]
+
+mod common;
+
+use std::{io::prelude::*, net::SocketAddr, sync::Arc};
+
+use common::{exchange, router, start, start_with};
+use hello::{
+    request::Method,
+    router::Router,
+    server::Server,
+    tls::{Certificates, HttpsRedirect},
+};
+use rcgen::CertifiedKey;
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, ServerName},
+    ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+};
+
+/// Self-signed certificates for `localhost` (also valid for `127.0.0.1`)
+/// and `other.test`, and a server config presenting them by SNI with
+/// `localhost` as the default.
+fn certificates() -> (CertifiedKey, CertifiedKey, Arc<rustls::ServerConfig>) {
+    let localhost =
+        rcgen::generate_simple_self_signed(["localhost".to_string(), "127.0.0.1".to_string()])
+            .unwrap();
+    let other = rcgen::generate_simple_self_signed(["other.test".to_string()]).unwrap();
+
+    let mut certificates = Certificates::new();
+    certificates
+        .add_pem(
+            &["localhost"],
+            localhost.cert.pem().as_bytes(),
+            localhost.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap()
+        .add_pem(
+            &["other.test"],
+            other.cert.pem().as_bytes(),
+            other.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+
+    (localhost, other, certificates.server_config().unwrap())
+}
+
+/// Send `raw` over TLS asking for `name`, returning the certificate the
+/// server presented and everything it sent back.
+fn exchange_tls(
+    addr: SocketAddr,
+    name: &str,
+    trusted: &[&CertifiedKey],
+    raw: &[u8],
+) -> (CertificateDer<'static>, String) {
+    let mut roots = RootCertStore::empty();
+    for cert in trusted {
+        roots.add(cert.cert.der().clone()).unwrap();
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connection = ClientConnection::new(
+        Arc::new(config),
+        ServerName::try_from(name.to_string()).unwrap(),
+    )
+    .unwrap();
+
+    let mut stream = StreamOwned::new(connection, common::connect(addr));
+    stream.write_all(raw).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+
+    let presented = stream.conn.peer_certificates().unwrap()[0].clone();
+    (presented, String::from_utf8_lossy(&response).into_owned())
+}
+
+fn serves_https_by_sni(serve: fn(Server, Router) -> bool) {
+    let (localhost, other, config) = certificates();
+    let server = start_with(serve, router(), |s| s.tls(config));
+    let trusted = [&localhost, &other];
+
+    let (cert, response) = exchange_tls(
+        server.addr,
+        "localhost",
+        &trusted,
+        b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n\
+          POST /echo HTTP/1.1\r\nContent-Length: 4\r\nConnection: close\r\n\r\nping",
+    );
+    assert_eq!(&cert, localhost.cert.der());
+    assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+    assert!(response.ends_with("\r\n\r\nping"));
+
+    let (cert, response) = exchange_tls(
+        server.addr,
+        "other.test",
+        &trusted,
+        b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(&cert, other.cert.der());
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+}
+
+#[test]
+fn blocking_server_serves_https_by_sni() {
+    serves_https_by_sni(Server::serve);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_serves_https_by_sni() {
+    serves_https_by_sni(Server::serve_async);
+}
+
+#[test]
+fn unknown_names_get_the_default_certificate() {
+    let (localhost, _, config) = certificates();
+    let server = start(router(), |s| s.tls(config));
+
+    // Clients don't send SNI when connecting to an IP address.
+    let (cert, _) = exchange_tls(
+        server.addr,
+        "127.0.0.1",
+        &[&localhost],
+        b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(&cert, localhost.cert.der());
+}
+
+#[test]
+fn plain_http_on_the_https_port_is_dropped() {
+    let (_, _, config) = certificates();
+    let server = start(router(), |s| s.tls(config));
+
+    let response = exchange(server.addr, b"GET / HTTP/1.1\r\n\r\n");
+    assert!(!response.contains("200 OK"));
+}
+
+#[test]
+fn redirects_plain_http_to_https() {
+    let mut router = Router::new();
+    router.route(Method::Get, "/*path", HttpsRedirect::new(8443));
+    let server = start(router, |s| s);
+
+    let response = exchange(
+        server.addr,
+        b"GET /docs/a?x=1 HTTP/1.1\r\nHost: example.com:7878\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 308 Permanent Redirect\r\n"));
+    assert!(response.contains("\r\nLocation: https://example.com:8443/docs/a?x=1\r\n"));
+}
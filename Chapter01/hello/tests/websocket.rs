@@ -0,0 +1,267 @@
+mod common;
+
+use std::{
+    io::prelude::*,
+    net::{SocketAddr, TcpStream},
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+use common::{connect, exchange, start_with};
+use hello::{
+    request::Method,
+    response::{Response, StatusCode},
+    router::Router,
+    server::Server,
+    websocket::{Frame, Message, Opcode, Sender, WebSocket, WebSocketHandler, GOING_AWAY},
+};
+
+const MASK: [u8; 4] = [1, 2, 3, 4];
+
+const HANDSHAKE: &[u8] = b"GET /ws HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                          Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                          Sec-WebSocket-Version: 13\r\n\r\n";
+
+/// Echoes messages, and hands its senders to the test so it can push.
+struct Echo {
+    opened: Mutex<mpsc::Sender<Sender>>,
+    closed: Mutex<mpsc::Sender<u16>>,
+}
+
+impl WebSocketHandler for Echo {
+    fn on_open(&self, socket: &Sender) {
+        let _ = self.opened.lock().unwrap().send(socket.clone());
+    }
+
+    fn on_message(&self, socket: &Sender, message: Message) {
+        let _ = socket.send(message);
+    }
+
+    fn on_close(&self, code: u16) {
+        let _ = self.closed.lock().unwrap().send(code);
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    buf: Vec<u8>,
+}
+
+impl Client {
+    fn open(addr: SocketAddr) -> Client {
+        let mut stream = connect(addr);
+        stream.write_all(HANDSHAKE).unwrap();
+
+        let mut buf = Vec::new();
+        let end = loop {
+            let mut chunk = [0; 1024];
+            let read = stream.read(&mut chunk).unwrap();
+            assert!(read > 0, "connection closed during handshake");
+            buf.extend_from_slice(&chunk[..read]);
+            if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+        };
+        let head = String::from_utf8(buf[..end].to_vec()).unwrap();
+        assert!(
+            head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+            "{head}"
+        );
+        assert!(head.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(head.contains("\r\nUpgrade: websocket\r\n"));
+        assert!(!head.contains("Content-Length"));
+
+        Client {
+            stream,
+            buf: buf[end..].to_vec(),
+        }
+    }
+
+    fn send(&mut self, fin: bool, opcode: Opcode, payload: &[u8]) {
+        let frame = Frame {
+            fin,
+            opcode,
+            mask: Some(MASK),
+            payload: payload.to_vec(),
+        };
+        self.stream.write_all(&frame.encode()).unwrap();
+    }
+
+    /// The next frame, or `None` if the server closed the connection.
+    fn recv(&mut self) -> Option<Frame> {
+        loop {
+            if let Some((frame, len)) = Frame::parse(&self.buf, u64::MAX).unwrap() {
+                self.buf.drain(..len);
+                assert_eq!(frame.mask, None);
+                return Some(frame);
+            }
+            let mut chunk = [0; 1024];
+            match self.stream.read(&mut chunk) {
+                Ok(0) | Err(_) => return None,
+                Ok(read) => self.buf.extend_from_slice(&chunk[..read]),
+            }
+        }
+    }
+}
+
+fn serves_websockets(serve: fn(Server, Router) -> bool) {
+    let (opened, sockets) = mpsc::channel();
+    let (closed, codes) = mpsc::channel();
+    let echo = Echo {
+        opened: Mutex::new(opened),
+        closed: Mutex::new(closed),
+    };
+    let mut router = Router::new();
+    router.route(Method::Get, "/ws", WebSocket::new(echo));
+    let server = start_with(serve, router, |s| s);
+
+    let mut client = Client::open(server.addr);
+    let socket: Sender = sockets.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    client.send(true, Opcode::Text, b"hello");
+    assert_eq!(client.recv(), Some(Frame::new(Opcode::Text, "hello")));
+
+    client.send(false, Opcode::Binary, &[1, 2]);
+    client.send(true, Opcode::Ping, b"ping");
+    client.send(true, Opcode::Continuation, &[3]);
+    assert_eq!(client.recv(), Some(Frame::new(Opcode::Pong, "ping")));
+    assert_eq!(
+        client.recv(),
+        Some(Frame::new(Opcode::Binary, vec![1, 2, 3]))
+    );
+
+    // Pushed from outside the handler.
+    let pusher = Arc::new(socket);
+    std::thread::spawn({
+        let pusher = Arc::clone(&pusher);
+        move || pusher.send("update").unwrap()
+    })
+    .join()
+    .unwrap();
+    assert_eq!(client.recv(), Some(Frame::new(Opcode::Text, "update")));
+
+    client.send(true, Opcode::Close, &1000u16.to_be_bytes());
+    assert_eq!(client.recv(), Some(Frame::close(1000, "")));
+    assert_eq!(client.recv(), None);
+    assert_eq!(codes.recv_timeout(Duration::from_secs(5)), Ok(1000));
+    assert!(!pusher.is_open());
+
+    // A protocol error closes the connection with 1002.
+    let mut client = Client::open(server.addr);
+    client
+        .stream
+        .write_all(&Frame::new(Opcode::Text, "unmasked").encode())
+        .unwrap();
+    assert_eq!(client.recv(), Some(Frame::close(1002, "")));
+    assert_eq!(client.recv(), None);
+    assert_eq!(codes.recv_timeout(Duration::from_secs(5)), Ok(1002));
+
+    let response = exchange(
+        server.addr,
+        b"GET /ws HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+    assert!(response.contains("\r\nUpgrade: websocket\r\n"));
+
+    let response = exchange(
+        server.addr,
+        b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade, close\r\n\
+          Sec-WebSocket-Key: short\r\nSec-WebSocket-Version: 13\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+}
+
+fn echo_router() -> Router {
+    let mut router = Router::new();
+    router.route(
+        Method::Get,
+        "/ws",
+        WebSocket::new(|socket: &Sender, message: Message| {
+            let _ = socket.send(message);
+        }),
+    );
+    router
+}
+
+fn closes_idle_sessions(serve: fn(Server, Router) -> bool) {
+    let server = start_with(serve, echo_router(), |s| {
+        s.websocket_idle_timeout(Duration::from_millis(300))
+    });
+    let mut client = Client::open(server.addr);
+
+    // Answering the ping keeps the session open.
+    assert_eq!(client.recv(), Some(Frame::new(Opcode::Ping, Vec::new())));
+    client.send(true, Opcode::Pong, b"");
+    assert_eq!(client.recv(), Some(Frame::new(Opcode::Ping, Vec::new())));
+
+    assert_eq!(
+        client.recv(),
+        Some(Frame::close(GOING_AWAY, "idle timeout"))
+    );
+    client.send(true, Opcode::Close, &GOING_AWAY.to_be_bytes());
+    assert_eq!(client.recv(), None);
+}
+
+fn limits_open_sessions(serve: fn(Server, Router) -> bool) {
+    let server = start_with(serve, echo_router(), |s| s.max_websockets(1));
+    let mut client = Client::open(server.addr);
+
+    let response = exchange(server.addr, HANDSHAKE);
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+        "{response}"
+    );
+
+    client.send(true, Opcode::Close, &1000u16.to_be_bytes());
+    assert_eq!(client.recv(), Some(Frame::close(1000, "")));
+    assert_eq!(client.recv(), None);
+    let mut client = Client::open(server.addr);
+    client.send(true, Opcode::Text, b"again");
+    assert_eq!(client.recv(), Some(Frame::new(Opcode::Text, "again")));
+}
+
+#[test]
+fn blocking_server_serves_websockets() {
+    serves_websockets(Server::serve);
+}
+
+#[test]
+fn blocking_server_closes_idle_sessions() {
+    closes_idle_sessions(Server::serve);
+}
+
+#[test]
+fn blocking_server_limits_open_sessions() {
+    limits_open_sessions(Server::serve);
+}
+
+#[test]
+fn blocking_server_keeps_a_worker_for_requests() {
+    let mut router = echo_router();
+    router.get("/", |_| Response::new(StatusCode::Ok));
+    let server = start_with(Server::serve, router, |s| s.workers(2));
+    let _client = Client::open(server.addr);
+
+    let response = exchange(server.addr, HANDSHAKE);
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    let response = exchange(server.addr, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_serves_websockets() {
+    serves_websockets(Server::serve_async);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_closes_idle_sessions() {
+    closes_idle_sessions(Server::serve_async);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_server_limits_open_sessions() {
+    limits_open_sessions(Server::serve_async);
+}